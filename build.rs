@@ -27,7 +27,11 @@ fn main() -> Result<()> {
 
     // Generate protobuf bindings
     let empty_array: &[&Path] = &[];
-    prost_build::compile_protos(&["protos/messages.proto"], empty_array)?;
+    let mut config = prost_build::Config::new();
+    // Use BTreeMap for protobuf maps, so the encoded messages have the
+    // deterministic order of keys and graph files are reproducible.
+    config.btree_map(["."]);
+    config.compile_protos(&["protos/messages.proto"], empty_array)?;
 
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,944 @@
+// BN254 scalar field arithmetic and the witness calculation runtime for the
+// C code generated by graph2c. The generator inserts the field constants
+// (FR_P, FR_R2, FR_ONE, FR_HALF, FR_NP) and the graph description at the
+// GRAPH_CONSTANTS marker and appends the graph evaluation function
+// `calc_nodes`.
+
+#include <stdint.h>
+#include <stddef.h>
+#include <stdlib.h>
+#include <string.h>
+
+// Not every graph uses all the operations.
+#if defined(__GNUC__)
+#pragma GCC diagnostic ignored "-Wunused-function"
+#endif
+
+#define FR_LIMBS 8
+#define FR_BITS 254
+
+// Little-endian 32-bit limbs. Values of the graph nodes are in Montgomery
+// form, operands of the integer operations are converted to canonical form.
+typedef struct {
+  uint32_t v[FR_LIMBS];
+} fr_t;
+
+// @GRAPH_CONSTANTS@
+
+static const fr_t FR_ZERO = {{0}};
+
+static int raw_is_zero(const fr_t *a) {
+  for (int i = 0; i < FR_LIMBS; i++) {
+    if (a->v[i] != 0) {
+      return 0;
+    }
+  }
+  return 1;
+}
+
+// -1 if a < b, 0 if a == b, 1 if a > b
+static int raw_cmp(const fr_t *a, const fr_t *b) {
+  for (int i = FR_LIMBS - 1; i >= 0; i--) {
+    if (a->v[i] < b->v[i]) {
+      return -1;
+    }
+    if (a->v[i] > b->v[i]) {
+      return 1;
+    }
+  }
+  return 0;
+}
+
+static uint32_t raw_add(fr_t *r, const fr_t *a, const fr_t *b) {
+  uint64_t carry = 0;
+  for (int i = 0; i < FR_LIMBS; i++) {
+    carry += (uint64_t)a->v[i] + b->v[i];
+    r->v[i] = (uint32_t)carry;
+    carry >>= 32;
+  }
+  return (uint32_t)carry;
+}
+
+static uint32_t raw_sub(fr_t *r, const fr_t *a, const fr_t *b) {
+  uint64_t borrow = 0;
+  for (int i = 0; i < FR_LIMBS; i++) {
+    uint64_t d = (uint64_t)a->v[i] - b->v[i] - borrow;
+    r->v[i] = (uint32_t)d;
+    borrow = (d >> 32) & 1;
+  }
+  return (uint32_t)borrow;
+}
+
+// Reduce the value less than 2^256 modulo p.
+static void raw_reduce(fr_t *a) {
+  while (raw_cmp(a, &FR_P) >= 0) {
+    raw_sub(a, a, &FR_P);
+  }
+}
+
+static void raw_shl1(fr_t *a) {
+  for (int i = FR_LIMBS - 1; i > 0; i--) {
+    a->v[i] = (a->v[i] << 1) | (a->v[i - 1] >> 31);
+  }
+  a->v[0] <<= 1;
+}
+
+static int raw_bit(const fr_t *a, int i) {
+  return (a->v[i / 32] >> (i % 32)) & 1;
+}
+
+static void fr_add(fr_t *r, const fr_t *a, const fr_t *b) {
+  uint32_t carry = raw_add(r, a, b);
+  if (carry || raw_cmp(r, &FR_P) >= 0) {
+    raw_sub(r, r, &FR_P);
+  }
+}
+
+static void fr_sub(fr_t *r, const fr_t *a, const fr_t *b) {
+  if (raw_sub(r, a, b)) {
+    raw_add(r, r, &FR_P);
+  }
+}
+
+static void fr_neg(fr_t *r, const fr_t *a) {
+  if (raw_is_zero(a)) {
+    *r = FR_ZERO;
+  } else {
+    raw_sub(r, &FR_P, a);
+  }
+}
+
+// Montgomery multiplication (CIOS): r = a * b / 2^256 mod p
+static void fr_mul(fr_t *r, const fr_t *a, const fr_t *b) {
+  uint32_t t[FR_LIMBS + 2] = {0};
+  for (int i = 0; i < FR_LIMBS; i++) {
+    uint64_t c = 0;
+    for (int j = 0; j < FR_LIMBS; j++) {
+      c = (uint64_t)t[j] + (uint64_t)a->v[j] * b->v[i] + (c >> 32);
+      t[j] = (uint32_t)c;
+    }
+    c = (uint64_t)t[FR_LIMBS] + (c >> 32);
+    t[FR_LIMBS] = (uint32_t)c;
+    t[FR_LIMBS + 1] = (uint32_t)(c >> 32);
+
+    uint32_t m = t[0] * FR_NP;
+    c = (uint64_t)t[0] + (uint64_t)m * FR_P.v[0];
+    for (int j = 1; j < FR_LIMBS; j++) {
+      c = (uint64_t)t[j] + (uint64_t)m * FR_P.v[j] + (c >> 32);
+      t[j - 1] = (uint32_t)c;
+    }
+    c = (uint64_t)t[FR_LIMBS] + (c >> 32);
+    t[FR_LIMBS - 1] = (uint32_t)c;
+    t[FR_LIMBS] = t[FR_LIMBS + 1] + (uint32_t)(c >> 32);
+  }
+
+  fr_t res;
+  memcpy(res.v, t, sizeof(res.v));
+  if (t[FR_LIMBS] || raw_cmp(&res, &FR_P) >= 0) {
+    raw_sub(&res, &res, &FR_P);
+  }
+  *r = res;
+}
+
+static void fr_to_mont(fr_t *r, const fr_t *a) {
+  fr_mul(r, a, &FR_R2);
+}
+
+static void fr_from_mont(fr_t *r, const fr_t *a) {
+  fr_t one = {{1}};
+  fr_mul(r, a, &one);
+}
+
+static void fr_from_canonical_bool(fr_t *r, int b) {
+  *r = b ? FR_ONE : FR_ZERO;
+}
+
+// r = a^e, where a is in Montgomery form and e is canonical
+static void fr_pow_raw(fr_t *r, const fr_t *a, const fr_t *e) {
+  fr_t res = FR_ONE;
+  fr_t base = *a;
+  for (int i = FR_LIMBS * 32 - 1; i >= 0; i--) {
+    fr_mul(&res, &res, &res);
+    if (raw_bit(e, i)) {
+      fr_mul(&res, &res, &base);
+    }
+  }
+  *r = res;
+}
+
+static void fr_pow(fr_t *r, const fr_t *a, const fr_t *b) {
+  fr_t e;
+  fr_from_mont(&e, b);
+  fr_pow_raw(r, a, &e);
+}
+
+// Division by zero returns zero, as in the interpreter.
+static void fr_div(fr_t *r, const fr_t *a, const fr_t *b) {
+  if (raw_is_zero(b)) {
+    *r = FR_ZERO;
+    return;
+  }
+  fr_t e, two = {{2}}, inv;
+  raw_sub(&e, &FR_P, &two);
+  fr_pow_raw(&inv, b, &e);
+  fr_mul(r, a, &inv);
+}
+
+// Integer division of canonical values: q = a / b, m = a % b
+static void raw_divmod(fr_t *q, fr_t *m, const fr_t *a, const fr_t *b) {
+  fr_t quot = FR_ZERO, rem = FR_ZERO;
+  for (int i = FR_LIMBS * 32 - 1; i >= 0; i--) {
+    uint32_t overflow = rem.v[FR_LIMBS - 1] >> 31;
+    raw_shl1(&rem);
+    rem.v[0] |= (uint32_t)raw_bit(a, i);
+    if (overflow || raw_cmp(&rem, b) >= 0) {
+      raw_sub(&rem, &rem, b);
+      quot.v[i / 32] |= (uint32_t)1 << (i % 32);
+    }
+  }
+  *q = quot;
+  *m = rem;
+}
+
+static void fr_idiv_mod(fr_t *r, const fr_t *a, const fr_t *b, int want_mod) {
+  if (raw_is_zero(b)) {
+    *r = FR_ZERO;
+    return;
+  }
+  fr_t ca, cb, q, m;
+  fr_from_mont(&ca, a);
+  fr_from_mont(&cb, b);
+  raw_divmod(&q, &m, &ca, &cb);
+  fr_to_mont(r, want_mod ? &m : &q);
+}
+
+static void fr_idiv(fr_t *r, const fr_t *a, const fr_t *b) {
+  fr_idiv_mod(r, a, b, 0);
+}
+
+static void fr_mod(fr_t *r, const fr_t *a, const fr_t *b) {
+  fr_idiv_mod(r, a, b, 1);
+}
+
+static void fr_eq(fr_t *r, const fr_t *a, const fr_t *b) {
+  fr_from_canonical_bool(r, raw_cmp(a, b) == 0);
+}
+
+static void fr_neq(fr_t *r, const fr_t *a, const fr_t *b) {
+  fr_from_canonical_bool(r, raw_cmp(a, b) != 0);
+}
+
+// Compare as signed values: values greater than (p-1)/2 are negative.
+static int fr_signed_cmp(const fr_t *a, const fr_t *b) {
+  fr_t ca, cb;
+  fr_from_mont(&ca, a);
+  fr_from_mont(&cb, b);
+  int a_neg = raw_cmp(&ca, &FR_HALF) > 0;
+  int b_neg = raw_cmp(&cb, &FR_HALF) > 0;
+  if (a_neg != b_neg) {
+    return a_neg ? -1 : 1;
+  }
+  return raw_cmp(&ca, &cb);
+}
+
+static void fr_lt(fr_t *r, const fr_t *a, const fr_t *b) {
+  fr_from_canonical_bool(r, fr_signed_cmp(a, b) < 0);
+}
+
+static void fr_gt(fr_t *r, const fr_t *a, const fr_t *b) {
+  fr_from_canonical_bool(r, fr_signed_cmp(a, b) > 0);
+}
+
+static void fr_leq(fr_t *r, const fr_t *a, const fr_t *b) {
+  fr_from_canonical_bool(r, fr_signed_cmp(a, b) <= 0);
+}
+
+static void fr_geq(fr_t *r, const fr_t *a, const fr_t *b) {
+  fr_from_canonical_bool(r, fr_signed_cmp(a, b) >= 0);
+}
+
+static void fr_land(fr_t *r, const fr_t *a, const fr_t *b) {
+  fr_from_canonical_bool(r, !raw_is_zero(a) && !raw_is_zero(b));
+}
+
+static void fr_lor(fr_t *r, const fr_t *a, const fr_t *b) {
+  fr_from_canonical_bool(r, !raw_is_zero(a) || !raw_is_zero(b));
+}
+
+// Shift amount if it is less than FR_BITS, otherwise FR_BITS.
+static int shift_amount(const fr_t *b) {
+  fr_t cb;
+  fr_from_mont(&cb, b);
+  for (int i = 1; i < FR_LIMBS; i++) {
+    if (cb.v[i] != 0) {
+      return FR_BITS;
+    }
+  }
+  return cb.v[0] < FR_BITS ? (int)cb.v[0] : FR_BITS;
+}
+
+static void fr_shl(fr_t *r, const fr_t *a, const fr_t *b) {
+  int n = shift_amount(b);
+  if (n >= FR_BITS) {
+    *r = FR_ZERO;
+    return;
+  }
+  fr_t ca, res = FR_ZERO;
+  fr_from_mont(&ca, a);
+  int limbs = n / 32, bits = n % 32;
+  for (int i = FR_LIMBS - 1; i >= limbs; i--) {
+    res.v[i] = ca.v[i - limbs] << bits;
+    if (bits && i - limbs - 1 >= 0) {
+      res.v[i] |= ca.v[i - limbs - 1] >> (32 - bits);
+    }
+  }
+  // truncate to FR_BITS as circom does, then the value is less than 2q
+  res.v[FR_LIMBS - 1] &= (1u << (FR_BITS - 32 * (FR_LIMBS - 1))) - 1;
+  raw_reduce(&res);
+  fr_to_mont(r, &res);
+}
+
+static void fr_shr(fr_t *r, const fr_t *a, const fr_t *b) {
+  int n = shift_amount(b);
+  if (n >= FR_BITS) {
+    *r = FR_ZERO;
+    return;
+  }
+  fr_t ca, res = FR_ZERO;
+  fr_from_mont(&ca, a);
+  int limbs = n / 32, bits = n % 32;
+  for (int i = 0; i + limbs < FR_LIMBS; i++) {
+    res.v[i] = ca.v[i + limbs] >> bits;
+    if (bits && i + limbs + 1 < FR_LIMBS) {
+      res.v[i] |= ca.v[i + limbs + 1] << (32 - bits);
+    }
+  }
+  fr_to_mont(r, &res);
+}
+
+#define FR_BITWISE(name, op)                                      \
+  static void name(fr_t *r, const fr_t *a, const fr_t *b) {       \
+    fr_t ca, cb, res;                                             \
+    fr_from_mont(&ca, a);                                         \
+    fr_from_mont(&cb, b);                                         \
+    for (int i = 0; i < FR_LIMBS; i++) {                          \
+      res.v[i] = ca.v[i] op cb.v[i];                              \
+    }                                                             \
+    raw_reduce(&res);                                             \
+    fr_to_mont(r, &res);                                          \
+  }
+
+FR_BITWISE(fr_band, &)
+FR_BITWISE(fr_bor, |)
+FR_BITWISE(fr_bxor, ^)
+
+static void fr_tern_cond(fr_t *r, const fr_t *a, const fr_t *b, const fr_t *c) {
+  *r = raw_is_zero(a) ? *c : *b;
+}
+
+// Native gadgets. Arguments and results are in Montgomery form.
+
+static const uint32_t SHA256_K[64] = {
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1,
+    0x923f82a4, 0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
+    0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786,
+    0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147,
+    0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+    0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+    0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a,
+    0x5b9cca4f, 0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+    0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2};
+
+#define ROTR(x, n) (((x) >> (n)) | ((x) << (32 - (n))))
+
+static void sha256_compress(uint32_t *h, uint32_t *w) {
+  for (int i = 16; i < 64; i++) {
+    uint32_t s0 = ROTR(w[i - 15], 7) ^ ROTR(w[i - 15], 18) ^ (w[i - 15] >> 3);
+    uint32_t s1 = ROTR(w[i - 2], 17) ^ ROTR(w[i - 2], 19) ^ (w[i - 2] >> 10);
+    w[i] = w[i - 16] + s0 + w[i - 7] + s1;
+  }
+  uint32_t a = h[0], b = h[1], c = h[2], d = h[3];
+  uint32_t e = h[4], f = h[5], g = h[6], hh = h[7];
+  for (int i = 0; i < 64; i++) {
+    uint32_t s1 = ROTR(e, 6) ^ ROTR(e, 11) ^ ROTR(e, 25);
+    uint32_t ch = (e & f) ^ (~e & g);
+    uint32_t t1 = hh + s1 + ch + SHA256_K[i] + w[i];
+    uint32_t s0 = ROTR(a, 2) ^ ROTR(a, 13) ^ ROTR(a, 22);
+    uint32_t maj = (a & b) ^ (a & c) ^ (b & c);
+    uint32_t t2 = s0 + maj;
+    hh = g;
+    g = f;
+    f = e;
+    e = d + t1;
+    d = c;
+    c = b;
+    b = a;
+    a = t1 + t2;
+  }
+  h[0] += a;
+  h[1] += b;
+  h[2] += c;
+  h[3] += d;
+  h[4] += e;
+  h[5] += f;
+  h[6] += g;
+  h[7] += hh;
+}
+
+// circomlib Sha256(n): n message bits, 256 digest bits, most significant
+// bit first. A non-zero argument is treated as 1.
+static void gadget_sha256(const fr_t *const *args, size_t n, fr_t *out) {
+  uint32_t h[8] = {0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+                   0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19};
+  uint32_t w[64];
+  size_t blocks_num = (n + 64) / 512 + 1;
+  size_t len_start = blocks_num * 512 - 64;
+  for (size_t blk = 0; blk < blocks_num; blk++) {
+    for (size_t i = 0; i < 512; i++) {
+      size_t p = blk * 512 + i;
+      uint32_t bit;
+      if (p < n) {
+        bit = !raw_is_zero(args[p]);
+      } else if (p == n) {
+        bit = 1;
+      } else if (p >= len_start) {
+        bit = (uint32_t)(((uint64_t)n >> (63 - (p - len_start))) & 1);
+      } else {
+        bit = 0;
+      }
+      if (i % 32 == 0) {
+        w[i / 32] = 0;
+      }
+      w[i / 32] = (w[i / 32] << 1) | bit;
+    }
+    sha256_compress(h, w);
+  }
+  for (int k = 0; k < 256; k++) {
+    out[k] = (h[k / 32] >> (31 - k % 32)) & 1 ? FR_ONE : FR_ZERO;
+  }
+}
+
+// Limbs of limb_bits bits of the value, the least significant limb first.
+static void gadget_limbs(const fr_t *a, int limb_bits, size_t limbs_num,
+                         fr_t *out) {
+  fr_t ca;
+  fr_from_mont(&ca, a);
+  for (size_t k = 0; k < limbs_num; k++) {
+    fr_t limb = FR_ZERO;
+    for (int j = 0; j < limb_bits; j++) {
+      size_t p = k * limb_bits + j;
+      if (p < FR_LIMBS * 32 && (ca.v[p / 32] >> (p % 32)) & 1) {
+        limb.v[j / 32] |= (uint32_t)1 << (j % 32);
+      }
+    }
+    if (limb_bits == 1) {
+      out[k] = limb.v[0] ? FR_ONE : FR_ZERO;
+    } else {
+      fr_to_mont(&out[k], &limb);
+    }
+  }
+}
+
+// Bigint operations of the gadgets. Numbers are little-endian arrays of
+// 32-bit words, the operands of the same operation have the same length.
+
+// Number of words for the value of limbs_num limbs of limb_bits bits. Limbs
+// are field elements and can be larger than 2^limb_bits.
+static size_t big_words(size_t limbs_num, int limb_bits) {
+  return ((limbs_num - 1) * limb_bits + FR_BITS) / 32 + 2;
+}
+
+static int big_is_zero(const uint32_t *a, size_t words) {
+  for (size_t i = 0; i < words; i++) {
+    if (a[i] != 0) {
+      return 0;
+    }
+  }
+  return 1;
+}
+
+static int big_cmp(const uint32_t *a, const uint32_t *b, size_t words) {
+  for (size_t i = words; i-- > 0;) {
+    if (a[i] != b[i]) {
+      return a[i] < b[i] ? -1 : 1;
+    }
+  }
+  return 0;
+}
+
+// a -= b modulo 2^(32 * words)
+static void big_sub(uint32_t *a, const uint32_t *b, size_t words) {
+  uint64_t borrow = 0;
+  for (size_t i = 0; i < words; i++) {
+    uint64_t t = (uint64_t)a[i] - b[i] - borrow;
+    a[i] = (uint32_t)t;
+    borrow = (t >> 32) & 1;
+  }
+}
+
+// r = value of the limbs, the least significant limb first
+static void big_from_limbs(uint32_t *r, size_t words, const fr_t *const *limbs,
+                           size_t limbs_num, int limb_bits) {
+  memset(r, 0, words * sizeof(uint32_t));
+  for (size_t i = 0; i < limbs_num; i++) {
+    fr_t c;
+    fr_from_mont(&c, limbs[i]);
+    size_t w0 = i * limb_bits / 32;
+    int b = (int)(i * limb_bits % 32);
+    uint64_t carry = 0;
+    for (size_t j = w0; j < words; j++) {
+      size_t src = j - w0;
+      if (src > FR_LIMBS && carry == 0) {
+        break;
+      }
+      uint32_t x = src < FR_LIMBS ? c.v[src] << b : 0;
+      if (b && src >= 1 && src - 1 < FR_LIMBS) {
+        x |= c.v[src - 1] >> (32 - b);
+      }
+      uint64_t t = (uint64_t)r[j] + x + carry;
+      r[j] = (uint32_t)t;
+      carry = t >> 32;
+    }
+  }
+}
+
+static void big_to_limbs(fr_t *out, const uint32_t *a, size_t words,
+                         int limb_bits, size_t limbs_num) {
+  for (size_t k = 0; k < limbs_num; k++) {
+    fr_t limb = FR_ZERO;
+    for (int j = 0; j < limb_bits; j++) {
+      size_t p = k * limb_bits + j;
+      if (p < words * 32 && (a[p / 32] >> (p % 32)) & 1) {
+        limb.v[j / 32] |= (uint32_t)1 << (j % 32);
+      }
+    }
+    fr_to_mont(&out[k], &limb);
+  }
+}
+
+// r = a * b, r has 2 * words words
+static void big_mul(uint32_t *r, const uint32_t *a, const uint32_t *b,
+                    size_t words) {
+  memset(r, 0, 2 * words * sizeof(uint32_t));
+  for (size_t i = 0; i < words; i++) {
+    uint64_t carry = 0;
+    for (size_t j = 0; j < words; j++) {
+      uint64_t t = (uint64_t)a[i] * b[j] + r[i + j] + carry;
+      r[i + j] = (uint32_t)t;
+      carry = t >> 32;
+    }
+    r[i + words] = (uint32_t)carry;
+  }
+}
+
+// q = a / b, rem = a % b, both are zero if b is zero
+static void big_divmod(uint32_t *q, uint32_t *rem, const uint32_t *a,
+                       const uint32_t *b, size_t words) {
+  memset(q, 0, words * sizeof(uint32_t));
+  memset(rem, 0, words * sizeof(uint32_t));
+  if (big_is_zero(b, words)) {
+    return;
+  }
+  size_t top = words;
+  while (top > 0 && a[top - 1] == 0) {
+    top--;
+  }
+  for (size_t i = top * 32; i-- > 0;) {
+    uint32_t carry = (a[i / 32] >> (i % 32)) & 1;
+    for (size_t j = 0; j < words; j++) {
+      uint32_t t = rem[j] >> 31;
+      rem[j] = (rem[j] << 1) | carry;
+      carry = t;
+    }
+    if (carry || big_cmp(rem, b, words) >= 0) {
+      big_sub(rem, b, words);
+      q[i / 32] |= (uint32_t)1 << (i % 32);
+    }
+  }
+}
+
+// r = a^e mod p, zero if p is zero
+static void big_modpow(uint32_t *r, const uint32_t *a, const uint32_t *e,
+                       const uint32_t *p, size_t words) {
+  uint32_t *buf = calloc(10 * words, sizeof(uint32_t));
+  uint32_t *base = buf, *prod = buf + words, *p2 = buf + 3 * words;
+  uint32_t *q = buf + 5 * words, *rem = buf + 7 * words;
+  memcpy(p2, p, words * sizeof(uint32_t));
+
+  memset(r, 0, words * sizeof(uint32_t));
+  if (big_is_zero(p, words)) {
+    free(buf);
+    return;
+  }
+  memset(prod, 0, 2 * words * sizeof(uint32_t));
+  prod[0] = 1;
+  big_divmod(q, rem, prod, p2, 2 * words);
+  memcpy(r, rem, words * sizeof(uint32_t));
+  memcpy(prod, a, words * sizeof(uint32_t));
+  big_divmod(q, rem, prod, p2, 2 * words);
+  memcpy(base, rem, words * sizeof(uint32_t));
+
+  int started = 0;
+  for (size_t i = words * 32; i-- > 0;) {
+    int bit = (e[i / 32] >> (i % 32)) & 1;
+    // skip the leading zero bits of the exponent
+    started |= bit;
+    if (!started) {
+      continue;
+    }
+    big_mul(prod, r, r, words);
+    big_divmod(q, rem, prod, p2, 2 * words);
+    memcpy(r, rem, words * sizeof(uint32_t));
+    if (bit) {
+      big_mul(prod, r, base, words);
+      big_divmod(q, rem, prod, p2, 2 * words);
+      memcpy(r, rem, words * sizeof(uint32_t));
+    }
+  }
+  free(buf);
+}
+
+// prod(n, k, a, b): 2k limbs of a * b
+static void gadget_big_mul(const fr_t *const *args, int limb_bits,
+                           size_t limbs_num, fr_t *out) {
+  size_t words = big_words(limbs_num, limb_bits);
+  uint32_t *buf = calloc(4 * words, sizeof(uint32_t));
+  uint32_t *a = buf, *b = buf + words, *r = buf + 2 * words;
+  big_from_limbs(a, words, args, limbs_num, limb_bits);
+  big_from_limbs(b, words, args + limbs_num, limbs_num, limb_bits);
+  big_mul(r, a, b, words);
+  big_to_limbs(out, r, 2 * words, limb_bits, 2 * limbs_num);
+  free(buf);
+}
+
+// long_div(n, k, m, a, b): m + 1 limbs of a / b and k limbs of a % b
+static void gadget_big_divmod(const fr_t *const *args, int limb_bits,
+                              size_t limbs_num, size_t extra_limbs,
+                              fr_t *out) {
+  size_t a_limbs = limbs_num + extra_limbs;
+  size_t words = big_words(a_limbs, limb_bits);
+  uint32_t *buf = calloc(4 * words, sizeof(uint32_t));
+  uint32_t *a = buf, *b = buf + words, *q = buf + 2 * words;
+  uint32_t *rem = buf + 3 * words;
+  big_from_limbs(a, words, args, a_limbs, limb_bits);
+  big_from_limbs(b, words, args + a_limbs, limbs_num, limb_bits);
+  big_divmod(q, rem, a, b, words);
+  big_to_limbs(out, q, words, limb_bits, extra_limbs + 1);
+  big_to_limbs(out + extra_limbs + 1, rem, words, limb_bits, limbs_num);
+  free(buf);
+}
+
+// mod_exp(n, k, a, p, e): k limbs of a^e mod p
+static void gadget_big_modexp(const fr_t *const *args, int limb_bits,
+                              size_t limbs_num, fr_t *out) {
+  size_t words = big_words(limbs_num, limb_bits);
+  uint32_t *buf = calloc(4 * words, sizeof(uint32_t));
+  uint32_t *a = buf, *p = buf + words, *e = buf + 2 * words;
+  uint32_t *r = buf + 3 * words;
+  big_from_limbs(a, words, args, limbs_num, limb_bits);
+  big_from_limbs(p, words, args + limbs_num, limbs_num, limb_bits);
+  big_from_limbs(e, words, args + 2 * limbs_num, limbs_num, limb_bits);
+  big_modpow(r, a, e, p, words);
+  big_to_limbs(out, r, words, limb_bits, limbs_num);
+  free(buf);
+}
+
+// mod_inv(n, k, a, p): k limbs of a^(p-2) mod p
+static void gadget_big_modinv(const fr_t *const *args, int limb_bits,
+                              size_t limbs_num, fr_t *out) {
+  size_t words = big_words(limbs_num, limb_bits);
+  uint32_t *buf = calloc(4 * words, sizeof(uint32_t));
+  uint32_t *a = buf, *p = buf + words, *e = buf + 2 * words;
+  uint32_t *r = buf + 3 * words;
+  big_from_limbs(a, words, args, limbs_num, limb_bits);
+  big_from_limbs(p, words, args + limbs_num, limbs_num, limb_bits);
+  // e = p - 2, or 0 if p < 2
+  memcpy(e, p, words * sizeof(uint32_t));
+  memset(r, 0, words * sizeof(uint32_t));
+  r[0] = 2;
+  if (big_cmp(p, r, words) >= 0) {
+    big_sub(e, r, words);
+  } else {
+    memset(e, 0, words * sizeof(uint32_t));
+  }
+  big_modpow(r, a, e, p, words);
+  big_to_limbs(out, r, words, limb_bits, limbs_num);
+  free(buf);
+}
+
+#ifndef RUST_GRAPH_WITNESS_H
+#define RUST_GRAPH_WITNESS_H
+
+typedef enum {
+  OK = 0,
+  ERROR = 1
+} GW_ERROR_CODE;
+
+typedef struct {
+  GW_ERROR_CODE code;
+  char *error_msg;
+} gw_status_t;
+
+#endif // RUST_GRAPH_WITNESS_H
+
+static void prepare_status(const gw_status_t *status, GW_ERROR_CODE code,
+                           const char *msg) {
+  gw_status_t *st = (gw_status_t *)status;
+  if (st == NULL) {
+    return;
+  }
+  size_t ln = strlen(msg);
+  st->code = code;
+  st->error_msg = (char *)malloc(ln + 1);
+  if (st->error_msg != NULL) {
+    memcpy(st->error_msg, msg, ln + 1);
+  }
+}
+
+// Minimal JSON parser for the inputs object: keys are signal names, values
+// are numbers, decimal strings or arrays of them.
+
+typedef struct {
+  const char *s;
+  const char *err;
+} json_t;
+
+static void json_ws(json_t *j) {
+  while (*j->s == ' ' || *j->s == '\t' || *j->s == '\n' || *j->s == '\r') {
+    j->s++;
+  }
+}
+
+static int json_expect(json_t *j, char c) {
+  json_ws(j);
+  if (*j->s != c) {
+    j->err = "unexpected character in inputs JSON";
+    return 0;
+  }
+  j->s++;
+  return 1;
+}
+
+// Parse a string without escapes into buf.
+static int json_string(json_t *j, char *buf, size_t buf_len) {
+  if (!json_expect(j, '"')) {
+    return 0;
+  }
+  size_t n = 0;
+  while (*j->s != '"') {
+    if (*j->s == '\0' || *j->s == '\\') {
+      j->err = "unsupported string in inputs JSON";
+      return 0;
+    }
+    if (n + 1 >= buf_len) {
+      j->err = "string is too long in inputs JSON";
+      return 0;
+    }
+    buf[n++] = *j->s++;
+  }
+  buf[n] = '\0';
+  j->s++;
+  return 1;
+}
+
+// Parse a decimal number (bare or quoted) and convert it to Montgomery form.
+static int json_number(json_t *j, fr_t *r) {
+  json_ws(j);
+  int quoted = *j->s == '"';
+  if (quoted) {
+    j->s++;
+  }
+  if (*j->s < '0' || *j->s > '9') {
+    j->err = "signal value is not a positive integer";
+    return 0;
+  }
+  fr_t x = FR_ZERO;
+  while (*j->s >= '0' && *j->s <= '9') {
+    uint64_t carry = (uint64_t)(*j->s - '0');
+    for (int i = 0; i < FR_LIMBS; i++) {
+      carry += (uint64_t)x.v[i] * 10;
+      x.v[i] = (uint32_t)carry;
+      carry >>= 32;
+    }
+    if (carry) {
+      j->err = "signal value does not fit into 256 bits";
+      return 0;
+    }
+    j->s++;
+  }
+  if (quoted && !json_expect(j, '"')) {
+    return 0;
+  }
+  raw_reduce(&x);
+  fr_to_mont(r, &x);
+  return 1;
+}
+
+static int input_signal(const char *name, size_t *offset, size_t *len) {
+  for (size_t i = 0; i < INPUT_SIGNALS_NUM; i++) {
+    if (strcmp(INPUT_SIGNALS[i].name, name) == 0) {
+      *offset = INPUT_SIGNALS[i].offset;
+      *len = INPUT_SIGNALS[i].len;
+      return 1;
+    }
+  }
+  return 0;
+}
+
+static int parse_inputs(json_t *j, fr_t *inputs) {
+  char name[1024];
+  if (!json_expect(j, '{')) {
+    return 0;
+  }
+  json_ws(j);
+  if (*j->s == '}') {
+    j->s++;
+    return 1;
+  }
+  while (1) {
+    size_t offset, len, n = 0;
+    if (!json_string(j, name, sizeof(name)) || !json_expect(j, ':')) {
+      return 0;
+    }
+    if (!input_signal(name, &offset, &len)) {
+      j->err = "unknown input signal";
+      return 0;
+    }
+    json_ws(j);
+    if (*j->s == '[') {
+      j->s++;
+      json_ws(j);
+      if (*j->s == ']') {
+        j->s++;
+      } else {
+        while (1) {
+          fr_t v;
+          if (!json_number(j, &v)) {
+            return 0;
+          }
+          if (n < len) {
+            inputs[offset + n] = v;
+          }
+          n++;
+          json_ws(j);
+          if (*j->s == ']') {
+            j->s++;
+            break;
+          }
+          if (!json_expect(j, ',')) {
+            return 0;
+          }
+        }
+      }
+    } else {
+      if (!json_number(j, &inputs[offset])) {
+        return 0;
+      }
+      n = 1;
+    }
+    if (n != len) {
+      j->err = "invalid input length";
+      return 0;
+    }
+    json_ws(j);
+    if (*j->s == '}') {
+      j->s++;
+      return 1;
+    }
+    if (!json_expect(j, ',')) {
+      return 0;
+    }
+  }
+}
+
+static void calc_nodes(const fr_t *inputs, fr_t *v);
+
+static void put_u32(uint8_t **p, uint32_t x) {
+  for (int i = 0; i < 4; i++) {
+    *(*p)++ = (uint8_t)(x >> (8 * i));
+  }
+}
+
+static void put_u64(uint8_t **p, uint64_t x) {
+  for (int i = 0; i < 8; i++) {
+    *(*p)++ = (uint8_t)(x >> (8 * i));
+  }
+}
+
+static void put_fr(uint8_t **p, const fr_t *x) {
+  for (int i = 0; i < FR_LIMBS; i++) {
+    put_u32(p, x->v[i]);
+  }
+}
+
+int gw_calc_witness(const char *inputs_json, const void *graph_data,
+                    const size_t graph_data_len, void **wtns_data,
+                    size_t *wtns_len, const gw_status_t *status) {
+  // The graph is compiled in, graph_data is not used.
+  (void)graph_data;
+  (void)graph_data_len;
+
+  if (inputs_json == NULL) {
+    prepare_status(status, ERROR, "inputs is null");
+    return 1;
+  }
+
+  fr_t *inputs = (fr_t *)calloc(INPUTS_NUM, sizeof(fr_t));
+  fr_t *v = (fr_t *)malloc((NODES_NUM > 0 ? NODES_NUM : 1) * sizeof(fr_t));
+  if (inputs == NULL || v == NULL) {
+    free(inputs);
+    free(v);
+    prepare_status(status, ERROR, "failed to allocate memory");
+    return 1;
+  }
+  inputs[0] = FR_ONE;
+
+  json_t j = {inputs_json, NULL};
+  if (!parse_inputs(&j, inputs)) {
+    free(inputs);
+    free(v);
+    prepare_status(status, ERROR, j.err);
+    return 1;
+  }
+
+  calc_nodes(inputs, v);
+  free(inputs);
+
+  // wtns file: header, section 1 with the field description and section 2
+  // with the witness values
+  size_t n8 = FR_LIMBS * 4;
+  size_t ln = 4 + 4 + 4 + (4 + 8 + 4 + n8 + 4) + (4 + 8 + n8 * WITNESS_NUM);
+  uint8_t *buf = (uint8_t *)malloc(ln);
+  if (buf == NULL) {
+    free(v);
+    prepare_status(status, ERROR, "failed to allocate memory for wtns_data");
+    return 1;
+  }
+  uint8_t *p = buf;
+  memcpy(p, "wtns", 4);
+  p += 4;
+  put_u32(&p, 2);
+  put_u32(&p, 2);
+
+  put_u32(&p, 1);
+  put_u64(&p, 4 + n8 + 4);
+  put_u32(&p, (uint32_t)n8);
+  put_fr(&p, &FR_P);
+  put_u32(&p, (uint32_t)WITNESS_NUM);
+
+  put_u32(&p, 2);
+  put_u64(&p, (uint64_t)(n8 * WITNESS_NUM));
+  for (size_t i = 0; i < WITNESS_NUM; i++) {
+    fr_t x;
+    fr_from_mont(&x, &v[WITNESS_SIGNALS[i]]);
+    put_fr(&p, &x);
+  }
+  free(v);
+
+  *wtns_data = buf;
+  *wtns_len = ln;
+  prepare_status(status, OK, "");
+  return 0;
+}
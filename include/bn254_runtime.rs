@@ -0,0 +1,422 @@
+// BN254 scalar field operations and native gadgets for the Rust code
+// generated by graph2rust. The module depends only on ark-bn254 and ark-ff.
+// The operations have the same semantics as the interpreter: comparisons
+// treat values greater than (p-1)/2 as negative, integer operations work on
+// the canonical values, division by zero returns zero. The generator appends
+// the graph constants and the `witness` function.
+
+#![allow(dead_code, unused_imports, clippy::all)]
+
+use std::cmp::Ordering;
+use ark_bn254::Fr;
+use ark_ff::{BigInt, BigInteger, Field, MontFp, One, PrimeField, Zero};
+
+type Int = BigInt<4>;
+
+fn from_bool(b: bool) -> Fr {
+    if b { Fr::one() } else { Fr::zero() }
+}
+
+// Reduce the value less than 2^256 modulo p.
+fn reduce(mut a: Int) -> Fr {
+    if a >= Fr::MODULUS {
+        a.sub_with_borrow(&Fr::MODULUS);
+    }
+    Fr::from_bigint(a).unwrap()
+}
+
+fn fr_div(a: Fr, b: Fr) -> Fr {
+    if b.is_zero() { Fr::zero() } else { a / b }
+}
+
+fn fr_pow(a: Fr, b: Fr) -> Fr {
+    a.pow(b.into_bigint())
+}
+
+fn fr_idiv(a: Fr, b: Fr) -> Fr {
+    let (q, _) = big_divmod(&a.into_bigint().0, &b.into_bigint().0);
+    Fr::from_bigint(BigInt([q[0], q[1], q[2], q[3]])).unwrap()
+}
+
+fn fr_mod(a: Fr, b: Fr) -> Fr {
+    let (_, r) = big_divmod(&a.into_bigint().0, &b.into_bigint().0);
+    Fr::from_bigint(BigInt([r[0], r[1], r[2], r[3]])).unwrap()
+}
+
+fn fr_eq(a: Fr, b: Fr) -> Fr {
+    from_bool(a == b)
+}
+
+fn fr_neq(a: Fr, b: Fr) -> Fr {
+    from_bool(a != b)
+}
+
+// Compare as signed values: values greater than (p-1)/2 are negative.
+fn signed_cmp(a: Fr, b: Fr) -> Ordering {
+    let (a, b) = (a.into_bigint(), b.into_bigint());
+    let half = Fr::MODULUS_MINUS_ONE_DIV_TWO;
+    match (a > half, b > half) {
+        (true, false) => Ordering::Less,
+        (false, true) => Ordering::Greater,
+        _ => a.cmp(&b),
+    }
+}
+
+fn fr_lt(a: Fr, b: Fr) -> Fr {
+    from_bool(signed_cmp(a, b) == Ordering::Less)
+}
+
+fn fr_gt(a: Fr, b: Fr) -> Fr {
+    from_bool(signed_cmp(a, b) == Ordering::Greater)
+}
+
+fn fr_leq(a: Fr, b: Fr) -> Fr {
+    from_bool(signed_cmp(a, b) != Ordering::Greater)
+}
+
+fn fr_geq(a: Fr, b: Fr) -> Fr {
+    from_bool(signed_cmp(a, b) != Ordering::Less)
+}
+
+fn fr_land(a: Fr, b: Fr) -> Fr {
+    from_bool(!a.is_zero() && !b.is_zero())
+}
+
+fn fr_lor(a: Fr, b: Fr) -> Fr {
+    from_bool(!a.is_zero() || !b.is_zero())
+}
+
+// Shift amount if it is less than the modulus bit size, otherwise None.
+fn shift_amount(b: Fr) -> Option<u32> {
+    let b = b.into_bigint();
+    if b >= Int::from(Fr::MODULUS_BIT_SIZE as u64) {
+        return None;
+    }
+    Some(b.0[0] as u32)
+}
+
+// As in circom, the result is truncated to the modulus bit size and reduced.
+fn fr_shl(a: Fr, b: Fr) -> Fr {
+    let Some(n) = shift_amount(b) else {
+        return Fr::zero();
+    };
+    let mut r = a.into_bigint();
+    r.muln(n);
+    r.0[3] &= (1u64 << (Fr::MODULUS_BIT_SIZE - 192)) - 1;
+    reduce(r)
+}
+
+fn fr_shr(a: Fr, b: Fr) -> Fr {
+    let Some(n) = shift_amount(b) else {
+        return Fr::zero();
+    };
+    let mut r = a.into_bigint();
+    r.divn(n);
+    Fr::from_bigint(r).unwrap()
+}
+
+fn bitwise(a: Fr, b: Fr, f: impl Fn(u64, u64) -> u64) -> Fr {
+    let (a, b) = (a.into_bigint(), b.into_bigint());
+    reduce(BigInt([
+        f(a.0[0], b.0[0]), f(a.0[1], b.0[1]), f(a.0[2], b.0[2]),
+        f(a.0[3], b.0[3])]))
+}
+
+fn fr_band(a: Fr, b: Fr) -> Fr {
+    bitwise(a, b, |x, y| x & y)
+}
+
+fn fr_bor(a: Fr, b: Fr) -> Fr {
+    bitwise(a, b, |x, y| x | y)
+}
+
+fn fr_bxor(a: Fr, b: Fr) -> Fr {
+    bitwise(a, b, |x, y| x ^ y)
+}
+
+fn fr_tern_cond(a: Fr, b: Fr, c: Fr) -> Fr {
+    if a.is_zero() { c } else { b }
+}
+
+// Native gadgets.
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1,
+    0x923f82a4, 0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
+    0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786,
+    0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147,
+    0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+    0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+    0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a,
+    0x5b9cca4f, 0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+    0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2];
+
+const SHA256_H: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c,
+    0x1f83d9ab, 0x5be0cd19];
+
+// Process one block, the first 16 words of w are the block.
+fn sha256_compress(h: &mut [u32; 8], w: &mut [u32; 64]) {
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18)
+            ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19)
+            ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7])
+            .wrapping_add(s1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = *h;
+    for i in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = hh.wrapping_add(s1).wrapping_add(ch)
+            .wrapping_add(SHA256_K[i]).wrapping_add(w[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+        hh = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+
+    for (x, y) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+        *x = x.wrapping_add(y);
+    }
+}
+
+// circomlib Sha256(n): n message bits, 256 digest bits, most significant bit
+// first. A non-zero argument is treated as 1.
+fn gadget_sha256(args: &[Fr]) -> Vec<Fr> {
+    // Padding: bit 1, zeros up to 448 mod 512 and 64-bit message length.
+    let blocks_num = (args.len() + 64) / 512 + 1;
+    let mut padded = vec![false; blocks_num * 512];
+    for (p, a) in padded.iter_mut().zip(args) {
+        *p = !a.is_zero();
+    }
+    padded[args.len()] = true;
+    let ln = args.len() as u64;
+    for i in 0..64 {
+        padded[blocks_num * 512 - 1 - i] = (ln >> i) & 1 == 1;
+    }
+
+    let mut h = SHA256_H;
+    for block in padded.chunks(512) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(32).enumerate() {
+            w[i] = word.iter().fold(0u32, |acc, &b| (acc << 1) | b as u32);
+        }
+        sha256_compress(&mut h, &mut w);
+    }
+
+    h.iter()
+        .flat_map(|&x| (0..32).rev().map(move |i| from_bool((x >> i) & 1 == 1)))
+        .collect()
+}
+
+// Limbs of limb_bits bits of the value, the least significant limb first.
+fn gadget_limbs(a: Fr, limb_bits: usize, limbs_num: usize) -> Vec<Fr> {
+    let a = a.into_bigint();
+    (0..limbs_num)
+        .map(|k| {
+            let shift = k * limb_bits;
+            if shift >= 256 {
+                return Fr::zero();
+            }
+            let mut limb = a;
+            limb.divn(shift as u32);
+            for (i, w) in limb.0.iter_mut().enumerate() {
+                if limb_bits <= 64 * i {
+                    *w = 0;
+                } else if limb_bits < 64 * (i + 1) {
+                    *w &= (1u64 << (limb_bits - 64 * i)) - 1;
+                }
+            }
+            Fr::from_bigint(limb).unwrap()
+        })
+        .collect()
+}
+
+// Bigint operations of the gadgets. Numbers are little-endian vectors of
+// 64-bit words.
+
+// Number of words for the value of limbs_num limbs of limb_bits bits. Limbs
+// are field elements and can be larger than 2^limb_bits.
+fn big_words(limbs_num: usize, limb_bits: usize) -> usize {
+    ((limbs_num - 1) * limb_bits + Fr::MODULUS_BIT_SIZE as usize) / 64 + 2
+}
+
+fn big_cmp(a: &[u64], b: &[u64]) -> Ordering {
+    a.iter().rev().cmp(b.iter().rev())
+}
+
+// a -= b modulo 2^(64 * a.len()), b is not longer than a
+fn big_sub(a: &mut [u64], b: &[u64]) {
+    let mut borrow = false;
+    for (i, x) in a.iter_mut().enumerate() {
+        let (t, b1) = x.overflowing_sub(b.get(i).copied().unwrap_or(0));
+        let (t, b2) = t.overflowing_sub(borrow as u64);
+        *x = t;
+        borrow = b1 || b2;
+    }
+}
+
+// Value of the limbs, the least significant limb first.
+fn big_from_limbs(limbs: &[Fr], limb_bits: usize, words: usize) -> Vec<u64> {
+    let mut r = vec![0u64; words];
+    for (i, limb) in limbs.iter().enumerate() {
+        let c = limb.into_bigint().0;
+        let w0 = i * limb_bits / 64;
+        let b = (i * limb_bits % 64) as u32;
+        let mut carry = 0u128;
+        for j in w0..words {
+            let src = j - w0;
+            if src > c.len() && carry == 0 {
+                break;
+            }
+            let mut x = if src < c.len() { c[src] << b } else { 0 };
+            if b > 0 && src >= 1 && src - 1 < c.len() {
+                x |= c[src - 1] >> (64 - b);
+            }
+            let t = r[j] as u128 + x as u128 + carry;
+            r[j] = t as u64;
+            carry = t >> 64;
+        }
+    }
+    r
+}
+
+fn big_to_limbs(a: &[u64], limb_bits: usize, limbs_num: usize) -> Vec<Fr> {
+    (0..limbs_num)
+        .map(|k| {
+            let mut limb = Int::zero();
+            for j in 0..limb_bits {
+                let p = k * limb_bits + j;
+                if p < a.len() * 64 && (a[p / 64] >> (p % 64)) & 1 == 1 {
+                    limb.0[j / 64] |= 1u64 << (j % 64);
+                }
+            }
+            Fr::from_bigint(limb).unwrap()
+        })
+        .collect()
+}
+
+// a * b, the result has a.len() + b.len() words
+fn big_mul(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let mut r = vec![0u64; a.len() + b.len()];
+    for (i, &x) in a.iter().enumerate() {
+        let mut carry = 0u128;
+        for (j, &y) in b.iter().enumerate() {
+            let t = x as u128 * y as u128 + r[i + j] as u128 + carry;
+            r[i + j] = t as u64;
+            carry = t >> 64;
+        }
+        r[i + b.len()] = carry as u64;
+    }
+    r
+}
+
+// a / b and a % b with a.len() words each, both are zero if b is zero
+fn big_divmod(a: &[u64], b: &[u64]) -> (Vec<u64>, Vec<u64>) {
+    let mut q = vec![0u64; a.len()];
+    let mut rem = vec![0u64; a.len()];
+    if b.iter().all(|&x| x == 0) {
+        return (q, rem);
+    }
+    let mut b = b.to_vec();
+    b.resize(a.len().max(b.len()), 0);
+    rem.resize(b.len(), 0);
+    let top = a.iter().rposition(|&x| x != 0).map_or(0, |i| i + 1);
+    for i in (0..top * 64).rev() {
+        let mut carry = (a[i / 64] >> (i % 64)) & 1;
+        for w in rem.iter_mut() {
+            let t = *w >> 63;
+            *w = (*w << 1) | carry;
+            carry = t;
+        }
+        if carry == 1 || big_cmp(&rem, &b) != Ordering::Less {
+            big_sub(&mut rem, &b);
+            q[i / 64] |= 1u64 << (i % 64);
+        }
+    }
+    rem.truncate(a.len());
+    (q, rem)
+}
+
+// a^e mod p with p.len() words, zero if p is zero
+fn big_modpow(a: &[u64], e: &[u64], p: &[u64]) -> Vec<u64> {
+    let words = p.len();
+    if p.iter().all(|&x| x == 0) {
+        return vec![0u64; words];
+    }
+    let mut one = vec![0u64; words];
+    one[0] = 1;
+    let (_, mut r) = big_divmod(&one, p);
+    let (_, base) = big_divmod(a, p);
+    let top = e.iter().rposition(|&x| x != 0).map_or(0, |i| i + 1);
+    for i in (0..top * 64).rev() {
+        r = big_divmod(&big_mul(&r, &r), p).1;
+        r.truncate(words);
+        if (e[i / 64] >> (i % 64)) & 1 == 1 {
+            r = big_divmod(&big_mul(&r, &base), p).1;
+            r.truncate(words);
+        }
+    }
+    r
+}
+
+// prod(n, k, a, b): 2k limbs of a * b
+fn gadget_big_mul(args: &[Fr], limb_bits: usize, limbs_num: usize) -> Vec<Fr> {
+    let words = big_words(limbs_num, limb_bits);
+    let a = big_from_limbs(&args[..limbs_num], limb_bits, words);
+    let b = big_from_limbs(&args[limbs_num..], limb_bits, words);
+    big_to_limbs(&big_mul(&a, &b), limb_bits, 2 * limbs_num)
+}
+
+// long_div(n, k, m, a, b): m + 1 limbs of a / b and k limbs of a % b
+fn gadget_big_divmod(
+    args: &[Fr], limb_bits: usize, limbs_num: usize,
+    extra_limbs: usize) -> Vec<Fr> {
+
+    let a_limbs = limbs_num + extra_limbs;
+    let words = big_words(a_limbs, limb_bits);
+    let a = big_from_limbs(&args[..a_limbs], limb_bits, words);
+    let b = big_from_limbs(&args[a_limbs..], limb_bits, words);
+    let (q, r) = big_divmod(&a, &b);
+    let mut out = big_to_limbs(&q, limb_bits, extra_limbs + 1);
+    out.extend(big_to_limbs(&r, limb_bits, limbs_num));
+    out
+}
+
+// mod_exp(n, k, a, p, e): k limbs of a^e mod p, zero if p is zero
+fn gadget_big_modexp(args: &[Fr], limb_bits: usize, limbs_num: usize) -> Vec<Fr> {
+    let words = big_words(limbs_num, limb_bits);
+    let a = big_from_limbs(&args[..limbs_num], limb_bits, words);
+    let p = big_from_limbs(&args[limbs_num..2 * limbs_num], limb_bits, words);
+    let e = big_from_limbs(&args[2 * limbs_num..], limb_bits, words);
+    big_to_limbs(&big_modpow(&a, &e, &p), limb_bits, limbs_num)
+}
+
+// mod_inv(n, k, a, p): k limbs of a^(p-2) mod p
+fn gadget_big_modinv(args: &[Fr], limb_bits: usize, limbs_num: usize) -> Vec<Fr> {
+    let words = big_words(limbs_num, limb_bits);
+    let a = big_from_limbs(&args[..limbs_num], limb_bits, words);
+    let p = big_from_limbs(&args[limbs_num..], limb_bits, words);
+    let mut two = vec![0u64; words];
+    two[0] = 2;
+    let e = if big_cmp(&p, &two) == Ordering::Less {
+        vec![0u64; words]
+    } else {
+        let mut e = p.clone();
+        big_sub(&mut e, &two);
+        e
+    };
+    big_to_limbs(&big_modpow(&a, &e, &p), limb_bits, limbs_num)
+}
@@ -1,7 +1,7 @@
 use compiler::circuit_design::template::TemplateCode;
 use compiler::compiler_interface::{run_compiler, Circuit, Config};
 use compiler::intermediate_representation::ir_interface::{AddressType, CallBucket, ComputeBucket, CreateCmpBucket, FinalData, InputInformation, Instruction, InstructionPointer, LoadBucket, LocationRule, OperatorType, ReturnBucket, ReturnType, StatusInput, StoreBucket, ValueBucket, ValueType};
-use constraint_generation::{build_circuit, BuildConfig};
+use constraint_generation::{build_circuit, BuildConfig, ConstraintWriter};
 use program_structure::error_definition::Report;
 use ruint::aliases::U256;
 use ruint::uint;
@@ -9,30 +9,36 @@ use std::collections::HashMap;
 use std::{env, fmt, fs};
 use std::error::Error;
 use std::path::PathBuf;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use code_producers::c_elements::IODef;
 use code_producers::components::TemplateInstanceIOMap;
 use compiler::circuit_design::function::FunctionCode;
 use lazy_static::lazy_static;
 use type_analysis::check_types::check_types;
 use circom_witnesscalc::{deserialize_inputs, InputSignalsInfo};
-use circom_witnesscalc::graph::{optimize, Node, Operation, UnoOperation, TresOperation, Nodes, NodeConstErr, NodeIdx};
-use circom_witnesscalc::storage::serialize_witnesscalc_graph;
+use circom_witnesscalc::gadgets::{Gadget, GADGET_FUNCTIONS, GADGET_TEMPLATES};
+use circom_witnesscalc::graph::{allocate_slots, evaluate, evaluate_reference, OptLevel, PassManager, Node, Operation, UnoOperation, TresOperation, Nodes, NodeConstErr, NodeIdx};
+use circom_witnesscalc::storage::{graph_metadata, serialize_witnesscalc_graph_with_metadata};
+use circom_witnesscalc::symbols::SymbolTable;
+use circom_witnesscalc::proto::PublicSignals;
 
 pub const M: U256 =
     uint!(21888242871839275222246405745257275088548364400416034343698204186575808495617_U256);
 
 // if instruction pointer is a store to the signal, return the signal index
 // and the src instruction to store to the signal
+#[allow(clippy::too_many_arguments)]
 fn try_signal_store<'a>(
     inst: &'a InstructionPointer,
     nodes: &mut Nodes,
     vars: &mut Vec<Option<Var>>,
     component_signal_start: usize,
     signal_node_idx: &mut Vec<usize>,
-    subcomponents: &Vec<Option<ComponentInstance>>,
+    subcomponents: &[Option<ComponentInstance>],
     io_map: &TemplateInstanceIOMap,
     print_debug: bool,
-    call_stack: &Vec<String>,
+    call_stack: &[String],
 ) -> Option<(usize, &'a InstructionPointer)> {
     let store_bucket = match **inst {
         Instruction::Store(ref store_bucket) => store_bucket,
@@ -65,7 +71,7 @@ fn try_signal_store<'a>(
 
 fn var_from_value_instruction_n(
     value_bucket: &ValueBucket, nodes: &Nodes, n: usize,
-    call_stack: &Vec<String>) -> Vec<Var> {
+    call_stack: &[String]) -> Vec<Var> {
 
     match value_bucket.parse_as {
         ValueType::BigInt => {
@@ -92,17 +98,18 @@ fn var_from_value_instruction_n(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn operator_argument_instruction_n(
     inst: &InstructionPointer,
     nodes: &mut Nodes,
     signal_node_idx: &mut Vec<usize>,
     vars: &mut Vec<Option<Var>>,
     component_signal_start: usize,
-    subcomponents: &Vec<Option<ComponentInstance>>,
+    subcomponents: &[Option<ComponentInstance>],
     size: usize,
     io_map: &TemplateInstanceIOMap,
     print_debug: bool,
-    call_stack: &Vec<String>,
+    call_stack: &[String],
 ) -> Vec<usize> {
     assert!(size > 0, "size = {}", size);
 
@@ -141,7 +148,7 @@ fn operator_argument_instruction_n(
                                 component_signal_start, signal_idx, i);
                             result.push(signal_node);
                         }
-                        return result;
+                        result
                     }
                     LocationRule::Mapped { .. } => {
                         todo!()
@@ -174,7 +181,7 @@ fn operator_argument_instruction_n(
                         LocationRule::Mapped { ref signal_code, ref indexes } => {
                             calc_mapped_signal_idx(
                                 subcomponents, subcomponent_idx, io_map,
-                                signal_code.clone(), indexes, nodes, vars,
+                                *signal_code, indexes, nodes, vars,
                                 component_signal_start, signal_node_idx,
                                 print_debug, call_stack)
                         }
@@ -229,7 +236,7 @@ fn operator_argument_instruction_n(
                             },
                             Some(Var::Value(ref v)) => {
                                 result.push(
-                                    nodes.push(Node::Constant(v.clone())).0);
+                                    nodes.push(Node::Constant(*v)).0);
                             }
                             None => { panic!("variable is not set: {}, {:?}",
                                              load_bucket.line, call_stack); }
@@ -246,16 +253,17 @@ fn operator_argument_instruction_n(
 }
 
 
+#[allow(clippy::too_many_arguments)]
 fn operator_argument_instruction(
     inst: &InstructionPointer,
     nodes: &mut Nodes,
     signal_node_idx: &mut Vec<usize>,
     vars: &mut Vec<Option<Var>>,
     component_signal_start: usize,
-    subcomponents: &Vec<Option<ComponentInstance>>,
+    subcomponents: &[Option<ComponentInstance>],
     io_map: &TemplateInstanceIOMap,
     print_debug: bool,
-    call_stack: &Vec<String>,
+    call_stack: &[String],
 ) -> usize {
     match **inst {
         Instruction::Load(ref load_bucket) => {
@@ -277,7 +285,7 @@ fn operator_argument_instruction(
                         let signal_idx = component_signal_start + signal_idx;
                         let signal_node = signal_node_idx[signal_idx];
                         assert_ne!(signal_node, usize::MAX, "signal is not set yet");
-                        return signal_node;
+                        signal_node
                     }
                     LocationRule::Mapped { .. } => {
                         todo!()
@@ -311,7 +319,7 @@ fn operator_argument_instruction(
                         LocationRule::Mapped { ref signal_code, ref indexes } => {
                             calc_mapped_signal_idx(
                                 subcomponents, subcomponent_idx, io_map,
-                                signal_code.clone(), indexes, nodes, vars,
+                                *signal_code, indexes, nodes, vars,
                                 component_signal_start, signal_node_idx,
                                 print_debug, call_stack)
                         }
@@ -330,7 +338,7 @@ fn operator_argument_instruction(
                     let signal_idx = signal_offset + signal_idx;
                     let signal_node = signal_node_idx[signal_idx];
                     assert_ne!(signal_node, usize::MAX, "signal is not set yet");
-                    return signal_node;
+                    signal_node
                 }
                 AddressType::Variable => {
                     match load_bucket.src {
@@ -345,7 +353,7 @@ fn operator_argument_instruction(
                             match vars[var_idx] {
                                 Some(Var::Node(idx)) => idx,
                                 Some(Var::Value(ref v)) => {
-                                    nodes.push(Node::Constant(v.clone())).0
+                                    nodes.push(Node::Constant(*v)).0
                                 }
                                 None => { panic!("variable is not set"); }
                             }
@@ -421,16 +429,17 @@ lazy_static! {
     };
 }
 
+#[allow(clippy::too_many_arguments)]
 fn node_from_compute_bucket(
     compute_bucket: &ComputeBucket,
     nodes: &mut Nodes,
     signal_node_idx: &mut Vec<usize>,
     vars: &mut Vec<Option<Var>>,
     component_signal_start: usize,
-    subcomponents: &Vec<Option<ComponentInstance>>,
+    subcomponents: &[Option<ComponentInstance>],
     io_map: &TemplateInstanceIOMap,
     print_debug: bool,
-    call_stack: &Vec<String>,
+    call_stack: &[String],
 ) -> Node {
     if let Some(op) = DUO_OPERATORS_MAP.get(&compute_bucket.op) {
         let arg1 = operator_argument_instruction(
@@ -441,27 +450,28 @@ fn node_from_compute_bucket(
             &compute_bucket.stack[1], nodes, signal_node_idx, vars,
             component_signal_start, subcomponents, io_map, print_debug,
             call_stack);
-        return Node::Op(op.clone(), arg1, arg2);
+        return Node::Op(*op, arg1, arg2);
     }
     if let Some(op) = UNO_OPERATORS_MAP.get(&compute_bucket.op) {
         let arg1 = operator_argument_instruction(
             &compute_bucket.stack[0], nodes, signal_node_idx, vars,
             component_signal_start, subcomponents, io_map, print_debug,
             call_stack);
-        return Node::UnoOp(op.clone(), arg1);
+        return Node::UnoOp(*op, arg1);
     }
     panic!(
         "not implemented: this operator is not supported to be converted to Node: {}",
         compute_bucket.to_string());
 }
 
+#[allow(clippy::too_many_arguments)]
 fn calc_mapped_signal_idx(
-    subcomponents: &Vec<Option<ComponentInstance>>,
+    subcomponents: &[Option<ComponentInstance>],
     subcomponent_idx: usize, io_map: &TemplateInstanceIOMap, signal_code: usize,
-    indexes: &Vec<InstructionPointer>, nodes: &mut Nodes,
+    indexes: &[InstructionPointer], nodes: &mut Nodes,
     vars: &mut Vec<Option<Var>>, component_signal_start: usize,
     signal_node_idx: &mut Vec<usize>, print_debug: bool,
-    call_stack: &Vec<String>) -> (usize, String) {
+    call_stack: &[String]) -> (usize, String) {
 
     let template_id = &subcomponents[subcomponent_idx]
         .as_ref()
@@ -509,18 +519,20 @@ fn calc_mapped_signal_idx(
     (map_access, template_def)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn process_instruction(
     inst: &InstructionPointer,
     nodes: &mut Nodes,
     signal_node_idx: &mut Vec<usize>,
     vars: &mut Vec<Option<Var>>,
     subcomponents: &mut Vec<Option<ComponentInstance>>,
-    templates: &Vec<TemplateCode>,
-    functions: &Vec<FunctionCode>,
+    templates: &[TemplateCode],
+    functions: &[FunctionCode],
+    gadgets: &mut Gadgets,
     component_signal_start: usize,
     io_map: &TemplateInstanceIOMap,
     print_debug: bool,
-    call_stack: &Vec<String>,
+    call_stack: &[String],
 ) {
     match **inst {
         Instruction::Value(..) => {
@@ -626,7 +638,7 @@ fn process_instruction(
                         cmp_address, input_information, nodes, vars,
                         component_signal_start, signal_node_idx, subcomponents,
                         io_map, &node_idxs, &store_bucket.dest,
-                        store_bucket.context.size, templates, functions,
+                        store_bucket.context.size, templates, functions, gadgets,
                         print_debug, call_stack);
                 }
             };
@@ -637,9 +649,8 @@ fn process_instruction(
         Instruction::Call(ref call_bucket) => {
             let mut fn_vars: Vec<Option<Var>> = vec![None; call_bucket.arena_size];
 
-            let mut idx = 0;
             let mut count: usize = 0;
-            for inst2 in &call_bucket.arguments {
+            for (idx, inst2) in call_bucket.arguments.iter().enumerate() {
                 let args = calc_expression_n(
                     inst2, nodes, vars, component_signal_start, signal_node_idx,
                     subcomponents, call_bucket.argument_types[idx].size,
@@ -648,12 +659,11 @@ fn process_instruction(
                     fn_vars[count] = Some(arg);
                     count += 1;
                 }
-                idx += 1;
             }
 
             let r = run_function(
-                call_bucket, functions, &mut fn_vars, nodes, print_debug,
-                call_stack);
+                call_bucket, functions, gadgets, &mut fn_vars, nodes,
+                print_debug, call_stack);
 
             match call_bucket.return_info {
                 ReturnType::Intermediate{ ..} => { todo!(); }
@@ -665,7 +675,7 @@ fn process_instruction(
                     store_function_return_results(
                         final_data, &fn_vars, &r, vars, nodes,
                         component_signal_start, signal_node_idx,
-                        subcomponents, io_map, templates, functions,
+                        subcomponents, io_map, templates, functions, gadgets,
                         print_debug, call_stack);
                 }
             }
@@ -685,7 +695,7 @@ fn process_instruction(
                     for inst in inst_list {
                         process_instruction(
                             inst, nodes, signal_node_idx, vars, subcomponents,
-                            templates, functions, component_signal_start,
+                            templates, functions, gadgets, component_signal_start,
                             io_map, print_debug, call_stack);
                     }
                 }
@@ -768,7 +778,7 @@ fn process_instruction(
                 for i in &loop_bucket.body {
                     process_instruction(
                         i, nodes, signal_node_idx, vars, subcomponents,
-                        templates, functions, component_signal_start, io_map,
+                        templates, functions, gadgets, component_signal_start, io_map,
                         print_debug, call_stack);
                 }
             }
@@ -791,11 +801,13 @@ fn process_instruction(
 
             let mut cmp_signal_offset = create_component_bucket.signal_offset;
 
-            for i in sub_cmp_idx..sub_cmp_idx + create_component_bucket.number_of_cmp {
-                if let Some(_) = subcomponents[i] {
+            let cmp_range =
+                sub_cmp_idx..sub_cmp_idx + create_component_bucket.number_of_cmp;
+            for subcomponent in subcomponents[cmp_range.clone()].iter_mut() {
+                if subcomponent.is_some() {
                     panic!("subcomponent already set");
                 }
-                subcomponents[i] = Some(ComponentInstance {
+                *subcomponent = Some(ComponentInstance {
                     template_id: create_component_bucket.template_id,
                     signal_offset: component_signal_start + cmp_signal_offset,
                     number_of_inputs: templates[create_component_bucket.template_id]
@@ -808,17 +820,17 @@ fn process_instruction(
                     "{}",
                     fmt_create_cmp_bucket(
                         create_component_bucket, nodes, vars,
-                        component_signal_start, signal_node_idx, &subcomponents,
+                        component_signal_start, signal_node_idx, subcomponents,
                         io_map, print_debug, call_stack));
             }
             if !create_component_bucket.has_inputs {
-                for i in sub_cmp_idx..sub_cmp_idx + create_component_bucket.number_of_cmp {
+                for subcomponent in subcomponents[cmp_range].iter() {
+                    let subcomponent = subcomponent.as_ref().unwrap();
                     run_template(
-                        templates, functions,
-                        subcomponents[i].as_ref().unwrap().template_id, nodes,
-                        signal_node_idx,
-                        subcomponents[i].as_ref().unwrap().signal_offset,
-                        io_map, print_debug, call_stack)
+                        templates, functions, gadgets,
+                        subcomponent.template_id, nodes, signal_node_idx,
+                        subcomponent.signal_offset, io_map, print_debug,
+                        call_stack)
                 }
             }
         }
@@ -826,9 +838,9 @@ fn process_instruction(
 }
 
 fn store_function_return_results_into_variable(
-    final_data: &FinalData, src_vars: &Vec<Option<Var>>, ret: &FnReturn,
+    final_data: &FinalData, src_vars: &[Option<Var>], ret: &FnReturn,
     dst_vars: &mut Vec<Option<Var>>, nodes: &mut Nodes,
-    call_stack: &Vec<String>) {
+    call_stack: &[String]) {
 
     assert!(matches!(final_data.dest_address_type, AddressType::Variable));
 
@@ -866,14 +878,15 @@ fn store_function_return_results_into_variable(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn store_function_return_results_into_subsignal(
-    final_data: &FinalData, src_vars: &Vec<Option<Var>>, ret: &FnReturn,
+    final_data: &FinalData, src_vars: &[Option<Var>], ret: &FnReturn,
     dst_vars: &mut Vec<Option<Var>>, nodes: &mut Nodes,
     component_signal_start: usize, signal_node_idx: &mut Vec<usize>,
-    subcomponents: &mut Vec<Option<ComponentInstance>>,
-    io_map: &TemplateInstanceIOMap, templates: &Vec<TemplateCode>,
-    functions: &Vec<FunctionCode>, print_debug: bool,
-    call_stack: &Vec<String>) {
+    subcomponents: &mut [Option<ComponentInstance>],
+    io_map: &TemplateInstanceIOMap, templates: &[TemplateCode],
+    functions: &[FunctionCode], gadgets: &mut Gadgets, print_debug: bool,
+    call_stack: &[String]) {
 
     let (cmp_address, input_information) = if let AddressType::SubcmpSignal {cmp_address, input_information, ..} = &final_data.dest_address_type {
         (cmp_address, input_information)
@@ -891,7 +904,7 @@ fn store_function_return_results_into_subsignal(
                     }
                     Some(Var::Value(v)) => {
                         src_node_idxs.push(
-                            nodes.push(Node::Constant(v.clone())).0);
+                            nodes.push(Node::Constant(v)).0);
                     }
                     None => {
                         panic!("variable at index {} is not set", i);
@@ -904,10 +917,10 @@ fn store_function_return_results_into_subsignal(
             assert_eq!(final_data.context.size, 1);
             match v {
                 Var::Node(node_idx) => {
-                    src_node_idxs.push(node_idx.clone());
+                    src_node_idxs.push(*node_idx);
                 }
                 Var::Value(v) => {
-                    src_node_idxs.push(nodes.push(Node::Constant(v.clone())).0);
+                    src_node_idxs.push(nodes.push(Node::Constant(*v)).0);
                 }
             }
         }
@@ -916,17 +929,19 @@ fn store_function_return_results_into_subsignal(
     store_subcomponent_signals(
         cmp_address, input_information, nodes, dst_vars, component_signal_start,
         signal_node_idx, subcomponents, io_map, &src_node_idxs, &final_data.dest,
-        final_data.context.size, templates, functions, print_debug, call_stack);
+        final_data.context.size, templates, functions, gadgets, print_debug,
+        call_stack);
 }
 
+#[allow(clippy::too_many_arguments)]
 fn store_function_return_results(
-    final_data: &FinalData, src_vars: &Vec<Option<Var>>, ret: &FnReturn,
+    final_data: &FinalData, src_vars: &[Option<Var>], ret: &FnReturn,
     dst_vars: &mut Vec<Option<Var>>, nodes: &mut Nodes,
     component_signal_start: usize, signal_node_idx: &mut Vec<usize>,
-    subcomponents: &mut Vec<Option<ComponentInstance>>,
-    io_map: &TemplateInstanceIOMap, templates: &Vec<TemplateCode>,
-    functions: &Vec<FunctionCode>, print_debug: bool,
-    call_stack: &Vec<String>) {
+    subcomponents: &mut [Option<ComponentInstance>],
+    io_map: &TemplateInstanceIOMap, templates: &[TemplateCode],
+    functions: &[FunctionCode], gadgets: &mut Gadgets, print_debug: bool,
+    call_stack: &[String]) {
 
     match &final_data.dest_address_type {
         AddressType::Signal => todo!("Signal"),
@@ -938,15 +953,15 @@ fn store_function_return_results(
             store_function_return_results_into_subsignal(
                 final_data, src_vars, ret, dst_vars, nodes,
                 component_signal_start, signal_node_idx, subcomponents,
-                io_map, templates, functions, print_debug, call_stack);
+                io_map, templates, functions, gadgets, print_debug, call_stack);
         }
     }
 }
 
 fn run_function(
-    call_bucket: &CallBucket, functions: &Vec<FunctionCode>,
+    call_bucket: &CallBucket, functions: &[FunctionCode], gadgets: &Gadgets,
     fn_vars: &mut Vec<Option<Var>>, nodes: &mut Nodes,
-    print_debug: bool, call_stack: &Vec<String>) -> FnReturn {
+    print_debug: bool, call_stack: &[String]) -> FnReturn {
 
     // for i in functions {
     //     println!("Function: {} {}", i.header, i.name);
@@ -957,13 +972,22 @@ fn run_function(
         println!("Run function {}", &call_bucket.symbol);
     }
 
-    let mut call_stack = call_stack.clone();
+    if gadgets.names.contains(&f.name) {
+        if let Some(r) = run_gadget_function(f, call_bucket, fn_vars, nodes) {
+            if print_debug {
+                println!("Function {} computed with gadget", &call_bucket.symbol);
+            }
+            return r;
+        }
+    }
+
+    let mut call_stack = call_stack.to_vec();
     call_stack.push(f.name.clone());
 
     let mut r: Option<FnReturn> = None;
     for i in &f.body {
         r = process_function_instruction(
-            i, fn_vars, nodes, functions, print_debug, &call_stack);
+            i, fn_vars, nodes, functions, gadgets, print_debug, &call_stack);
         if r.is_some() {
             break;
         }
@@ -976,9 +1000,86 @@ fn run_function(
     }
     r
 }
+
+// Compute the call of the bigint function with the gadget node. The arguments
+// of the call are the scalar parameters followed by the arrays of limbs in
+// fn_vars. The results are written to fn_vars after the arguments. Returns
+// None if the arguments do not match the gadget.
+fn run_gadget_function(
+    f: &FunctionCode, call_bucket: &CallBucket,
+    fn_vars: &mut Vec<Option<Var>>, nodes: &mut Nodes) -> Option<FnReturn> {
+
+    let ret_size = match call_bucket.return_info {
+        ReturnType::Final(ref final_data) => final_data.context.size,
+        ReturnType::Intermediate { .. } => return None,
+    };
+
+    let mut args: Vec<&[Option<Var>]> = Vec::new();
+    let mut args_len = 0usize;
+    for t in call_bucket.argument_types.iter() {
+        args.push(&fn_vars[args_len..args_len + t.size]);
+        args_len += t.size;
+    }
+
+    // try the number of leading scalar parameters the gadget accepts
+    let (gadget, params_num) = (0..=args.len()).find_map(|params_num| {
+        let params = args[..params_num].iter()
+            .map(|a| match a {
+                [Some(Var::Value(v))] => bigint_to_usize(v).ok(),
+                _ => None,
+            })
+            .collect::<Option<Vec<usize>>>()?;
+        let gadget = Gadget::for_function(&f.name, &params)?;
+        let limbs = gadget.operand_limbs();
+        let fits = limbs.len() == args.len() - params_num
+            && limbs.iter().zip(&args[params_num..]).all(|(&l, a)| l <= a.len());
+        if fits { Some((gadget, params_num)) } else { None }
+    })?;
+
+    let mut operands: Vec<Var> = Vec::new();
+    for (&limbs_num, a) in gadget.operand_limbs().iter().zip(&args[params_num..]) {
+        for v in a[..limbs_num].iter() {
+            operands.push(v.clone()?);
+        }
+    }
+    let results_num = gadget.outputs_num(operands.len());
+    if gadget.function_result_position(results_num - 1, ret_size) >= ret_size {
+        return None;
+    }
+
+    let results: Vec<Var> = if operands.iter().all(|v| matches!(v, Var::Value(_))) {
+        let values = operands.iter()
+            .map(|v| match v {
+                Var::Value(v) => *v,
+                Var::Node(_) => unreachable!(),
+            })
+            .collect::<Vec<U256>>();
+        gadget.eval(&values).into_iter().map(Var::Value).collect()
+    } else {
+        let operands = operands.iter()
+            .map(|v| node_from_var(v, nodes))
+            .collect::<Vec<usize>>();
+        let gadget_idx = nodes.push(Node::Gadget(gadget, operands)).0;
+        (0..results_num)
+            .map(|k| Var::Node(nodes.push(Node::Extract(gadget_idx, k)).0))
+            .collect()
+    };
+
+    if fn_vars.len() < args_len + ret_size {
+        fn_vars.resize(args_len + ret_size, None);
+    }
+    for i in 0..ret_size {
+        fn_vars[args_len + i] = Some(Var::Value(U256::ZERO));
+    }
+    for (k, v) in results.into_iter().enumerate() {
+        fn_vars[args_len + gadget.function_result_position(k, ret_size)] = Some(v);
+    }
+    Some(FnReturn::FnVar { idx: args_len, ln: ret_size })
+}
+
 fn calc_function_expression_n(
     inst: &InstructionPointer, fn_vars: &mut Vec<Option<Var>>,
-    nodes: &mut Nodes, n: usize, call_stack: &Vec<String>) -> Vec<Var> {
+    nodes: &mut Nodes, n: usize, call_stack: &[String]) -> Vec<Var> {
 
     if n == 1 {
         let v = calc_function_expression(inst, fn_vars, nodes, call_stack);
@@ -1029,7 +1130,7 @@ fn calc_function_expression_n(
 
 fn calc_function_expression(
     inst: &InstructionPointer, fn_vars: &mut Vec<Option<Var>>,
-    nodes: &mut Nodes, call_stack: &Vec<String>) -> Var {
+    nodes: &mut Nodes, call_stack: &[String]) -> Var {
 
     match **inst {
         Instruction::Value(ref value_bucket) => {
@@ -1085,7 +1186,7 @@ fn calc_function_expression(
 fn node_from_var(v: &Var, nodes: &mut Nodes) -> usize {
     match v {
         Var::Value(ref v) => {
-            nodes.push(Node::Constant(v.clone())).0
+            nodes.push(Node::Constant(*v)).0
         }
         Var::Node(node_idx) => *node_idx,
     }
@@ -1093,24 +1194,24 @@ fn node_from_var(v: &Var, nodes: &mut Nodes) -> usize {
 
 fn compute_function_expression(
     compute_bucket: &ComputeBucket, fn_vars: &mut Vec<Option<Var>>,
-    nodes: &mut Nodes, call_stack: &Vec<String>) -> Var {
+    nodes: &mut Nodes, call_stack: &[String]) -> Var {
 
     if let Some(op) = DUO_OPERATORS_MAP.get(&compute_bucket.op) {
         assert_eq!(compute_bucket.stack.len(), 2);
         let a = calc_function_expression(
-            compute_bucket.stack.get(0).unwrap(), fn_vars,
+            compute_bucket.stack.first().unwrap(), fn_vars,
             nodes, call_stack);
         let b = calc_function_expression(
             compute_bucket.stack.get(1).unwrap(), fn_vars,
             nodes, call_stack);
         match (&a, &b) {
             (Var::Value(a), Var::Value(b)) => {
-                return Var::Value(op.eval(a.clone(), b.clone()));
+                return Var::Value(op.eval(*a, *b));
             }
             _ => {
                 let a_idx = node_from_var(&a, nodes);
                 let b_idx = node_from_var(&b, nodes);
-                return Var::Node(nodes.push(Node::Op(op.clone(), a_idx, b_idx)).0);
+                return Var::Node(nodes.push(Node::Op(*op, a_idx, b_idx)).0);
             }
         }
     }
@@ -1118,14 +1219,14 @@ fn compute_function_expression(
     if let Some(op) = UNO_OPERATORS_MAP.get(&compute_bucket.op) {
         assert_eq!(compute_bucket.stack.len(), 1);
         let a = calc_function_expression(
-            compute_bucket.stack.get(0).unwrap(), fn_vars,
+            compute_bucket.stack.first().unwrap(), fn_vars,
             nodes, call_stack);
         match &a {
             Var::Value(v) => {
-                return Var::Value(op.eval(v.clone()));
+                return Var::Value(op.eval(*v));
             }
             Var::Node(node_idx) => {
-                return Var::Node(nodes.push(Node::UnoOp(op.clone(), *node_idx)).0);
+                return Var::Node(nodes.push(Node::UnoOp(*op, *node_idx)).0);
             }
         }
     }
@@ -1142,7 +1243,7 @@ enum FnReturn {
 
 fn build_return(
     return_bucket: &ReturnBucket, fn_vars: &mut Vec<Option<Var>>,
-    nodes: &mut Nodes, call_stack: &Vec<String>) -> FnReturn {
+    nodes: &mut Nodes, call_stack: &[String]) -> FnReturn {
 
     match *return_bucket.value {
         Instruction::Load(ref load_bucket) => {
@@ -1172,7 +1273,7 @@ fn build_return(
 
 fn calc_return_load_idx(
     load_bucket: &LoadBucket, fn_vars: &mut Vec<Option<Var>>,
-    nodes: &mut Nodes, call_stack: &Vec<String>) -> usize {
+    nodes: &mut Nodes, call_stack: &[String]) -> usize {
 
     match &load_bucket.address_type {
         AddressType::Variable => {}, // OK
@@ -1192,7 +1293,7 @@ fn calc_return_load_idx(
 // return variable value and it's index
 fn store_function_variable(
     store_bucket: &StoreBucket, fn_vars: &mut Vec<Option<Var>>,
-    nodes: &mut Nodes, call_stack: &Vec<String>) -> (Var, usize) {
+    nodes: &mut Nodes, call_stack: &[String]) -> (Var, usize) {
 
     assert!(matches!(store_bucket.dest_address_type, AddressType::Variable),
             "functions can store only inside variables: dest_address_type: {}",
@@ -1229,8 +1330,8 @@ fn store_function_variable(
 
 fn process_function_instruction(
     inst: &InstructionPointer, fn_vars: &mut Vec<Option<Var>>,
-    nodes: &mut Nodes, functions: &Vec<FunctionCode>,
-    print_debug: bool, call_stack: &Vec<String>) -> Option<FnReturn> {
+    nodes: &mut Nodes, functions: &[FunctionCode], gadgets: &Gadgets,
+    print_debug: bool, call_stack: &[String]) -> Option<FnReturn> {
 
     match **inst {
         Instruction::Store(ref store_bucket) => {
@@ -1288,7 +1389,8 @@ fn process_function_instruction(
                     };
                     for i in branch {
                         let r = process_function_instruction(
-                            i, fn_vars, nodes, functions, print_debug, call_stack);
+                            i, fn_vars, nodes, functions, gadgets, print_debug,
+                            call_stack);
                         if r.is_some() {
                             return r;
                         }
@@ -1363,7 +1465,8 @@ fn process_function_instruction(
 
                 for i in &loop_bucket.body {
                     process_function_instruction(
-                        i, fn_vars, nodes, functions, print_debug, call_stack);
+                        i, fn_vars, nodes, functions, gadgets, print_debug,
+                        call_stack);
                 }
             };
             None
@@ -1371,9 +1474,8 @@ fn process_function_instruction(
         Instruction::Call(ref call_bucket) => {
             let mut new_fn_vars: Vec<Option<Var>> = vec![None; call_bucket.arena_size];
 
-            let mut idx = 0;
             let mut count: usize = 0;
-            for inst2 in &call_bucket.arguments {
+            for (idx, inst2) in call_bucket.arguments.iter().enumerate() {
                 let args = calc_function_expression_n(
                     inst2, fn_vars, nodes, call_bucket.argument_types[idx].size,
                     call_stack);
@@ -1381,12 +1483,11 @@ fn process_function_instruction(
                     new_fn_vars[count] = Some(arg);
                     count += 1;
                 }
-                idx += 1;
             }
 
             let r = run_function(
-                call_bucket, functions, &mut new_fn_vars, nodes, print_debug,
-                call_stack);
+                call_bucket, functions, gadgets, &mut new_fn_vars, nodes,
+                print_debug, call_stack);
 
             match call_bucket.return_info {
                 ReturnType::Intermediate{ ..} => { todo!(); }
@@ -1416,7 +1517,7 @@ fn process_function_instruction(
 
 fn check_continue_condition_function(
     inst: &InstructionPointer, fn_vars: &mut Vec<Option<Var>>,
-    nodes: &mut Nodes, call_stack: &Vec<String>) -> bool {
+    nodes: &mut Nodes, call_stack: &[String]) -> bool {
 
     let val = calc_function_expression(inst, fn_vars, nodes, call_stack)
         .to_const(nodes)
@@ -1430,14 +1531,14 @@ fn check_continue_condition_function(
 
 
 
-fn find_function<'a>(name: &str, functions: &'a Vec<FunctionCode>) -> &'a FunctionCode {
+fn find_function<'a>(name: &str, functions: &'a [FunctionCode]) -> &'a FunctionCode {
     functions.iter().find(|f| f.header == name).expect("function not found")
 }
 
 #[derive(Debug, Clone)]
 struct ValueTooBigError {}
 
-impl<'a> fmt::Display for ValueTooBigError {
+impl fmt::Display for ValueTooBigError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "value is too big")
     }
@@ -1449,10 +1550,8 @@ fn bigint_to_usize(value: &U256) -> Result<usize, Box<dyn Error>> {
 
     // Convert U256 to usize
     let bytes = value.to_le_bytes::<32>().to_vec(); // Convert to little-endian bytes
-    for i in std::mem::size_of::<usize>()..bytes.len() {
-        if bytes[i] != 0 {
-            return Err(Box::new(ValueTooBigError {}));
-        }
+    if bytes[std::mem::size_of::<usize>()..].iter().any(|&b| b != 0) {
+        return Err(Box::new(ValueTooBigError {}));
     }
     Ok(usize::from_le_bytes(
         bytes[..std::mem::size_of::<usize>()]
@@ -1468,23 +1567,24 @@ struct ComponentInstance {
     number_of_inputs: usize,
 }
 
+#[allow(clippy::too_many_arguments)]
 fn fmt_create_cmp_bucket(
     cmp_bucket: &CreateCmpBucket,
     nodes: &mut Nodes,
     vars: &mut Vec<Option<Var>>,
     component_signal_start: usize,
     signal_node_idx: &mut Vec<usize>,
-    subcomponents: &Vec<Option<ComponentInstance>>,
+    subcomponents: &[Option<ComponentInstance>],
     io_map: &TemplateInstanceIOMap,
     print_debug: bool,
-    call_stack: &Vec<String>,
+    call_stack: &[String],
 ) -> String {
     let sub_cmp_id = calc_expression(
         &cmp_bucket.sub_cmp_id, nodes, vars, component_signal_start,
         signal_node_idx, subcomponents, io_map, print_debug, call_stack);
 
     let sub_cmp_id = match sub_cmp_id {
-        Var::Value(ref c) => format!("Constant {}", c.to_string()),
+        Var::Value(ref c) => format!("Constant {}", c),
         Var::Node(idx) => format!("Variable {}", idx)
     };
 
@@ -1526,11 +1626,11 @@ enum Var {
     Node(usize),
 }
 
-impl ToString for Var {
-    fn to_string(&self) -> String {
+impl fmt::Display for Var {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Var::Value(ref c) => { format!("Var::Value({})", c.to_string()) }
-            Var::Node(idx) => { format!("Var::Node({})", idx) }
+            Var::Value(ref c) => { write!(f, "Var::Value({})", c) }
+            Var::Node(idx) => { write!(f, "Var::Node({})", idx) }
         }
     }
 }
@@ -1539,18 +1639,18 @@ impl ToString for Var {
 impl Var {
     fn to_const(&self, nodes: &Nodes) -> Result<U256, NodeConstErr> {
         match self {
-            Var::Value(v) => Ok(v.clone()),
+            Var::Value(v) => Ok(*v),
             Var::Node(node_idx) => nodes.to_const(NodeIdx::from(*node_idx)),
         }
     }
 
     fn to_const_usize(&self, nodes: &Nodes) -> Result<usize, Box<dyn Error>> {
         let c = self.to_const(nodes)?;
-        Ok(bigint_to_usize(&c)?)
+        bigint_to_usize(&c)
     }
 
     fn must_const_usize(
-        &self, nodes: &Nodes, call_stack: &Vec<String>) -> usize {
+        &self, nodes: &Nodes, call_stack: &[String]) -> usize {
 
         self.to_const_usize(nodes).unwrap_or_else(|e| {
             panic!("{}: {}", e, call_stack.join(" -> "));
@@ -1558,13 +1658,14 @@ impl Var {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn load_n(
     load_bucket: &LoadBucket, nodes: &mut Nodes,
     vars: &mut Vec<Option<Var>>, component_signal_start: usize,
     signal_node_idx: &mut Vec<usize>,
-    subcomponents: &Vec<Option<ComponentInstance>>, size: usize,
+    subcomponents: &[Option<ComponentInstance>], size: usize,
     io_map: &TemplateInstanceIOMap, print_debug: bool,
-    call_stack: &Vec<String>) -> Vec<Var> {
+    call_stack: &[String]) -> Vec<Var> {
 
     match load_bucket.address_type {
         AddressType::Signal => match &load_bucket.src {
@@ -1591,7 +1692,7 @@ fn load_n(
                         component_signal_start, signal_idx, i);
                     result.push(Var::Node(signal_node));
                 }
-                return result;
+                result
             }
             LocationRule::Mapped { .. } => {
                 panic!("mapped signals expect only on address type SubcmpSignal");
@@ -1626,7 +1727,7 @@ fn load_n(
                 LocationRule::Mapped { ref signal_code, ref indexes } => {
                     calc_mapped_signal_idx(
                         subcomponents, subcomponent_idx, io_map,
-                        signal_code.clone(), indexes, nodes, vars,
+                        *signal_code, indexes, nodes, vars,
                         component_signal_start, signal_node_idx, print_debug,
                         call_stack)
                 }
@@ -1655,7 +1756,7 @@ fn load_n(
                     component_signal_start, signal_idx, i);
                 result.push(Var::Node(signal_node));
             }
-            return result;
+            result
         }
         AddressType::Variable => {
             let location = if let LocationRule::Indexed { location, template_header } = &load_bucket.src {
@@ -1685,16 +1786,17 @@ fn load_n(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn build_unary_op_var(
     compute_bucket: &ComputeBucket,
     nodes: &mut Nodes,
     vars: &mut Vec<Option<Var>>,
     component_signal_start: usize,
     signal_node_idx: &mut Vec<usize>,
-    subcomponents: &Vec<Option<ComponentInstance>>,
+    subcomponents: &[Option<ComponentInstance>],
     io_map: &TemplateInstanceIOMap,
     print_debug: bool,
-    call_stack: &Vec<String>,
+    call_stack: &[String],
 ) -> Var {
     assert_eq!(compute_bucket.stack.len(), 1);
     let a = calc_expression(
@@ -1704,8 +1806,8 @@ fn build_unary_op_var(
     match &a {
         Var::Value(ref a) => {
             Var::Value(match compute_bucket.op {
-                OperatorType::ToAddress => a.clone(),
-                OperatorType::PrefixSub => if a.clone() == U256::ZERO { U256::ZERO } else { M - a }
+                OperatorType::ToAddress => *a,
+                OperatorType::PrefixSub => if *a == U256::ZERO { U256::ZERO } else { M - a }
                 _ => {
                     todo!(
                         "unary operator not implemented: {}",
@@ -1721,7 +1823,7 @@ fn build_unary_op_var(
                     nodes.to_const(NodeIdx(*node_idx)).unwrap_or_else(|e| {
                         panic!(
                             "ToAddress argument is not a constant: {}: {}",
-                            e.to_string(), call_stack.join(" -> "));
+                            e, call_stack.join(" -> "));
                     });
                     UnoOperation::Id
                 }
@@ -1738,16 +1840,17 @@ fn build_unary_op_var(
 }
 
 // Create a Var from operation on two arguments a anb b
+#[allow(clippy::too_many_arguments)]
 fn build_binary_op_var(
     compute_bucket: &ComputeBucket,
     nodes: &mut Nodes,
     vars: &mut Vec<Option<Var>>,
     component_signal_start: usize,
     signal_node_idx: &mut Vec<usize>,
-    subcomponents: &Vec<Option<ComponentInstance>>,
+    subcomponents: &[Option<ComponentInstance>],
     io_map: &TemplateInstanceIOMap,
     print_debug: bool,
-    call_stack: &Vec<String>,
+    call_stack: &[String],
 ) -> Var {
     assert_eq!(compute_bucket.stack.len(), 2);
     let a = calc_expression(
@@ -1759,7 +1862,7 @@ fn build_binary_op_var(
 
     let mut node_idx = |v: &Var| match v {
         Var::Value(ref c) => {
-            nodes.push(Node::Constant(c.clone())).0
+            nodes.push(Node::Constant(*c)).0
         }
         Var::Node(idx) => { *idx }
     };
@@ -1767,8 +1870,8 @@ fn build_binary_op_var(
     match (&a, &b) {
         (Var::Value(ref a), Var::Value(ref b)) => {
             Var::Value(match compute_bucket.op {
-                OperatorType::Mul => Operation::Mul.eval(a.clone(), b.clone()),
-                OperatorType::Div => if b.clone() == U256::ZERO {
+                OperatorType::Mul => Operation::Mul.eval(*a, *b),
+                OperatorType::Div => if *b == U256::ZERO {
                     // as we are simulating a circuit execution with signals
                     // values all equal to 0, just return 0 here in case of
                     // division by zero
@@ -1776,23 +1879,23 @@ fn build_binary_op_var(
                 } else {
                     a.mul_mod(b.inv_mod(M).unwrap(), M)
                 },
-                OperatorType::Add => a.add_mod(b.clone(), M),
+                OperatorType::Add => a.add_mod(*b, M),
                 OperatorType::Sub => a.add_mod(M - b, M),
-                OperatorType::Pow => Operation::Pow.eval(a.clone(), b.clone()),
-                OperatorType::IntDiv => Operation::Idiv.eval(a.clone(), b.clone()),
-                OperatorType::Mod => Operation::Mod.eval(a.clone(), b.clone()),
-                OperatorType::ShiftL => Operation::Shl.eval(a.clone(), b.clone()),
-                OperatorType::ShiftR => Operation::Shr.eval(a.clone(), b.clone()),
-                OperatorType::LesserEq => Operation::Leq.eval(a.clone(), b.clone()),
-                OperatorType::GreaterEq => Operation::Geq.eval(a.clone(), b.clone()),
+                OperatorType::Pow => Operation::Pow.eval(*a, *b),
+                OperatorType::IntDiv => Operation::Idiv.eval(*a, *b),
+                OperatorType::Mod => Operation::Mod.eval(*a, *b),
+                OperatorType::ShiftL => Operation::Shl.eval(*a, *b),
+                OperatorType::ShiftR => Operation::Shr.eval(*a, *b),
+                OperatorType::LesserEq => Operation::Leq.eval(*a, *b),
+                OperatorType::GreaterEq => Operation::Geq.eval(*a, *b),
                 OperatorType::Lesser => if a < b { U256::from(1) } else { U256::ZERO }
-                OperatorType::Greater => Operation::Gt.eval(a.clone(), b.clone()),
-                OperatorType::Eq(1) => Operation::Eq.eval(a.clone(), b.clone()),
+                OperatorType::Greater => Operation::Gt.eval(*a, *b),
+                OperatorType::Eq(1) => Operation::Eq.eval(*a, *b),
                 OperatorType::NotEq => U256::from(a != b),
-                OperatorType::BoolAnd => Operation::Land.eval(a.clone(), b.clone()),
-                OperatorType::BitOr => Operation::Bor.eval(a.clone(), b.clone()),
-                OperatorType::BitAnd => Operation::Band.eval(a.clone(), b.clone()),
-                OperatorType::BitXor => Operation::Bxor.eval(a.clone(), b.clone()),
+                OperatorType::BoolAnd => Operation::Land.eval(*a, *b),
+                OperatorType::BitOr => Operation::Bor.eval(*a, *b),
+                OperatorType::BitAnd => Operation::Band.eval(*a, *b),
+                OperatorType::BitXor => Operation::Bxor.eval(*a, *b),
                 OperatorType::MulAddress => a * b,
                 OperatorType::AddAddress => a + b,
                 _ => {
@@ -1840,16 +1943,17 @@ fn build_binary_op_var(
 
 // This function should calculate node based only on constant or variable
 // values. Not based on signal values.
+#[allow(clippy::too_many_arguments)]
 fn calc_expression(
     inst: &InstructionPointer,
     nodes: &mut Nodes,
     vars: &mut Vec<Option<Var>>,
     component_signal_start: usize,
     signal_node_idx: &mut Vec<usize>,
-    subcomponents: &Vec<Option<ComponentInstance>>,
+    subcomponents: &[Option<ComponentInstance>],
     io_map: &TemplateInstanceIOMap,
     print_debug: bool,
-    call_stack: &Vec<String>,
+    call_stack: &[String],
 ) -> Var {
     match **inst {
         Instruction::Value(ref value_bucket) => {
@@ -1903,17 +2007,18 @@ fn calc_expression(
 
 // This function should calculate node based only on constant or variable
 // values. Not based on signal values.
+#[allow(clippy::too_many_arguments)]
 fn calc_expression_n(
     inst: &InstructionPointer,
     nodes: &mut Nodes,
     vars: &mut Vec<Option<Var>>,
     component_signal_start: usize,
     signal_node_idx: &mut Vec<usize>,
-    subcomponents: &Vec<Option<ComponentInstance>>,
+    subcomponents: &[Option<ComponentInstance>],
     size: usize,
     io_map: &TemplateInstanceIOMap,
     print_debug: bool,
-    call_stack: &Vec<String>,
+    call_stack: &[String],
 ) -> Vec<Var> {
     if size == 1 {
         return vec![calc_expression(
@@ -1937,16 +2042,17 @@ fn calc_expression_n(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn check_continue_condition(
     inst: &InstructionPointer,
     nodes: &mut Nodes,
     vars: &mut Vec<Option<Var>>,
     component_signal_start: usize,
     signal_node_idx: &mut Vec<usize>,
-    subcomponents: &Vec<Option<ComponentInstance>>,
+    subcomponents: &[Option<ComponentInstance>],
     io_map: &TemplateInstanceIOMap,
     print_debug: bool,
-    call_stack: &Vec<String>,
+    call_stack: &[String],
 ) -> bool {
     let val = calc_expression(
             inst, nodes, vars, component_signal_start, signal_node_idx,
@@ -1971,7 +2077,7 @@ fn get_constants(circuit: &Circuit) -> Vec<Node> {
 fn init_input_signals(
     circuit: &Circuit,
     nodes: &mut Nodes,
-    signal_node_idx: &mut Vec<usize>,
+    signal_node_idx: &mut [usize],
     input_file: Option<String>,
 ) -> (InputSignalsInfo, Vec<U256>) {
     let input_list = circuit.c_producer.get_main_input_list();
@@ -1992,7 +2098,7 @@ fn init_input_signals(
     };
 
     for (name, offset, len) in input_list {
-        inputs_info.insert(name.clone(), (signal_values.len(), len.clone()));
+        inputs_info.insert(name.clone(), (signal_values.len(), *len));
         match inputs {
             Some(ref inputs) => {
                 match inputs.get(name) {
@@ -2003,7 +2109,7 @@ fn init_input_signals(
                                 name, *len, values.len());
                         }
                         for (i, v) in values.iter().enumerate() {
-                            signal_values.push(v.clone());
+                            signal_values.push(*v);
                             signal_node_idx[offset + i] = nodes.push(
                                 Node::Input(signal_values.len() - 1)).0;
                         }
@@ -2023,24 +2129,150 @@ fn init_input_signals(
         }
     }
 
-    return (inputs_info, signal_values);
+    (inputs_info, signal_values)
+}
+
+// Templates and functions to compute natively with gadgets instead of running
+// their code.
+struct Gadgets {
+    names: Vec<String>,
+    // Number of signals of an instance of each template, including the
+    // signals of its subcomponents.
+    template_signals: Vec<usize>,
+    // Sorted witness signals.
+    witness: Vec<usize>,
+    // Signal offsets of the template instances replaced with gadgets.
+    used: Vec<usize>,
+    // Number of the template instances run as usual, because their internal
+    // signals are in the witness.
+    expanded: usize,
+}
+
+impl Gadgets {
+    fn new(
+        names: Vec<String>, templates: &[TemplateCode],
+        witness_list: &[usize]) -> Self {
+
+        let mut template_signals = vec![None; templates.len()];
+        for id in 0..templates.len() {
+            count_template_signals(templates, id, &mut template_signals);
+        }
+        let mut witness = witness_list.to_vec();
+        witness.sort_unstable();
+        Gadgets {
+            names,
+            template_signals: template_signals.into_iter().map(Option::unwrap).collect(),
+            witness,
+            used: Vec::new(),
+            expanded: 0,
+        }
+    }
+
+    // The signals of a template instance are outputs, inputs, intermediate
+    // signals and then the signals of its subcomponents. Gadget sets the
+    // outputs only, so the instance is replaced with the gadget only if none
+    // of the signals after its inputs is in the witness.
+    fn find(&mut self, tmpl: &TemplateCode, signal_offset: usize) -> Option<Gadget> {
+        if !self.names.contains(&tmpl.name) {
+            return None;
+        }
+        let own_signals = tmpl.number_of_outputs + tmpl.number_of_inputs
+            + tmpl.number_of_intermediates;
+        let gadget = Gadget::for_template(
+            &tmpl.name, tmpl.number_of_inputs, tmpl.number_of_outputs,
+            tmpl.number_of_intermediates,
+            self.template_signals[tmpl.id] - own_signals)?;
+        let internal_start =
+            signal_offset + tmpl.number_of_outputs + tmpl.number_of_inputs;
+        let internal_end = signal_offset + self.template_signals[tmpl.id];
+        let n = self.witness.partition_point(|&s| s < internal_start);
+        if n < self.witness.len() && self.witness[n] < internal_end {
+            self.expanded += 1;
+            return None;
+        }
+        Some(gadget)
+    }
 }
 
+// Count the signals of the template instance: its own signals and the
+// signals of the subcomponents created in its body.
+fn count_template_signals(
+    templates: &[TemplateCode], id: usize, counts: &mut Vec<Option<usize>>) -> usize {
+
+    if let Some(n) = counts[id] {
+        return n;
+    }
+    let tmpl = &templates[id];
+    let mut n = tmpl.number_of_outputs + tmpl.number_of_inputs
+        + tmpl.number_of_intermediates;
+    let mut buckets = vec![];
+    create_cmp_buckets(&tmpl.body, &mut buckets);
+    for b in buckets {
+        let sub_signals = count_template_signals(templates, b.template_id, counts);
+        n = n.max(
+            b.signal_offset + (b.number_of_cmp - 1) * b.signal_offset_jump
+                + sub_signals);
+    }
+    counts[id] = Some(n);
+    n
+}
+
+fn create_cmp_buckets<'a>(
+    body: &'a [InstructionPointer], buckets: &mut Vec<&'a CreateCmpBucket>) {
+
+    for inst in body {
+        match **inst {
+            Instruction::CreateCmp(ref b) => buckets.push(b),
+            Instruction::Branch(ref b) => {
+                create_cmp_buckets(&b.if_branch, buckets);
+                create_cmp_buckets(&b.else_branch, buckets);
+            }
+            Instruction::Loop(ref b) => create_cmp_buckets(&b.body, buckets),
+            _ => {}
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn run_template(
-    templates: &Vec<TemplateCode>,
-    functions: &Vec<FunctionCode>,
+    templates: &[TemplateCode],
+    functions: &[FunctionCode],
+    gadgets: &mut Gadgets,
     template_id: usize,
     nodes: &mut Nodes,
     signal_node_idx: &mut Vec<usize>,
     component_signal_start: usize,
     io_map: &TemplateInstanceIOMap,
     print_debug: bool,
-    call_stack: &Vec<String>,
+    call_stack: &[String],
 ) {
     let tmpl = &templates[template_id];
 
+    if let Some(gadget) = gadgets.find(tmpl, component_signal_start) {
+        // The signals of the component are outputs, then inputs, then
+        // intermediate signals. Gadget sets the outputs only.
+        let inputs_start = component_signal_start + tmpl.number_of_outputs;
+        let args = signal_node_idx[inputs_start..inputs_start+tmpl.number_of_inputs]
+            .to_vec();
+        assert!(
+            args.iter().all(|&a| a != usize::MAX),
+            "input of the gadget {:?} is not set", gadget);
+        let gadget_idx = nodes.push(Node::Gadget(gadget, args)).0;
+        for k in 0..tmpl.number_of_outputs {
+            signal_node_idx[component_signal_start + k] =
+                nodes.push(Node::Extract(gadget_idx, k)).0;
+        }
+        gadgets.used.push(component_signal_start);
+        if print_debug {
+            println!(
+                "Template {}_{} computed with gadget {:?}", tmpl.name, tmpl.id,
+                gadget);
+        }
+        return;
+    }
+
     let tmpl_name: String = format!("{}_{}", tmpl.name, tmpl.id);
-    let mut call_stack = call_stack.clone();
+    let mut call_stack = call_stack.to_vec();
     call_stack.push(tmpl_name.clone());
 
     if print_debug {
@@ -2057,9 +2289,9 @@ fn run_template(
 
     for inst in &tmpl.body {
         process_instruction(
-            &inst, nodes, signal_node_idx, &mut vars, &mut components,
-            templates, functions, component_signal_start, io_map, print_debug,
-            &call_stack);
+            inst, nodes, signal_node_idx, &mut vars, &mut components,
+            templates, functions, gadgets, component_signal_start, io_map,
+            print_debug, &call_stack);
     }
 
     if print_debug {
@@ -2075,6 +2307,13 @@ struct Args {
     link_libraries: Vec<PathBuf>,
     print_unoptimized: bool,
     print_debug: bool,
+    symbols: bool,
+    probabilistic_constants: bool,
+    opt_level: OptLevel,
+    max_passes: Option<usize>,
+    verify_passes: bool,
+    verify_inputs: usize,
+    gadgets: Vec<String>,
 }
 
 fn parse_args() -> Args {
@@ -2086,10 +2325,17 @@ fn parse_args() -> Args {
     let mut inputs_file: Option<String> = None;
     let mut print_unoptimized = false;
     let mut print_debug = false;
+    let mut symbols = false;
+    let mut probabilistic_constants = false;
+    let mut opt_level = OptLevel::O2;
+    let mut max_passes: Option<usize> = None;
+    let mut verify_passes = false;
+    let mut verify_inputs = 0usize;
+    let mut gadgets: Vec<String> = Vec::new();
 
     let usage = |err_msg: &str| -> String {
         eprintln!("{}", err_msg);
-        eprintln!("Usage: {} <circuit_file> <graph_file> [-l <link_library>]* [-i <inputs_file.json>] [-print-unoptimized] [-v]", args[0]);
+        eprintln!("Usage: {} <circuit_file> <graph_file> [-l <link_library>]* [-i <inputs_file.json>] [-print-unoptimized] [-symbols] [-probabilistic-constants] [-O0|-O1|-O2] [-max-passes <n>] [-verify-passes] [-verify-inputs <n>] [-gadget <template|function>]* [-v]", args[0]);
         std::process::exit(1);
     };
 
@@ -2107,13 +2353,13 @@ fn parse_args() -> Args {
             if i >= args.len() {
                 usage("missing argument for -i");
             }
-            if let None = inputs_file {
+            if inputs_file.is_none() {
                 inputs_file = Some(args[i].clone());
             } else {
                 usage("multiple inputs files");
             }
         } else if args[i].starts_with("-i") {
-            if let None = inputs_file {
+            if inputs_file.is_none() {
                 inputs_file = Some(args[i][2..].to_string());
             } else {
                 usage("multiple inputs files");
@@ -2122,12 +2368,60 @@ fn parse_args() -> Args {
             print_unoptimized = true;
         } else if args[i] == "-v" {
             print_debug = true;
+        } else if args[i] == "-symbols" {
+            symbols = true;
+        } else if args[i] == "-probabilistic-constants" {
+            probabilistic_constants = true;
+        } else if args[i] == "-O0" {
+            opt_level = OptLevel::O0;
+        } else if args[i] == "-O1" {
+            opt_level = OptLevel::O1;
+        } else if args[i] == "-O2" {
+            opt_level = OptLevel::O2;
+        } else if args[i] == "-max-passes" {
+            i += 1;
+            if i >= args.len() {
+                usage("missing argument for -max-passes");
+            }
+            match args[i].parse::<usize>() {
+                Ok(n) => max_passes = Some(n),
+                Err(_) => {
+                    usage(format!("invalid number of passes: {}", args[i]).as_str());
+                }
+            }
+        } else if args[i] == "-verify-passes" {
+            verify_passes = true;
+        } else if args[i] == "-verify-inputs" {
+            i += 1;
+            if i >= args.len() {
+                usage("missing argument for -verify-inputs");
+            }
+            match args[i].parse::<usize>() {
+                Ok(n) => verify_inputs = n,
+                Err(_) => {
+                    usage(format!("invalid number of input sets: {}", args[i]).as_str());
+                }
+            }
+        } else if args[i] == "-gadget" {
+            i += 1;
+            if i >= args.len() {
+                usage("missing argument for -gadget");
+            }
+            if !GADGET_TEMPLATES.contains(&args[i].as_str())
+                && !GADGET_FUNCTIONS.contains(&args[i].as_str()) {
+
+                usage(format!(
+                    "no gadget for {}, supported templates: {}, functions: {}",
+                    args[i], GADGET_TEMPLATES.join(", "),
+                    GADGET_FUNCTIONS.join(", ")).as_str());
+            }
+            gadgets.push(args[i].clone());
         } else if args[i].starts_with("-") {
             let message = format!("unknown argument: {}", args[i]);
             usage(&message);
-        } else if let None = circuit_file {
+        } else if circuit_file.is_none() {
             circuit_file = Some(args[i].clone());
-        } else if let None = graph_file {
+        } else if graph_file.is_none() {
             graph_file = Some(args[i].clone());
         } else {
             usage(format!("unexpected argument: {}", args[i]).as_str());
@@ -2142,6 +2436,13 @@ fn parse_args() -> Args {
         link_libraries,
         print_unoptimized,
         print_debug,
+        symbols,
+        probabilistic_constants,
+        opt_level,
+        max_passes,
+        verify_passes,
+        verify_inputs,
+        gadgets,
     }
 }
 
@@ -2199,7 +2500,17 @@ fn main() {
         prime: String::from("bn128"),
     };
 
-    let (_, vcp) = build_circuit(program_archive, build_config).unwrap();
+    let public_inputs = program_archive
+        .get_public_inputs_main_component()
+        .clone();
+
+    let (constraint_writer, vcp) = build_circuit(program_archive, build_config).unwrap();
+
+    let symbols = if args.symbols {
+        Some(read_symbols(&constraint_writer))
+    } else {
+        None
+    };
 
     let main_template_id = vcp.main_id;
     let witness_list = vcp.get_witness_list().clone();
@@ -2219,15 +2530,6 @@ fn main() {
     println!("functions len: {}", circuit.functions.len());
     println!("main header: {}", circuit.c_producer.main_header);
 
-    let mut signal_node_idx: Vec<usize> =
-        vec![usize::MAX; circuit.c_producer.total_number_of_signals];
-
-    let mut nodes = Nodes::new();
-    nodes.0.extend(get_constants(&circuit));
-
-    let (input_signals, input_signal_values): (InputSignalsInfo, Vec<U256>) = init_input_signals(
-        &circuit, &mut nodes, &mut signal_node_idx, args.inputs_file);
-
     // assert that template id is equal to index in templates list
     for (i, t) in circuit.templates.iter().enumerate() {
         assert_eq!(i, t.id);
@@ -2236,16 +2538,47 @@ fn main() {
         }
     }
 
+    // Gadgets do not set the internal signals of the templates, so the
+    // template instances with internal signals in the witness are run as
+    // usual.
+    let mut gadgets = Gadgets::new(
+        args.gadgets.clone(), &circuit.templates, &witness_list);
+    let mut signal_node_idx: Vec<usize> =
+        vec![usize::MAX; circuit.c_producer.total_number_of_signals];
+
+    let mut nodes = Nodes::new();
+    nodes.set_decompose(args.gadgets.iter().any(|g| g == "Num2Bits"));
+    nodes.extend(get_constants(&circuit));
+
+    let (input_signals, input_signal_values): (InputSignalsInfo, Vec<U256>) = init_input_signals(
+        &circuit, &mut nodes, &mut signal_node_idx, args.inputs_file.clone());
+
     let main_component_signal_start = 1usize;
     run_template(
-        &circuit.templates, &circuit.functions, main_template_id, &mut nodes,
-        &mut signal_node_idx, main_component_signal_start,
-        circuit.c_producer.get_io_map(), args.print_debug, &vec![]);
+        &circuit.templates, &circuit.functions, &mut gadgets,
+        main_template_id, &mut nodes, &mut signal_node_idx,
+        main_component_signal_start, circuit.c_producer.get_io_map(),
+        args.print_debug, &[]);
+    if gadgets.expanded > 0 {
+        println!(
+            "{} gadget template instances have internal signals in the witness and were run without gadgets",
+            gadgets.expanded);
+    }
+    // optimization passes work on the decoded nodes
+    let mut nodes = nodes.into_nodes();
 
-    for (idx, i) in signal_node_idx.iter().enumerate() {
-        if *i == usize::MAX {
-            println!("[warning] signal #{} is not set", idx);
+    if gadgets.used.is_empty() {
+        for (idx, i) in signal_node_idx.iter().enumerate() {
+            if *i == usize::MAX {
+                println!("[warning] signal #{} is not set", idx);
+            }
         }
+    } else {
+        // internal signals of the gadgets are expected to be not set
+        println!(
+            "{} template instances computed with gadgets, {} signals are not set",
+            gadgets.used.len(),
+            signal_node_idx.iter().filter(|&&i| i == usize::MAX).count());
     }
 
     let mut witness_node_idxes = witness_list
@@ -2263,19 +2596,154 @@ fn main() {
 
     println!("number of nodes {}, signals {}", nodes.len(), witness_node_idxes.len());
 
-    optimize(&mut nodes.0, &mut witness_node_idxes);
+    let unoptimized = if args.verify_inputs > 0 {
+        Some((nodes.clone(), witness_node_idxes.clone()))
+    } else {
+        None
+    };
+
+    let mut pass_manager = PassManager::with_level(
+        args.opt_level, args.probabilistic_constants);
+    if let Some(max_passes) = args.max_passes {
+        pass_manager.truncate(max_passes);
+    }
+    pass_manager.verify(args.verify_passes);
+    let stats = pass_manager.run(&mut nodes, &mut witness_node_idxes);
+    for s in stats.iter() {
+        println!("{}", s);
+    }
 
     println!(
         "number of nodes after optimize {}, signals {}",
         nodes.len(), witness_node_idxes.len());
 
+    if let Some((unoptimized_nodes, unoptimized_outputs)) = unoptimized {
+        let inputs = verification_inputs(
+            &input_signal_values, args.inputs_file.is_some(), args.verify_inputs);
+        if let Err(e) = verify_optimized(
+            &unoptimized_nodes, &unoptimized_outputs, &nodes,
+            &witness_node_idxes, &inputs) {
+
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        println!("optimized graph verified on {} input sets", inputs.len());
+    }
+
+    let mut metadata = graph_metadata(&witness_node_idxes, &input_signals);
+    match public_signals_layout(&circuit, &public_inputs) {
+        Ok(layout) => metadata.public_signals = Some(layout),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(symbols) = symbols {
+        metadata.symbols = symbols.into_symbols();
+    }
+    let node_slots = allocate_slots(&nodes);
+    println!(
+        "number of evaluator slots {}",
+        node_slots.iter().max().map_or(0, |&m| m + 1));
+    metadata.node_slots = node_slots.iter().map(|&s| s as u64).collect();
+
     let f = fs::File::create(&args.graph_file).unwrap();
-    serialize_witnesscalc_graph(f, &nodes, &witness_node_idxes, &input_signals).unwrap();
+    serialize_witnesscalc_graph_with_metadata(f, &nodes, &metadata).unwrap();
 
     println!("circuit graph saved to file: {}", &args.graph_file)
 }
 
-fn evaluate_unoptimized(nodes: &Nodes, inputs: &[U256], signal_node_idx: &Vec<usize>, witness_signals: &[usize]) {
+// Seed for the random inputs of the optimized graph verification.
+const VERIFY_SEED: u64 = 0x7665726966790000;
+
+// Input sets to verify the optimized graph with. If inputs were provided by
+// the user, they are the first set, the rest are random. The first input is
+// always the constant 1 signal.
+fn verification_inputs(
+    user_inputs: &[U256], has_user_inputs: bool, n: usize) -> Vec<Vec<U256>> {
+
+    let mut rng = ChaCha8Rng::seed_from_u64(VERIFY_SEED);
+    let mut inputs = Vec::with_capacity(n);
+    if has_user_inputs && n > 0 {
+        inputs.push(user_inputs.to_vec());
+    }
+    while inputs.len() < n {
+        let mut set = vec![U256::from(1)];
+        set.extend((1..user_inputs.len()).map(|_| rng.gen::<U256>() % M));
+        inputs.push(set);
+    }
+    inputs
+}
+
+// Evaluate the unoptimized graph on U256 values and the optimized graph in
+// Montgomery form and return an error with the first diverging witness index.
+fn verify_optimized(
+    unoptimized: &[Node], unoptimized_outputs: &[usize], optimized: &[Node],
+    optimized_outputs: &[usize], inputs: &[Vec<U256>]) -> Result<(), String> {
+
+    assert_eq!(unoptimized_outputs.len(), optimized_outputs.len());
+    for (i, inputs) in inputs.iter().enumerate() {
+        let want = evaluate_reference(unoptimized, inputs, unoptimized_outputs);
+        let got = evaluate(optimized, inputs, optimized_outputs);
+        if let Some(idx) = (0..want.len()).find(|&j| want[j] != got[j]) {
+            return Err(format!(
+                "optimized graph diverges from unoptimized on input set #{} at witness index {}: want {}, got {}",
+                i, idx, want[idx], got[idx]));
+        }
+    }
+    Ok(())
+}
+
+// Number of public outputs, public inputs and private inputs of the main
+// component, the same as in the header of the r1cs file.
+fn public_signals_layout(
+    circuit: &Circuit, public_inputs: &[String]) -> Result<PublicSignals, String> {
+
+    let n_pub_out = circuit.c_producer.get_number_of_main_outputs();
+    let mut n_pub_in = 0usize;
+    let mut n_prv_in = 0usize;
+    for (name, offset, len) in circuit.c_producer.get_main_input_list() {
+        if public_inputs.contains(name) {
+            // public signals go right after the constant 1 and public outputs
+            if *offset != 1 + n_pub_out + n_pub_in {
+                return Err(format!(
+                    "public input {} is expected to be before private inputs",
+                    name));
+            }
+            n_pub_in += len;
+        } else {
+            n_prv_in += len;
+        }
+    }
+
+    Ok(PublicSignals {
+        n_pub_out: n_pub_out as u64,
+        n_pub_in: n_pub_in as u64,
+        n_prv_in: n_prv_in as u64,
+    })
+}
+
+// Get the signal symbol table from circom. The constraint writer can only
+// write the .sym file to the disk, so we write it to the temporary file and
+// parse it back.
+fn read_symbols(constraint_writer: &ConstraintWriter) -> SymbolTable {
+    let sym_file = env::temp_dir()
+        .join(format!("build-circuit-{}.sym", std::process::id()));
+    let sym_file_str = sym_file.to_str()
+        .expect("temporary file path is not a valid string");
+
+    constraint_writer.sym(sym_file_str)
+        .unwrap_or_else(|_| panic!("failed to write sym file to {}", sym_file_str));
+
+    let sym_data = fs::read_to_string(&sym_file)
+        .expect("failed to read sym file");
+    fs::remove_file(&sym_file).expect("failed to remove sym file");
+
+    SymbolTable::parse_sym(&sym_data)
+        .unwrap_or_else(|e| panic!("failed to parse sym file: {}", e))
+}
+
+fn evaluate_unoptimized(nodes: &[Node], inputs: &[U256], signal_node_idx: &[usize], witness_signals: &[usize]) {
     let mut node_idx_to_signal: HashMap<usize, Vec<usize>> = HashMap::new();
     for (signal_idx, &node_idx) in signal_node_idx.iter().enumerate() {
         if node_idx == usize::MAX {
@@ -2296,16 +2764,23 @@ fn evaluate_unoptimized(nodes: &Nodes, inputs: &[U256], signal_node_idx: &Vec<us
     }
 
     let mut values = Vec::with_capacity(nodes.len());
+    let mut gadget_values: HashMap<usize, Vec<U256>> = HashMap::new();
 
     println!("<node idx> <value> <signal indexes> <witness indexes> <node descr>");
-    for (node_idx, &node) in nodes.iter().enumerate() {
-        let value = match node {
+    for (node_idx, node) in nodes.iter().enumerate() {
+        let value = match *node {
             Node::Constant(c) => c,
             Node::MontConstant(_) => { panic!("no montgomery constant expected in unoptimized graph") }
             Node::Input(i) => inputs[i],
             Node::Op(op, a, b) => op.eval(values[a], values[b]),
             Node::UnoOp(op, a) => op.eval(values[a]),
             Node::TresOp(op, a, b, c) => op.eval(values[a], values[b], values[c]),
+            Node::Gadget(gadget, ref args) => {
+                let args = args.iter().map(|&a| values[a]).collect::<Vec<U256>>();
+                gadget_values.insert(node_idx, gadget.eval(&args));
+                U256::ZERO
+            }
+            Node::Extract(g, k) => gadget_values[&g][k],
         };
         values.push(value);
 
@@ -2328,14 +2803,15 @@ fn evaluate_unoptimized(nodes: &Nodes, inputs: &[U256], signal_node_idx: &Vec<us
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn store_subcomponent_signals(
     cmp_address: &InstructionPointer, input_information: &InputInformation,
     nodes: &mut Nodes, tmpl_vars: &mut Vec<Option<Var>>,
     component_signal_start: usize, signal_node_idx: &mut Vec<usize>,
-    subcomponents: &mut Vec<Option<ComponentInstance>>,
-    io_map: &TemplateInstanceIOMap, src_node_idxs: &Vec<usize>, dest: &LocationRule,
-    size: usize, templates: &Vec<TemplateCode>, functions: &Vec<FunctionCode>,
-    print_debug: bool, call_stack: &Vec<String>) {
+    subcomponents: &mut [Option<ComponentInstance>],
+    io_map: &TemplateInstanceIOMap, src_node_idxs: &[usize], dest: &LocationRule,
+    size: usize, templates: &[TemplateCode], functions: &[FunctionCode],
+    gadgets: &mut Gadgets, print_debug: bool, call_stack: &[String]) {
 
     let input_status: &StatusInput;
     if let InputInformation::Input { ref status } = input_information {
@@ -2366,7 +2842,7 @@ fn store_subcomponent_signals(
         LocationRule::Mapped { ref signal_code, ref indexes } => {
             calc_mapped_signal_idx(
                 subcomponents, subcomponent_idx, io_map,
-                signal_code.clone(), indexes, nodes, tmpl_vars,
+                *signal_code, indexes, nodes, tmpl_vars,
                 component_signal_start, signal_node_idx, print_debug,
                 call_stack)
         }
@@ -2414,6 +2890,7 @@ fn store_subcomponent_signals(
         run_template(
             templates,
             functions,
+            gadgets,
             subcomponents[subcomponent_idx]
                 .as_ref()
                 .unwrap()
@@ -2433,12 +2910,8 @@ fn store_subcomponent_signals(
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-
     #[test]
     fn test_calc_const_expression() {
         println!("OK");
     }
 }
-
-// TODO remove nodes.0 from this file
\ No newline at end of file
@@ -2,25 +2,71 @@ use std::env;
 use std::fs::File;
 use std::io::Write;
 use std::time::Instant;
-use circom_witnesscalc::{calc_witness, wtns_from_witness};
+use circom_witnesscalc::{outputs_json, public_signals, public_signals_json, wtns_from_witness, Graph};
 
 struct Args {
     graph_file: String,
     inputs_file: String,
-    witness_file: String,
+    // None in the --only-outputs mode
+    witness_file: Option<String>,
+    public_file: Option<String>,
+    only_outputs: bool,
 }
 
 fn parse_args() -> Args {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 4 {
-        eprintln!("Usage: {} <graph.bin> <inputs.json> <witness.wtns>", args[0]);
+
+    let usage = |err_msg: &str| -> ! {
+        if !err_msg.is_empty() {
+            eprintln!("{}", err_msg);
+        }
+        eprintln!("Usage: {} <graph.bin> <inputs.json> <witness.wtns> [--public <public.json>]", args[0]);
+        eprintln!("       {} <graph.bin> <inputs.json> --only-outputs", args[0]);
         std::process::exit(1);
+    };
+
+    let mut positional: Vec<String> = Vec::new();
+    let mut public_file: Option<String> = None;
+    let mut only_outputs = false;
+
+    let mut i = 1;
+    while i < args.len() {
+        if args[i] == "--public" {
+            i += 1;
+            if i >= args.len() {
+                usage("missing argument for --public");
+            }
+            if public_file.is_some() {
+                usage("multiple public signals files");
+            }
+            public_file = Some(args[i].clone());
+        } else if args[i] == "--only-outputs" {
+            only_outputs = true;
+        } else if args[i].starts_with("-") {
+            usage(format!("unknown argument: {}", args[i]).as_str());
+        } else {
+            positional.push(args[i].clone());
+        }
+        i += 1;
+    }
+
+    if only_outputs {
+        if positional.len() != 2 {
+            usage("");
+        }
+        if public_file.is_some() {
+            usage("--public can't be used with --only-outputs");
+        }
+    } else if positional.len() != 3 {
+        usage("");
     }
 
     Args {
-        graph_file: args[1].clone(),
-        inputs_file: args[2].clone(),
-        witness_file: args[3].clone(),
+        graph_file: positional[0].clone(),
+        inputs_file: positional[1].clone(),
+        witness_file: positional.get(2).cloned(),
+        public_file,
+        only_outputs,
     }
 }
 
@@ -34,18 +80,42 @@ fn main() {
 
     let start = Instant::now();
 
-    let witness = calc_witness(&inputs_data, &graph_data).unwrap();
+    let graph = Graph::deserialize(&graph_data).expect("Failed to read graph file");
+
+    if args.only_outputs {
+        let outputs = graph.calc_outputs(&inputs_data).unwrap();
+        eprintln!("Outputs calculated in: {:?}", start.elapsed());
+        println!("{}", outputs_json(&outputs));
+        return;
+    }
+
+    let witness_file = args.witness_file.as_ref().unwrap();
+
+    let witness = graph.calc_witness(&inputs_data).unwrap();
+
+    let public_json = args.public_file.as_ref().map(|_| {
+        let public = public_signals(&witness, graph.metadata())
+            .expect("Failed to get public signals");
+        public_signals_json(public)
+    });
+
     let wtns_bytes = wtns_from_witness(witness);
 
     let duration = start.elapsed();
     println!("Witness generated in: {:?}", duration);
 
     {
-        let mut f = File::create(&args.witness_file).unwrap();
+        let mut f = File::create(witness_file).unwrap();
         f.write_all(&wtns_bytes).unwrap();
     }
 
-    println!("witness saved to {}", &args.witness_file);
+    println!("witness saved to {}", witness_file);
+
+    if let (Some(public_file), Some(public_json)) = (&args.public_file, public_json) {
+        let mut f = File::create(public_file).unwrap();
+        f.write_all(public_json.as_bytes()).unwrap();
+        println!("public signals saved to {}", public_file);
+    }
 }
 
 #[cfg(test)]
@@ -55,4 +125,4 @@ mod tests {
         println!("OK");
     }
 
-}
\ No newline at end of file
+}
@@ -0,0 +1,22 @@
+use std::env;
+use std::fs::File;
+use circom_witnesscalc::c_codegen::graph_to_c;
+use circom_witnesscalc::storage::deserialize_witnesscalc_graph;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} <graph.bin> <witness.c>", args[0]);
+        std::process::exit(1);
+    }
+
+    let f = File::open(&args[1]).expect("Failed to open graph file");
+    let (nodes, witness_signals, input_signals) =
+        deserialize_witnesscalc_graph(std::io::BufReader::new(f))
+            .expect("Failed to read graph file");
+
+    let code = graph_to_c(&nodes, &witness_signals, &input_signals);
+    std::fs::write(&args[2], code).expect("Failed to write C file");
+
+    println!("C code saved to {}", &args[2]);
+}
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use ruint::aliases::U256;
+use circom_witnesscalc::specialize::specialize_with_metadata;
+use circom_witnesscalc::storage::{deserialize_witnesscalc_graph_with_metadata, serialize_witnesscalc_graph_with_metadata};
+
+struct Args {
+    input_file: String,
+    output_file: String,
+    fixed: HashMap<String, Vec<U256>>,
+}
+
+fn parse_args() -> Args {
+    let args: Vec<String> = env::args().collect();
+
+    let usage = |err_msg: &str| -> ! {
+        if !err_msg.is_empty() {
+            eprintln!("{}", err_msg);
+        }
+        eprintln!("Usage: {} <graph.bin> [--fix <name>=<value>[,<value>]*]* <specialized_graph.bin>", args[0]);
+        std::process::exit(1);
+    };
+
+    let mut positional: Vec<String> = Vec::new();
+    let mut fixed: HashMap<String, Vec<U256>> = HashMap::new();
+
+    let mut i = 1;
+    while i < args.len() {
+        if args[i] == "--fix" {
+            i += 1;
+            if i >= args.len() {
+                usage("missing argument for --fix");
+            }
+            let Some((name, values)) = args[i].split_once('=') else {
+                usage(format!("invalid --fix argument: {}", args[i]).as_str());
+            };
+            let values = values.split(',')
+                .map(|v| U256::from_str_radix(v.trim(), 10))
+                .collect::<Result<Vec<U256>, _>>()
+                .unwrap_or_else(|e| usage(format!("invalid value of {}: {}", name, e).as_str()));
+            if fixed.insert(name.to_string(), values).is_some() {
+                usage(format!("input {} is fixed multiple times", name).as_str());
+            }
+        } else if args[i].starts_with("-") {
+            usage(format!("unknown argument: {}", args[i]).as_str());
+        } else {
+            positional.push(args[i].clone());
+        }
+        i += 1;
+    }
+
+    if positional.len() != 2 {
+        usage("");
+    }
+
+    Args {
+        input_file: positional[0].clone(),
+        output_file: positional[1].clone(),
+        fixed,
+    }
+}
+
+fn main() {
+    let args = parse_args();
+
+    let f = File::open(&args.input_file).expect("Failed to open graph file");
+    let (mut nodes, mut metadata) =
+        deserialize_witnesscalc_graph_with_metadata(BufReader::new(f))
+            .expect("Failed to read graph file");
+
+    let nodes_num = nodes.len();
+    let stats = match specialize_with_metadata(&mut nodes, &mut metadata, &args.fixed) {
+        Ok(stats) => stats,
+        Err(e) => {
+            eprintln!("Failed to specialize the graph: {}", e);
+            std::process::exit(1);
+        }
+    };
+    for s in stats.iter() {
+        println!("{}", s);
+    }
+    println!("nodes {} -> {}", nodes_num, nodes.len());
+
+    let f = File::create(&args.output_file).expect("Failed to create graph file");
+    serialize_witnesscalc_graph_with_metadata(BufWriter::new(f), &nodes, &metadata)
+        .expect("Failed to write graph file");
+
+    println!("specialized graph saved to {}", &args.output_file);
+}
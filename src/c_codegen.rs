@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use ruint::aliases::U256;
+use crate::field::M;
+use crate::gadgets::Gadget;
+use crate::graph::{Node, Operation, TresOperation, UnoOperation};
+use crate::InputSignalsInfo;
+
+const RUNTIME: &str = include_str!("../include/bn254_runtime.c");
+const CONSTANTS_MARKER: &str = "// @GRAPH_CONSTANTS@\n";
+// Number of nodes per generated function. C compilers slow down much faster
+// than linearly on huge functions.
+const CHUNK_NODES: usize = 1024;
+
+/// Generate the self-contained C file that calculates the witness of the
+/// graph. The file bundles BN254 Montgomery arithmetic with 32-bit limbs and
+/// exports `gw_calc_witness` with the same signature as the library, except
+/// that the graph is compiled in and `graph_data` is ignored. The result is
+/// the same as of [crate::graph::evaluate] where the interpreter does not
+/// panic. The nodes are calculated by functions of a fixed number of nodes,
+/// called in order by `calc_nodes`.
+pub fn graph_to_c(
+    nodes: &[Node], witness_signals: &[usize],
+    input_signals: &InputSignalsInfo) -> String {
+
+    let (head, tail) = RUNTIME.split_once(CONSTANTS_MARKER)
+        .expect("constants marker is not found in the C runtime");
+
+    // Writing to a String never fails.
+    let mut w = String::new();
+    writeln!(w, "// Code generated by graph2c. DO NOT EDIT.").unwrap();
+    writeln!(w).unwrap();
+    w.push_str(head);
+
+    let r = mont_r();
+    writeln!(w, "static const fr_t FR_P = {};", fr_literal(M)).unwrap();
+    writeln!(w, "static const fr_t FR_R2 = {};", fr_literal(r.mul_mod(r, M))).unwrap();
+    writeln!(w, "static const fr_t FR_ONE = {};", fr_literal(r)).unwrap();
+    writeln!(w, "static const fr_t FR_HALF = {};", fr_literal(M >> 1)).unwrap();
+    writeln!(w, "static const uint32_t FR_NP = 0x{:08x}u;", np()).unwrap();
+    writeln!(w).unwrap();
+
+    let inputs_num = nodes.iter()
+        .filter_map(|n| match n {
+            Node::Input(i) => Some(*i + 1),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    writeln!(w, "#define NODES_NUM ((size_t){})", nodes.len()).unwrap();
+    writeln!(w, "#define INPUTS_NUM ((size_t){})", inputs_num).unwrap();
+    writeln!(w, "#define WITNESS_NUM ((size_t){})", witness_signals.len()).unwrap();
+
+    // Empty arrays are not allowed in C, so add a dummy element.
+    let mut signals = witness_signals.iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<String>>();
+    if signals.is_empty() {
+        signals.push("0".to_string());
+    }
+    writeln!(
+        w, "static const size_t WITNESS_SIGNALS[] = {{{}}};",
+        signals.join(", ")).unwrap();
+
+    let mut inputs = input_signals.iter()
+        .map(|(name, (offset, len))| (name.as_str(), *offset, *len))
+        .collect::<Vec<(&str, usize, usize)>>();
+    inputs.sort_by_key(|(_, offset, _)| *offset);
+    writeln!(w, "#define INPUT_SIGNALS_NUM ((size_t){})", inputs.len()).unwrap();
+    writeln!(w, "static const struct {{").unwrap();
+    writeln!(w, "  const char *name;").unwrap();
+    writeln!(w, "  size_t offset;").unwrap();
+    writeln!(w, "  size_t len;").unwrap();
+    writeln!(w, "}} INPUT_SIGNALS[] = {{").unwrap();
+    for (name, offset, len) in inputs.iter() {
+        writeln!(w, "  {{{}, {}, {}}},", c_string(name), offset, len).unwrap();
+    }
+    if inputs.is_empty() {
+        writeln!(w, "  {{NULL, 0, 0}},").unwrap();
+    }
+    writeln!(w, "}};").unwrap();
+    writeln!(w).unwrap();
+
+    w.push_str(tail);
+
+    // Results of the gadget are written directly to the extract nodes.
+    let mut extracts: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+    for (i, node) in nodes.iter().enumerate() {
+        if let Node::Extract(g, k) = *node {
+            extracts.entry(g).or_default().push((i, k));
+        }
+    }
+
+    for (c, chunk) in nodes.chunks(CHUNK_NODES).enumerate() {
+        writeln!(w).unwrap();
+        writeln!(
+            w, "static void calc_nodes_{}(const fr_t *inputs, fr_t *v) {{", c)
+            .unwrap();
+        if !chunk.iter().any(|n| matches!(n, Node::Input(_))) {
+            writeln!(w, "  (void)inputs;").unwrap();
+        }
+        for (i, node) in chunk.iter().enumerate() {
+            write_node(&mut w, c * CHUNK_NODES + i, node, &extracts, r);
+        }
+        writeln!(w, "}}").unwrap();
+    }
+
+    writeln!(w).unwrap();
+    writeln!(w, "static void calc_nodes(const fr_t *inputs, fr_t *v) {{").unwrap();
+    if nodes.is_empty() {
+        writeln!(w, "  (void)inputs;").unwrap();
+        writeln!(w, "  (void)v;").unwrap();
+    }
+    for c in 0..nodes.len().div_ceil(CHUNK_NODES) {
+        writeln!(w, "  calc_nodes_{}(inputs, v);", c).unwrap();
+    }
+    writeln!(w, "}}").unwrap();
+
+    w
+}
+
+// Write the statement that calculates the node i.
+fn write_node(
+    w: &mut String, i: usize, node: &Node,
+    extracts: &HashMap<usize, Vec<(usize, usize)>>, r: U256) {
+
+    match *node {
+        Node::Gadget(gadget, ref args) => {
+            let args_list = args.iter()
+                .map(|a| format!("&v[{}]", a))
+                .collect::<Vec<String>>();
+            writeln!(w, "  {{").unwrap();
+            writeln!(
+                w, "    const fr_t *args[] = {{{}}};",
+                if args.is_empty() { "NULL".to_string() } else { args_list.join(", ") })
+                .unwrap();
+            writeln!(
+                w, "    fr_t out[{}];", gadget.outputs_num(args.len())).unwrap();
+            writeln!(w, "    {};", c_gadget_call(gadget, args.len())).unwrap();
+            for (e, k) in extracts.get(&i).map_or(&[][..], |v| v.as_slice()) {
+                writeln!(w, "    v[{}] = out[{}];", e, k).unwrap();
+            }
+            writeln!(w, "  }}").unwrap();
+        }
+        Node::Extract(..) => {}
+        Node::Input(n) => {
+            writeln!(w, "  v[{}] = inputs[{}];", i, n).unwrap();
+        }
+        Node::Constant(c) => {
+            let c = (c % M).mul_mod(r, M);
+            writeln!(w, "  v[{}] = (fr_t){};", i, fr_literal(c)).unwrap();
+        }
+        Node::MontConstant(c) => {
+            let c = Into::<U256>::into(c).mul_mod(r, M);
+            writeln!(w, "  v[{}] = (fr_t){};", i, fr_literal(c)).unwrap();
+        }
+        Node::UnoOp(UnoOperation::Neg, a) => {
+            writeln!(w, "  fr_neg(&v[{}], &v[{}]);", i, a).unwrap();
+        }
+        Node::UnoOp(UnoOperation::Id, a) => {
+            writeln!(w, "  v[{}] = v[{}];", i, a).unwrap();
+        }
+        Node::Op(op, a, b) => {
+            writeln!(
+                w, "  {}(&v[{}], &v[{}], &v[{}]);", c_function(op), i, a, b)
+                .unwrap();
+        }
+        Node::TresOp(TresOperation::TernCond, a, b, c) => {
+            writeln!(
+                w, "  fr_tern_cond(&v[{}], &v[{}], &v[{}], &v[{}]);",
+                i, a, b, c).unwrap();
+        }
+    }
+}
+
+// 2^256 mod M, Montgomery form of 1.
+fn mont_r() -> U256 {
+    (U256::MAX % M + U256::from(1)) % M
+}
+
+// -M^-1 mod 2^32
+fn np() -> u32 {
+    let m0 = M.as_limbs()[0] as u32;
+    // Newton's iteration doubles the number of correct bits each step.
+    let mut inv = 1u32;
+    for _ in 0..5 {
+        inv = inv.wrapping_mul(2u32.wrapping_sub(m0.wrapping_mul(inv)));
+    }
+    inv.wrapping_neg()
+}
+
+fn fr_literal(x: U256) -> String {
+    let limbs = x.as_limbs().iter()
+        .flat_map(|&l| [l as u32, (l >> 32) as u32])
+        .map(|l| format!("0x{:08x}u", l))
+        .collect::<Vec<String>>();
+    format!("{{{{{}}}}}", limbs.join(", "))
+}
+
+fn c_string(s: &str) -> String {
+    let mut r = String::with_capacity(s.len() + 2);
+    r.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => r.push_str("\\\""),
+            '\\' => r.push_str("\\\\"),
+            _ => r.push(c),
+        }
+    }
+    r.push('"');
+    r
+}
+
+fn c_gadget_call(gadget: Gadget, args_num: usize) -> String {
+    match gadget {
+        Gadget::Sha256 => format!("gadget_sha256(args, {}, out)", args_num),
+        Gadget::Bits(n) => format!("gadget_limbs(args[0], 1, {}, out)", n),
+        Gadget::Limbs { limb_bits, limbs_num } => {
+            format!("gadget_limbs(args[0], {}, {}, out)", limb_bits, limbs_num)
+        }
+        Gadget::BigMul { limb_bits, limbs_num } => {
+            format!("gadget_big_mul(args, {}, {}, out)", limb_bits, limbs_num)
+        }
+        Gadget::BigDivMod { limb_bits, limbs_num, extra_limbs } => {
+            format!(
+                "gadget_big_divmod(args, {}, {}, {}, out)", limb_bits,
+                limbs_num, extra_limbs)
+        }
+        Gadget::BigModExp { limb_bits, limbs_num } => {
+            format!("gadget_big_modexp(args, {}, {}, out)", limb_bits, limbs_num)
+        }
+        Gadget::BigModInv { limb_bits, limbs_num } => {
+            format!("gadget_big_modinv(args, {}, {}, out)", limb_bits, limbs_num)
+        }
+    }
+}
+
+fn c_function(op: Operation) -> &'static str {
+    match op {
+        Operation::Mul => "fr_mul",
+        Operation::Div => "fr_div",
+        Operation::Add => "fr_add",
+        Operation::Sub => "fr_sub",
+        Operation::Pow => "fr_pow",
+        Operation::Idiv => "fr_idiv",
+        Operation::Mod => "fr_mod",
+        Operation::Eq => "fr_eq",
+        Operation::Neq => "fr_neq",
+        Operation::Lt => "fr_lt",
+        Operation::Gt => "fr_gt",
+        Operation::Leq => "fr_leq",
+        Operation::Geq => "fr_geq",
+        Operation::Land => "fr_land",
+        Operation::Lor => "fr_lor",
+        Operation::Shl => "fr_shl",
+        Operation::Shr => "fr_shr",
+        Operation::Bor => "fr_bor",
+        Operation::Band => "fr_band",
+        Operation::Bxor => "fr_bxor",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use ark_bn254::Fr;
+    use ark_ff::{BigInteger, PrimeField};
+    use crate::graph::evaluate;
+
+    #[test]
+    fn test_graph_to_c() {
+        let (nodes, outputs, input_signals) = crate::test_graphs::all_operations();
+        let inputs_json = r#"{"a": ["12", 0], "b": "21888242871839275222246405745257275088548364400416034343698204186575808495610"}"#;
+        let inputs = vec![
+            U256::from(1), U256::from(12), U256::from(0),
+            M - U256::from(7)];
+        let want = evaluate(&nodes, &inputs, &outputs);
+
+        let dir = std::env::temp_dir()
+            .join(format!("graph2c_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let code = graph_to_c(&nodes, &outputs, &input_signals);
+        // the graph does not fit into one function
+        assert!(nodes.len() > CHUNK_NODES);
+        assert!(code.contains("calc_nodes_1(inputs, v);"));
+        std::fs::write(dir.join("witness.c"), code).unwrap();
+        std::fs::write(dir.join("main.c"), format!(r#"
+#include <stdio.h>
+#include "witness.c"
+
+int main(void) {{
+  void *wtns;
+  size_t wtns_len;
+  gw_status_t status = {{OK, NULL}};
+  if (gw_calc_witness({:?}, NULL, 0, &wtns, &wtns_len, &status) != 0) {{
+    fprintf(stderr, "%s\n", status.error_msg);
+    return 1;
+  }}
+  fwrite(wtns, 1, wtns_len, stdout);
+  return 0;
+}}
+"#, inputs_json)).unwrap();
+
+        let bin = dir.join("witness");
+        let compiled = Command::new("cc")
+            .args(["-std=c99", "-O1", "-Wall", "-Werror", "-o"])
+            .arg(&bin)
+            .arg(dir.join("main.c"))
+            .status()
+            .expect("C compiler cc is required for this test");
+        assert!(compiled.success(), "failed to compile generated C");
+        let out = Command::new(&bin).output().unwrap();
+        assert!(
+            out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+        let wtns = out.stdout;
+        assert_eq!(&wtns[0..4], b"wtns");
+        let n = u32::from_le_bytes(wtns[60..64].try_into().unwrap()) as usize;
+        assert_eq!(n, want.len());
+        assert_eq!(&wtns[28..60], Fr::MODULUS.to_bytes_le().as_slice());
+        let values = &wtns[76..];
+        assert_eq!(values.len(), n * 32);
+        for (i, w) in want.iter().enumerate() {
+            let got = U256::from_le_slice(&values[i * 32..(i + 1) * 32]);
+            assert_eq!(got, *w, "witness #{}, node {:?}", i, nodes[outputs[i]]);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
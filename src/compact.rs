@@ -0,0 +1,564 @@
+use ark_bn254::Fr;
+use ruint::aliases::U256;
+use crate::field::M;
+use crate::gadgets::Gadget;
+use crate::graph::{allocate_slots_by, GadgetValues, Node, Operation, TresOperation, UnoOperation, Value};
+
+/// Operation of the node in [CompactNodes].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpCode {
+    Input,
+    Constant,
+    MontConstant,
+    UnoOp(UnoOperation),
+    Op(Operation),
+    TresOp(TresOperation),
+    Gadget,
+    Extract,
+}
+
+/// Graph nodes in the compact layout. `Node` stores `usize` operands and the
+/// constants inline, so each node takes the size of the largest variant. Here
+/// opcodes and operands are kept in separate arrays with `u32` operands, and
+/// the constants, gadgets and gadget arguments are in the pools referenced by
+/// index from the operands. The meaning of the operands depends on the opcode:
+///
+/// * `Input`: index in the inputs buffer;
+/// * `Constant`, `MontConstant`: index in the constants pool;
+/// * `UnoOp`, `Op`, `TresOp`: operand nodes;
+/// * `Gadget`: index in the gadgets pool, index of the first argument in the
+///   arguments pool and the number of arguments;
+/// * `Extract`: gadget node and the result index.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompactNodes {
+    ops: Vec<OpCode>,
+    operands: Vec<[u32; 3]>,
+    constants: Vec<U256>,
+    mont_constants: Vec<Fr>,
+    gadgets: Vec<Gadget>,
+    gadget_args: Vec<u32>,
+}
+
+/// The index of the node operand or of the pool entry does not fit into the
+/// `u32` operand of [CompactNodes].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexOverflow(pub usize);
+
+impl std::fmt::Display for IndexOverflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "index {} does not fit into u32", self.0)
+    }
+}
+
+impl std::error::Error for IndexOverflow {}
+
+impl From<IndexOverflow> for std::io::Error {
+    fn from(e: IndexOverflow) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+fn idx(i: usize) -> Result<u32, IndexOverflow> {
+    u32::try_from(i).map_err(|_| IndexOverflow(i))
+}
+
+impl CompactNodes {
+    pub fn new() -> Self {
+        CompactNodes::default()
+    }
+
+    pub fn with_capacity(n: usize) -> Self {
+        CompactNodes {
+            ops: Vec::with_capacity(n),
+            operands: Vec::with_capacity(n),
+            ..CompactNodes::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Append the node and return its index. Fails if an index does not fit
+    /// into `u32`, the nodes are not changed then.
+    pub fn push(&mut self, node: &Node) -> Result<usize, IndexOverflow> {
+        idx(self.ops.len())?;
+        let (op, operands) = match *node {
+            Node::Input(i) => (OpCode::Input, [idx(i)?, 0, 0]),
+            Node::Constant(c) => {
+                let operands = [idx(self.constants.len())?, 0, 0];
+                self.constants.push(c);
+                (OpCode::Constant, operands)
+            }
+            Node::MontConstant(c) => {
+                let operands = [idx(self.mont_constants.len())?, 0, 0];
+                self.mont_constants.push(c);
+                (OpCode::MontConstant, operands)
+            }
+            Node::UnoOp(op, a) => (OpCode::UnoOp(op), [idx(a)?, 0, 0]),
+            Node::Op(op, a, b) => (OpCode::Op(op), [idx(a)?, idx(b)?, 0]),
+            Node::TresOp(op, a, b, c) => {
+                (OpCode::TresOp(op), [idx(a)?, idx(b)?, idx(c)?])
+            }
+            Node::Gadget(gadget, ref args) => {
+                let operands = [
+                    idx(self.gadgets.len())?,
+                    idx(self.gadget_args.len())?,
+                    idx(args.len())?];
+                idx(self.gadget_args.len() + args.len())?;
+                let args = args.iter()
+                    .map(|&a| idx(a))
+                    .collect::<Result<Vec<u32>, IndexOverflow>>()?;
+                self.gadgets.push(gadget);
+                self.gadget_args.extend(args);
+                (OpCode::Gadget, operands)
+            }
+            Node::Extract(g, k) => (OpCode::Extract, [idx(g)?, idx(k)?, 0]),
+        };
+        self.ops.push(op);
+        self.operands.push(operands);
+        Ok(self.ops.len() - 1)
+    }
+
+    pub fn opcode(&self, i: usize) -> OpCode {
+        self.ops[i]
+    }
+
+    /// Call f for each operand node of the node i, the same as
+    /// [Node::for_each_operand].
+    pub fn for_each_operand(&self, i: usize, mut f: impl FnMut(usize)) {
+        let [a, b, c] = self.operands[i];
+        match self.ops[i] {
+            OpCode::Input | OpCode::Constant | OpCode::MontConstant => {}
+            OpCode::UnoOp(_) | OpCode::Extract => f(a as usize),
+            OpCode::Op(_) => {
+                f(a as usize);
+                f(b as usize);
+            }
+            OpCode::TresOp(_) => {
+                f(a as usize);
+                f(b as usize);
+                f(c as usize);
+            }
+            OpCode::Gadget => self.args(i).iter().for_each(|&a| f(a as usize)),
+        }
+    }
+
+    /// Number of the gadget nodes, the size of the gadgets pool.
+    #[cfg(feature = "parallel")]
+    pub(crate) fn gadgets_num(&self) -> usize {
+        self.gadgets.len()
+    }
+
+    /// Index of the gadget node i in the gadgets pool, the gadget nodes are
+    /// numbered from 0 in the order of the graph.
+    #[cfg(feature = "parallel")]
+    pub(crate) fn gadget_idx(&self, i: usize) -> usize {
+        debug_assert_eq!(self.ops[i], OpCode::Gadget);
+        self.operands[i][0] as usize
+    }
+
+    fn args(&self, i: usize) -> &[u32] {
+        let [_, start, n] = self.operands[i];
+        &self.gadget_args[start as usize..(start + n) as usize]
+    }
+
+    /// Decode the node at index i.
+    pub fn get(&self, i: usize) -> Node {
+        let [a, b, c] = self.operands[i].map(|x| x as usize);
+        match self.ops[i] {
+            OpCode::Input => Node::Input(a),
+            OpCode::Constant => Node::Constant(self.constants[a]),
+            OpCode::MontConstant => Node::MontConstant(self.mont_constants[a]),
+            OpCode::UnoOp(op) => Node::UnoOp(op, a),
+            OpCode::Op(op) => Node::Op(op, a, b),
+            OpCode::TresOp(op) => Node::TresOp(op, a, b, c),
+            OpCode::Gadget => Node::Gadget(
+                self.gadgets[a],
+                self.args(i).iter().map(|&x| x as usize).collect()),
+            OpCode::Extract => Node::Extract(a, b),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Node> + '_ {
+        (0..self.len()).map(|i| self.get(i))
+    }
+
+    pub fn to_vec(&self) -> Vec<Node> {
+        self.iter().collect()
+    }
+
+    /// Size of the inputs buffer: the maximum index of the input nodes plus
+    /// one. Input nodes are not necessarily at the beginning of the graph:
+    /// specialized graphs have constants in place of the fixed inputs.
+    pub fn inputs_size(&self) -> usize {
+        self.ops.iter().zip(self.operands.iter())
+            .filter(|(&op, _)| op == OpCode::Input)
+            .map(|(_, operands)| operands[0] as usize + 1)
+            .max()
+            .unwrap_or(1)
+    }
+
+    // Evaluate the node i with the operand values from the value function
+    // and the gadget results from the extract function. Gadget nodes have no
+    // value, their results are calculated by eval_gadget.
+    #[inline]
+    pub(crate) fn eval_node(
+        &self, i: usize, inputs: &[U256], value: impl Fn(u32) -> Value,
+        extract: impl Fn(usize, usize) -> Fr) -> Value {
+
+        let [a, b, c] = self.operands[i];
+        match self.ops[i] {
+            OpCode::Constant => Value::Int(self.constants[a as usize] % M),
+            OpCode::MontConstant => Value::Mont(self.mont_constants[a as usize]),
+            OpCode::Input => Value::Int(inputs[a as usize] % M),
+            OpCode::Op(op) => op.eval_value(value(a), value(b)),
+            OpCode::UnoOp(op) => op.eval_value(value(a)),
+            OpCode::TresOp(op) => op.eval_value(value(a), value(b), value(c)),
+            OpCode::Gadget => Value::default(),
+            OpCode::Extract => Value::Mont(extract(a as usize, b as usize)),
+        }
+    }
+
+    // Results of the gadget node i.
+    pub(crate) fn eval_gadget(&self, i: usize, value: impl Fn(u32) -> Value) -> Vec<Fr> {
+        let args = self.args(i).iter()
+            .map(|&x| value(x).fr())
+            .collect::<Vec<Fr>>();
+        self.gadgets[self.operands[i][0] as usize].eval_fr(&args)
+    }
+
+    // Evaluate the node i in the sequential evaluators: calculate the gadget
+    // results and release them after the last extract.
+    #[inline]
+    pub(crate) fn eval_step(
+        &self, i: usize, inputs: &[U256], value: impl Fn(u32) -> Value,
+        gadget_values: &mut GadgetValues<Fr>) -> Value {
+
+        match self.ops[i] {
+            OpCode::Gadget => {
+                gadget_values.insert(i, self.eval_gadget(i, value));
+                Value::default()
+            }
+            OpCode::Extract => {
+                let [g, k, _] = self.operands[i].map(|x| x as usize);
+                let v = gadget_values.get(g)[k];
+                gadget_values.extracted(i, g);
+                Value::Mont(v)
+            }
+            _ => self.eval_node(i, inputs, value, |_, _| unreachable!()),
+        }
+    }
+
+    /// Extract nodes and their gadget nodes, see [GadgetValues::new].
+    pub(crate) fn extracts(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (0..self.len()).filter_map(|i| self.extract_gadget(i).map(|g| (i, g)))
+    }
+
+    /// Gadget node of the extract node i, None for the other nodes.
+    pub(crate) fn extract_gadget(&self, i: usize) -> Option<usize> {
+        (self.ops[i] == OpCode::Extract).then(|| self.operands[i][0] as usize)
+    }
+
+    /// The same as [crate::graph::evaluate].
+    pub fn evaluate(&self, inputs: &[U256], outputs: &[usize]) -> Vec<U256> {
+        let mut values: Vec<Value> = Vec::with_capacity(self.len());
+        let mut gadget_values = GadgetValues::new(self.extracts());
+        for i in 0..self.len() {
+            let v = self.eval_step(
+                i, inputs, |a| values[a as usize], &mut gadget_values);
+            values.push(v);
+        }
+
+        outputs.iter().map(|&i| values[i].int()).collect()
+    }
+
+    /// The same as [crate::graph::evaluate_with_slots].
+    pub fn evaluate_with_slots(
+        &self, slots: &[usize], inputs: &[U256], outputs: &[usize]) -> Vec<U256> {
+
+        assert_eq!(self.len(), slots.len());
+
+        let mut outputs_order = (0..outputs.len()).collect::<Vec<usize>>();
+        outputs_order.sort_by_key(|&i| outputs[i]);
+        let mut next_output = 0;
+
+        let slots_num = slots.iter().max().map_or(0, |&m| m + 1);
+        let mut values = vec![Value::default(); slots_num];
+        let mut out = vec![U256::ZERO; outputs.len()];
+        let mut gadget_values = GadgetValues::new(self.extracts());
+        for i in 0..self.len() {
+            let value = self.eval_step(
+                i, inputs, |a| values[slots[a as usize]], &mut gadget_values);
+            values[slots[i]] = value;
+
+            while next_output < outputs_order.len()
+                && outputs[outputs_order[next_output]] == i {
+
+                out[outputs_order[next_output]] = value.int();
+                next_output += 1;
+            }
+        }
+
+        out
+    }
+
+    /// The same as [crate::graph::evaluate_subset].
+    pub fn evaluate_subset(
+        &self, inputs: &[U256], signals: &[usize], wanted: &[usize]) -> Vec<U256> {
+
+        let outputs = wanted.iter().map(|&i| signals[i]).collect::<Vec<usize>>();
+
+        // Operands always precede the node, so one backward pass is enough.
+        let mut needed = vec![false; self.len()];
+        for &i in outputs.iter() {
+            needed[i] = true;
+        }
+        for i in (0..self.len()).rev() {
+            if needed[i] {
+                self.for_each_operand(i, |a| needed[a] = true);
+            }
+        }
+
+        let mut values: Vec<Value> = vec![Value::default(); self.len()];
+        let mut gadget_values = GadgetValues::new(
+            self.extracts().filter(|&(i, _)| needed[i]));
+        for i in 0..self.len() {
+            if !needed[i] {
+                continue;
+            }
+            values[i] = self.eval_step(
+                i, inputs, |a| values[a as usize], &mut gadget_values);
+        }
+
+        outputs.iter().map(|&i| values[i].int()).collect()
+    }
+
+    /// Slots of the nodes for [CompactNodes::evaluate_with_slots] and
+    /// [CompactNodes::evaluate_batch_with_slots], the same as
+    /// [crate::graph::allocate_slots].
+    pub fn allocate_slots(&self) -> Vec<usize> {
+        allocate_slots_by(self.len(), |i, f| self.for_each_operand(i, f))
+    }
+
+    /// The same as [crate::graph::evaluate_batch]. The slots are allocated on
+    /// every call, use [CompactNodes::evaluate_batch_with_slots] to evaluate
+    /// many batches with the same graph.
+    pub fn evaluate_batch(
+        &self, inputs: &[Vec<U256>], outputs: &[usize]) -> Vec<Vec<U256>> {
+
+        self.evaluate_batch_with_slots(&self.allocate_slots(), inputs, outputs)
+    }
+
+    /// The same as [CompactNodes::evaluate_batch] with the slots from
+    /// [CompactNodes::allocate_slots] or from the graph metadata. The values
+    /// of the batch are in the structure-of-arrays layout: the k values of a
+    /// slot are contiguous, so each node is dispatched once and its operation
+    /// is applied in a loop over the k lanes.
+    pub fn evaluate_batch_with_slots(
+        &self, slots: &[usize], inputs: &[Vec<U256>],
+        outputs: &[usize]) -> Vec<Vec<U256>> {
+
+        assert_eq!(self.len(), slots.len());
+
+        let k = inputs.len();
+        if k == 0 {
+            return Vec::new();
+        }
+
+        let mut outputs_order = (0..outputs.len()).collect::<Vec<usize>>();
+        outputs_order.sort_by_key(|&i| outputs[i]);
+        let mut next_output = 0;
+
+        let slots_num = slots.iter().max().map_or(0, |&m| m + 1);
+        let mut values = vec![Fr::default(); slots_num * k];
+        let mut out = vec![vec![U256::ZERO; outputs.len()]; k];
+        // Gadget results for each input set by the gadget node index.
+        let mut gadget_values: GadgetValues<Vec<Fr>> = GadgetValues::new(self.extracts());
+        // first lane of the slot of the node
+        let lane = |x: u32| slots[x as usize] * k;
+        for (i, &slot) in slots.iter().enumerate() {
+            let r = slot * k;
+            let [a, b, c] = self.operands[i];
+            match self.ops[i] {
+                OpCode::Constant => {
+                    let v = Value::Int(self.constants[a as usize] % M).fr();
+                    values[r..r + k].fill(v);
+                }
+                OpCode::MontConstant => {
+                    values[r..r + k].fill(self.mont_constants[a as usize]);
+                }
+                OpCode::Input => {
+                    for (j, inputs) in inputs.iter().enumerate() {
+                        values[r + j] = Value::Int(inputs[a as usize] % M).fr();
+                    }
+                }
+                OpCode::UnoOp(op) => {
+                    let a = lane(a);
+                    for j in 0..k {
+                        values[r + j] = op.eval_fr(values[a + j]);
+                    }
+                }
+                OpCode::Op(op) => {
+                    let (a, b) = (lane(a), lane(b));
+                    match op {
+                        Operation::Add => map_lanes(&mut values, k, r, a, b, |x, y| x + y),
+                        Operation::Sub => map_lanes(&mut values, k, r, a, b, |x, y| x - y),
+                        Operation::Mul => map_lanes(&mut values, k, r, a, b, |x, y| x * y),
+                        _ => map_lanes(&mut values, k, r, a, b, |x, y| op.eval_fr(x, y)),
+                    }
+                }
+                OpCode::TresOp(op) => {
+                    let (a, b, c) = (lane(a), lane(b), lane(c));
+                    for j in 0..k {
+                        values[r + j] = op.eval_fr(
+                            values[a + j], values[b + j], values[c + j]);
+                    }
+                }
+                OpCode::Gadget => {
+                    let gadget = &self.gadgets[a as usize];
+                    let args = self.args(i);
+                    let results = (0..k)
+                        .map(|j| {
+                            let args = args.iter()
+                                .map(|&x| values[lane(x) + j])
+                                .collect::<Vec<Fr>>();
+                            gadget.eval_fr(&args)
+                        })
+                        .collect();
+                    gadget_values.insert(i, results);
+                    values[r..r + k].fill(Fr::default());
+                }
+                OpCode::Extract => {
+                    let results = gadget_values.get(a as usize);
+                    for j in 0..k {
+                        values[r + j] = results[j][b as usize];
+                    }
+                    gadget_values.extracted(i, a as usize);
+                }
+            }
+
+            while next_output < outputs_order.len()
+                && outputs[outputs_order[next_output]] == i {
+
+                let o = outputs_order[next_output];
+                for j in 0..k {
+                    out[j][o] = values[r + j].into();
+                }
+                next_output += 1;
+            }
+        }
+
+        out
+    }
+}
+
+// Apply f to the k lanes of the operand slots starting at a and b and write
+// the results to the lanes starting at r. The result slot may be the same as
+// an operand slot, it is fine as every lane is read before it is written.
+#[inline(always)]
+fn map_lanes(
+    values: &mut [Fr], k: usize, r: usize, a: usize, b: usize,
+    f: impl Fn(Fr, Fr) -> Fr) {
+
+    for j in 0..k {
+        values[r + j] = f(values[a + j], values[b + j]);
+    }
+}
+
+impl TryFrom<&[Node]> for CompactNodes {
+    type Error = IndexOverflow;
+
+    fn try_from(nodes: &[Node]) -> Result<Self, IndexOverflow> {
+        let mut compact = CompactNodes::with_capacity(nodes.len());
+        for node in nodes.iter() {
+            compact.push(node)?;
+        }
+        Ok(compact)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{allocate_slots, evaluate, montgomery_form};
+    use ruint::uint;
+
+    #[test]
+    fn test_inputs_size() {
+        // specialized graph: the fixed input 1 is a constant, the last input
+        // is not at the beginning of the graph
+        let nodes = vec![
+            Node::Input(0),
+            Node::Constant(uint!(3_U256)),
+            Node::Input(2),
+            Node::Op(Operation::Mul, 1, 2),
+            Node::Input(3),
+            Node::Op(Operation::Add, 3, 4),
+        ];
+        assert_eq!(CompactNodes::try_from(nodes.as_slice()).unwrap().inputs_size(), 4);
+        assert_eq!(CompactNodes::try_from([Node::Input(0)].as_slice()).unwrap().inputs_size(), 1);
+    }
+
+    #[test]
+    fn test_index_overflow() {
+        let big = u32::MAX as usize + 1;
+        let mut compact = CompactNodes::new();
+        compact.push(&Node::Input(0)).unwrap();
+        compact.push(&Node::Constant(uint!(7_U256))).unwrap();
+        let before = compact.clone();
+        for node in [
+            Node::Input(big),
+            Node::Op(Operation::Add, 0, big),
+            Node::Gadget(Gadget::BigMul { limb_bits: 8, limbs_num: 1 }, vec![1, big]),
+            Node::Extract(big, 0),
+        ] {
+            assert_eq!(compact.push(&node), Err(IndexOverflow(big)));
+            assert_eq!(compact, before);
+        }
+        assert_eq!(
+            CompactNodes::try_from([Node::UnoOp(UnoOperation::Neg, big)].as_slice()),
+            Err(IndexOverflow(big)));
+    }
+
+    #[test]
+    fn test_compact_nodes() {
+        let mut nodes = vec![
+            Node::Input(0),
+            Node::Input(1),
+            Node::Constant(uint!(7_U256)),
+            Node::Op(Operation::Mul, 0, 1),
+            Node::Op(Operation::Add, 3, 2),
+            Node::Gadget(Gadget::Bits(254), vec![4]),
+            Node::Extract(5, 2),
+            Node::Gadget(Gadget::BigMul { limb_bits: 8, limbs_num: 2 }, vec![1, 2, 4, 3]),
+            Node::Extract(7, 1),
+            Node::UnoOp(UnoOperation::Neg, 8),
+            Node::TresOp(TresOperation::TernCond, 6, 9, 1),
+        ];
+        let compact = CompactNodes::try_from(nodes.as_slice()).unwrap();
+        assert_eq!(compact.len(), nodes.len());
+        assert_eq!(compact.to_vec(), nodes);
+        assert_eq!(compact.opcode(3), OpCode::Op(Operation::Mul));
+        assert_eq!(compact.inputs_size(), 2);
+        assert!(
+            std::mem::size_of::<OpCode>() + std::mem::size_of::<[u32; 3]>()
+            < std::mem::size_of::<Node>());
+
+        montgomery_form(&mut nodes);
+        let compact = CompactNodes::try_from(nodes.as_slice()).unwrap();
+        assert_eq!(compact.to_vec(), nodes);
+        let outputs = (0..nodes.len()).collect::<Vec<usize>>();
+        let slots = allocate_slots(&nodes);
+        for inputs in [[1, 6], [1, 0], [1, 1000]] {
+            let inputs = inputs.map(U256::from);
+            let want = evaluate(&nodes, &inputs, &outputs);
+            assert_eq!(compact.evaluate(&inputs, &outputs), want);
+            assert_eq!(compact.evaluate_with_slots(&slots, &inputs, &outputs), want);
+        }
+    }
+}
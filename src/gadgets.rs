@@ -0,0 +1,765 @@
+use ark_bn254::Fr;
+use ark_ff::{BigInteger, One, PrimeField, Zero};
+use num_bigint::BigUint;
+use ruint::aliases::U256;
+use serde::{Deserialize, Serialize};
+
+/// Well-known function computed natively instead of the expanded subgraph of
+/// its circom template. The gadget node takes any number of operands, its
+/// results are referenced with `Node::Extract`.
+#[derive(Hash, PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Gadget {
+    /// circomlib `Sha256(nBits)`. Operands are the `nBits` message bits, the
+    /// most significant bit of the first byte first. Results are the 256
+    /// digest bits in the same order. A non-zero operand is treated as 1.
+    Sha256,
+    /// The n least significant bits of the single operand, the least
+    /// significant bit first. The same as circomlib `Num2Bits(n)`.
+    Bits(usize),
+    /// Decomposition of the single operand into `limbs_num` limbs of
+    /// `limb_bits` bits each, the least significant limb first.
+    Limbs { limb_bits: usize, limbs_num: usize },
+    /// `prod(n, k, a, b)` of circom-ecdsa `bigint_func.circom`. Operands are
+    /// `limbs_num` limbs of a and `limbs_num` limbs of b, results are
+    /// `2 * limbs_num` limbs of a * b.
+    BigMul { limb_bits: usize, limbs_num: usize },
+    /// `long_div(n, k, m, a, b)`. Operands are `limbs_num + extra_limbs` limbs
+    /// of a and `limbs_num` limbs of b, results are `extra_limbs + 1` limbs of
+    /// a / b followed by `limbs_num` limbs of a % b. Both are zero if b is 0.
+    BigDivMod { limb_bits: usize, limbs_num: usize, extra_limbs: usize },
+    /// `mod_exp(n, k, a, p, e)`. Operands are `limbs_num` limbs of each of a,
+    /// p and e, results are `limbs_num` limbs of a^e mod p, zero if p is 0.
+    BigModExp { limb_bits: usize, limbs_num: usize },
+    /// `mod_inv(n, k, a, p)`. Operands are `limbs_num` limbs of a and p,
+    /// results are `limbs_num` limbs of a^(p-2) mod p, the inverse of a for
+    /// the prime p.
+    BigModInv { limb_bits: usize, limbs_num: usize },
+}
+
+/// Names of the templates that can be replaced with gadgets.
+pub const GADGET_TEMPLATES: &[&str] = &["Sha256", "Num2Bits"];
+
+/// Names of the circom functions that can be replaced with gadgets.
+pub const GADGET_FUNCTIONS: &[&str] = &["prod", "long_div", "mod_exp", "mod_inv"];
+
+impl Gadget {
+    /// Find the gadget for the template instance by the template name and the
+    /// number of its input, output and intermediate signals and the signals
+    /// of its subcomponents. The counts must match the circomlib template, so
+    /// a different template with the same name is not replaced.
+    pub fn for_template(
+        name: &str, inputs_num: usize, outputs_num: usize,
+        intermediates_num: usize, subcomponent_signals: usize) -> Option<Gadget> {
+
+        let gadget = match name {
+            // paddedIn[nBlocks * 512] and the compression subcomponents
+            "Sha256" if inputs_num > 0
+                && intermediates_num == sha256_padded_bits(inputs_num)
+                && subcomponent_signals > 0 => Gadget::Sha256,
+            // the bits are computed in a var, there are no other signals
+            "Num2Bits" if inputs_num == 1
+                && intermediates_num == 0
+                && subcomponent_signals == 0 => Gadget::Bits(outputs_num),
+            _ => return None,
+        };
+        if gadget.outputs_num(inputs_num) != outputs_num {
+            return None;
+        }
+        Some(gadget)
+    }
+
+    /// Find the gadget for the call of the bigint function by the function
+    /// name and the values of its scalar parameters (n, k and optional m for
+    /// `long_div`, where m is k if omitted).
+    pub fn for_function(name: &str, params: &[usize]) -> Option<Gadget> {
+        let gadget = match (name, params) {
+            ("prod", &[limb_bits, limbs_num]) => {
+                Gadget::BigMul { limb_bits, limbs_num }
+            }
+            ("long_div", &[limb_bits, limbs_num]) => Gadget::BigDivMod {
+                limb_bits, limbs_num, extra_limbs: limbs_num },
+            ("long_div", &[limb_bits, limbs_num, extra_limbs]) => {
+                Gadget::BigDivMod { limb_bits, limbs_num, extra_limbs }
+            }
+            ("mod_exp", &[limb_bits, limbs_num]) => {
+                Gadget::BigModExp { limb_bits, limbs_num }
+            }
+            ("mod_inv", &[limb_bits, limbs_num]) => {
+                Gadget::BigModInv { limb_bits, limbs_num }
+            }
+            _ => return None,
+        };
+        // limbs must fit into the field element
+        if params[0] == 0 || params[0] >= crate::field::M.bit_len() || params[1] == 0 {
+            return None;
+        }
+        Some(gadget)
+    }
+
+    /// Number of limbs of each bigint operand of the gadget, empty for the
+    /// other gadgets.
+    pub fn operand_limbs(&self) -> Vec<usize> {
+        match *self {
+            Gadget::Sha256 | Gadget::Bits(_) | Gadget::Limbs { .. } => vec![],
+            Gadget::BigMul { limbs_num, .. } => vec![limbs_num, limbs_num],
+            Gadget::BigDivMod { limbs_num, extra_limbs, .. } => {
+                vec![limbs_num + extra_limbs, limbs_num]
+            }
+            Gadget::BigModExp { limbs_num, .. } => vec![limbs_num; 3],
+            Gadget::BigModInv { limbs_num, .. } => vec![limbs_num; 2],
+        }
+    }
+
+    /// Number of operands the gadget takes, None if any number is accepted.
+    pub fn inputs_num(&self) -> Option<usize> {
+        match *self {
+            Gadget::Sha256 => None,
+            Gadget::Bits(_) | Gadget::Limbs { .. } => Some(1),
+            _ => Some(self.operand_limbs().iter().sum()),
+        }
+    }
+
+    /// True if the parameters are in the range the gadget is defined for: at
+    /// most 256 bits of the decomposition and limbs that fit into the field
+    /// element. Checked for the gadgets read from the graph file.
+    pub fn is_valid(&self) -> bool {
+        let field_bits = crate::field::M.bit_len();
+        match *self {
+            Gadget::Sha256 => true,
+            Gadget::Bits(n) => n > 0 && n <= 256,
+            Gadget::Limbs { limb_bits, limbs_num } => {
+                limb_bits > 0 && limb_bits < field_bits
+                    && limbs_num > 0 && limbs_num <= 256
+            }
+            Gadget::BigMul { limb_bits, limbs_num }
+            | Gadget::BigDivMod { limb_bits, limbs_num, .. }
+            | Gadget::BigModExp { limb_bits, limbs_num }
+            | Gadget::BigModInv { limb_bits, limbs_num } => {
+                limb_bits > 0 && limb_bits < field_bits && limbs_num > 0
+            }
+        }
+    }
+
+    /// Position of the result i in the array returned by the circom function
+    /// of size ret_size. `long_div` returns a two dimensional array with the
+    /// quotient and the remainder in the rows.
+    pub fn function_result_position(&self, i: usize, ret_size: usize) -> usize {
+        match *self {
+            Gadget::BigDivMod { extra_limbs, .. } if i > extra_limbs => {
+                ret_size / 2 + i - extra_limbs - 1
+            }
+            _ => i,
+        }
+    }
+
+    pub fn outputs_num(&self, _inputs_num: usize) -> usize {
+        match *self {
+            Gadget::Sha256 => 256,
+            Gadget::Bits(n) => n,
+            Gadget::Limbs { limbs_num, .. } => limbs_num,
+            Gadget::BigMul { limbs_num, .. } => 2 * limbs_num,
+            Gadget::BigDivMod { limbs_num, extra_limbs, .. } => {
+                extra_limbs + 1 + limbs_num
+            }
+            Gadget::BigModExp { limbs_num, .. } => limbs_num,
+            Gadget::BigModInv { limbs_num, .. } => limbs_num,
+        }
+    }
+
+    /// True if every result of the gadget is 0 or 1.
+    pub fn is_bool(&self) -> bool {
+        match *self {
+            Gadget::Sha256 | Gadget::Bits(_) => true,
+            Gadget::Limbs { limb_bits, .. } => limb_bits == 1,
+            Gadget::BigMul { .. } | Gadget::BigDivMod { .. }
+                | Gadget::BigModExp { .. } | Gadget::BigModInv { .. } => false,
+        }
+    }
+
+    pub fn eval(&self, inputs: &[U256]) -> Vec<U256> {
+        match self {
+            Gadget::Sha256 => {
+                let bits = inputs.iter()
+                    .map(|v| *v != U256::ZERO)
+                    .collect::<Vec<bool>>();
+                sha256_bits(&bits).iter().map(|&b| U256::from(b)).collect()
+            }
+            Gadget::Bits(n) => limbs(inputs[0], 1, *n),
+            Gadget::Limbs { limb_bits, limbs_num } => {
+                limbs(inputs[0], *limb_bits, *limbs_num)
+            }
+            Gadget::BigMul { limb_bits, limbs_num } => {
+                let (a, b) = inputs.split_at(*limbs_num);
+                let r = from_limbs(a, *limb_bits) * from_limbs(b, *limb_bits);
+                to_limbs(&r, *limb_bits, 2 * limbs_num)
+            }
+            Gadget::BigDivMod { limb_bits, limbs_num, extra_limbs } => {
+                let (a, b) = inputs.split_at(limbs_num + extra_limbs);
+                let a = from_limbs(a, *limb_bits);
+                let b = from_limbs(b, *limb_bits);
+                let (q, r) = if b == BigUint::ZERO {
+                    (BigUint::ZERO, BigUint::ZERO)
+                } else {
+                    (&a / &b, &a % &b)
+                };
+                let mut res = to_limbs(&q, *limb_bits, extra_limbs + 1);
+                res.extend(to_limbs(&r, *limb_bits, *limbs_num));
+                res
+            }
+            Gadget::BigModExp { limb_bits, limbs_num } => {
+                let a = from_limbs(&inputs[..*limbs_num], *limb_bits);
+                let p = from_limbs(&inputs[*limbs_num..2 * limbs_num], *limb_bits);
+                let e = from_limbs(&inputs[2 * limbs_num..], *limb_bits);
+                to_limbs(&mod_pow(&a, &e, &p), *limb_bits, *limbs_num)
+            }
+            Gadget::BigModInv { limb_bits, limbs_num } => {
+                let a = from_limbs(&inputs[..*limbs_num], *limb_bits);
+                let p = from_limbs(&inputs[*limbs_num..], *limb_bits);
+                let e = if p < BigUint::from(2u32) {
+                    BigUint::ZERO
+                } else {
+                    &p - 2u32
+                };
+                to_limbs(&mod_pow(&a, &e, &p), *limb_bits, *limbs_num)
+            }
+        }
+    }
+
+    pub fn eval_fr(&self, inputs: &[Fr]) -> Vec<Fr> {
+        match self {
+            Gadget::Sha256 => {
+                let bits = inputs.iter()
+                    .map(|v| !v.is_zero())
+                    .collect::<Vec<bool>>();
+                sha256_bits(&bits).iter()
+                    .map(|&b| if b { Fr::one() } else { Fr::zero() })
+                    .collect()
+            }
+            _ => {
+                let inputs = inputs.iter()
+                    .map(|x| U256::from_le_slice(&x.into_bigint().to_bytes_le()))
+                    .collect::<Vec<U256>>();
+                self.eval(&inputs).iter()
+                    .map(|v| Fr::from_le_bytes_mod_order(&v.to_le_bytes::<32>()))
+                    .collect()
+            }
+        }
+    }
+}
+
+// Value of the limbs of limb_bits bits, the least significant first. Limbs
+// are not required to be less than 2^limb_bits.
+fn from_limbs(limbs: &[U256], limb_bits: usize) -> BigUint {
+    limbs.iter().enumerate().fold(BigUint::ZERO, |acc, (i, x)| {
+        acc + (BigUint::from_bytes_le(&x.to_le_bytes::<32>()) << (i * limb_bits))
+    })
+}
+
+fn to_limbs(x: &BigUint, limb_bits: usize, limbs_num: usize) -> Vec<U256> {
+    let mask = (BigUint::from(1u32) << limb_bits) - 1u32;
+    (0..limbs_num)
+        .map(|i| {
+            let limb = (x >> (i * limb_bits)) & &mask;
+            U256::from_le_slice(&limb.to_bytes_le())
+        })
+        .collect()
+}
+
+// a^e mod p, zero if p is 0
+fn mod_pow(a: &BigUint, e: &BigUint, p: &BigUint) -> BigUint {
+    if *p == BigUint::ZERO {
+        return BigUint::ZERO;
+    }
+    a.modpow(e, p)
+}
+
+// Split the value into limbs of limb_bits bits, the least significant first.
+fn limbs(x: U256, limb_bits: usize, limbs_num: usize) -> Vec<U256> {
+    let mask = if limb_bits >= 256 {
+        U256::MAX
+    } else {
+        (U256::from(1) << limb_bits) - U256::from(1)
+    };
+    (0..limbs_num)
+        .map(|k| {
+            let shift = k * limb_bits;
+            if shift >= 256 { U256::ZERO } else { (x >> shift) & mask }
+        })
+        .collect()
+}
+
+// The gadget node without the operands.
+impl From<&Gadget> for crate::proto::GadgetNode {
+    fn from(v: &Gadget) -> Self {
+        use crate::proto::GadgetOp;
+        let mut node = crate::proto::GadgetNode::default();
+        let op = match *v {
+            Gadget::Sha256 => GadgetOp::Sha256,
+            Gadget::Bits(n) => {
+                node.outputs_num = n as u64;
+                GadgetOp::Bits
+            }
+            Gadget::Limbs { limb_bits, limbs_num } => {
+                node.outputs_num = limbs_num as u64;
+                node.limb_bits = limb_bits as u64;
+                GadgetOp::Limbs
+            }
+            Gadget::BigMul { limb_bits, limbs_num } => {
+                node.limb_bits = limb_bits as u64;
+                node.limbs_num = limbs_num as u64;
+                GadgetOp::BigMul
+            }
+            Gadget::BigDivMod { limb_bits, limbs_num, extra_limbs } => {
+                node.limb_bits = limb_bits as u64;
+                node.limbs_num = limbs_num as u64;
+                node.extra_limbs = extra_limbs as u64;
+                GadgetOp::BigDivMod
+            }
+            Gadget::BigModExp { limb_bits, limbs_num } => {
+                node.limb_bits = limb_bits as u64;
+                node.limbs_num = limbs_num as u64;
+                GadgetOp::BigModExp
+            }
+            Gadget::BigModInv { limb_bits, limbs_num } => {
+                node.limb_bits = limb_bits as u64;
+                node.limbs_num = limbs_num as u64;
+                GadgetOp::BigModInv
+            }
+        };
+        node.op = op as i32;
+        node
+    }
+}
+
+impl TryFrom<&crate::proto::GadgetNode> for Gadget {
+    type Error = std::io::Error;
+
+    fn try_from(value: &crate::proto::GadgetNode) -> std::io::Result<Self> {
+        use crate::proto::GadgetOp;
+        let invalid = |msg: String| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+        };
+        let to_usize = |v: u64| {
+            usize::try_from(v).map_err(
+                |_| invalid(format!("gadget parameter {} does not fit into usize", v)))
+        };
+        let limb_bits = to_usize(value.limb_bits)?;
+        let limbs_num = to_usize(value.limbs_num)?;
+        let outputs_num = to_usize(value.outputs_num)?;
+        let op = GadgetOp::try_from(value.op)
+            .map_err(|e| invalid(e.to_string()))?;
+        let gadget = match op {
+            GadgetOp::Sha256 => Gadget::Sha256,
+            GadgetOp::Bits => Gadget::Bits(outputs_num),
+            GadgetOp::Limbs => Gadget::Limbs {
+                limb_bits,
+                limbs_num: outputs_num,
+            },
+            GadgetOp::BigMul => Gadget::BigMul { limb_bits, limbs_num },
+            GadgetOp::BigDivMod => Gadget::BigDivMod {
+                limb_bits,
+                limbs_num,
+                extra_limbs: to_usize(value.extra_limbs)?,
+            },
+            GadgetOp::BigModExp => Gadget::BigModExp { limb_bits, limbs_num },
+            GadgetOp::BigModInv => Gadget::BigModInv { limb_bits, limbs_num },
+        };
+        if !gadget.is_valid() {
+            return Err(invalid(format!("invalid gadget parameters: {:?}", gadget)));
+        }
+        Ok(gadget)
+    }
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1,
+    0x923f82a4, 0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
+    0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786,
+    0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147,
+    0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+    0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+    0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a,
+    0x5b9cca4f, 0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+    0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2];
+
+const SHA256_H: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c,
+    0x1f83d9ab, 0x5be0cd19];
+
+// Length of the padded message of n_bits bits, the same as the size of the
+// circomlib `paddedIn` signal.
+fn sha256_padded_bits(n_bits: usize) -> usize {
+    ((n_bits + 64) / 512 + 1) * 512
+}
+
+// SHA-256 of the message of arbitrary bit length, bits are most significant
+// first, as in circomlib.
+fn sha256_bits(msg: &[bool]) -> Vec<bool> {
+    // Padding: bit 1, zeros up to 448 mod 512 and 64-bit message length.
+    let padded_bits = sha256_padded_bits(msg.len());
+    let blocks_num = padded_bits / 512;
+    let mut padded = vec![false; padded_bits];
+    padded[..msg.len()].copy_from_slice(msg);
+    padded[msg.len()] = true;
+    let ln = msg.len() as u64;
+    for i in 0..64 {
+        padded[blocks_num * 512 - 1 - i] = (ln >> i) & 1 == 1;
+    }
+
+    let mut h = SHA256_H;
+    for block in padded.chunks(512) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(32).enumerate() {
+            w[i] = word.iter().fold(0u32, |acc, &b| (acc << 1) | b as u32);
+        }
+        sha256_compress(&mut h, &mut w);
+    }
+
+    h.iter()
+        .flat_map(|&x| (0..32).rev().map(move |i| (x >> i) & 1 == 1))
+        .collect()
+}
+
+// Process one block, the first 16 words of w are the block.
+fn sha256_compress(h: &mut [u32; 8], w: &mut [u32; 64]) {
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18)
+            ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19)
+            ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7])
+            .wrapping_add(s1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = *h;
+    for i in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = hh.wrapping_add(s1).wrapping_add(ch)
+            .wrapping_add(SHA256_K[i]).wrapping_add(w[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+        hh = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+
+    for (x, y) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+        *x = x.wrapping_add(y);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use crate::graph::{evaluate, Node, Operation};
+
+    fn bytes_to_bits(bytes: &[u8]) -> Vec<bool> {
+        bytes.iter()
+            .flat_map(|&x| (0..8).rev().map(move |i| (x >> i) & 1 == 1))
+            .collect()
+    }
+
+    fn bits_to_hex(bits: &[bool]) -> String {
+        bits.chunks(8)
+            .map(|b| b.iter().fold(0u8, |acc, &x| (acc << 1) | x as u8))
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    #[test]
+    fn test_decomposition() {
+        assert_eq!(Gadget::for_template("Num2Bits", 1, 8, 0, 0), Some(Gadget::Bits(8)));
+        assert_eq!(Gadget::for_template("Num2Bits", 2, 8, 0, 0), None);
+        // a template with the same name but other signals
+        assert_eq!(Gadget::for_template("Num2Bits", 1, 8, 1, 0), None);
+        assert_eq!(Gadget::for_template("Num2Bits", 1, 8, 0, 3), None);
+        // Sha256(448) pads the message to two blocks
+        assert_eq!(Gadget::for_template("Sha256", 448, 256, 1024, 1), Some(Gadget::Sha256));
+        assert_eq!(Gadget::for_template("Sha256", 448, 256, 512, 1), None);
+        assert_eq!(Gadget::for_template("Sha256", 448, 256, 1024, 0), None);
+        assert_eq!(Gadget::for_template("Sha256", 448, 128, 1024, 1), None);
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let x = rng.gen::<U256>() % crate::field::M;
+        for (gadget, limb_bits) in [
+            (Gadget::Bits(254), 1),
+            (Gadget::Limbs { limb_bits: 64, limbs_num: 4 }, 64),
+            (Gadget::Limbs { limb_bits: 33, limbs_num: 8 }, 33)] {
+
+            let limbs = gadget.eval(&[x]);
+            assert_eq!(limbs.len(), gadget.outputs_num(1));
+            let y = limbs.iter().enumerate()
+                .fold(U256::ZERO, |acc, (k, &l)| acc | (l << (k * limb_bits)));
+            assert_eq!(y, x, "{:?}", gadget);
+
+            let limbs_fr = gadget.eval_fr(&[Fr::new(x.into())]);
+            assert_eq!(
+                limbs_fr.iter().map(|&v| v.into()).collect::<Vec<U256>>(),
+                limbs);
+        }
+
+        assert_eq!(
+            Gadget::Bits(3).eval(&[U256::from(6)]),
+            vec![U256::ZERO, U256::from(1), U256::from(1)]);
+    }
+
+    #[test]
+    fn test_bigint() {
+        let u = |v: &[u64]| v.iter().map(|&x| U256::from(x)).collect::<Vec<U256>>();
+
+        assert_eq!(
+            Gadget::for_function("long_div", &[64, 4]),
+            Some(Gadget::BigDivMod { limb_bits: 64, limbs_num: 4, extra_limbs: 4 }));
+        assert_eq!(Gadget::for_function("prod", &[64, 4, 1]), None);
+        assert_eq!(Gadget::for_function("mod_inv", &[254, 4]), None);
+
+        // 8-bit limbs: 0x0302 * 0x01ff = 0x0600fe
+        let mul = Gadget::BigMul { limb_bits: 8, limbs_num: 2 };
+        assert_eq!(mul.eval(&u(&[2, 3, 0xff, 1])), u(&[0xfe, 0, 6, 0]));
+        // unnormalized limbs: 0x1ff + 0x100 * 0 = 0x1ff
+        assert_eq!(mul.eval(&u(&[0x1ff, 0, 1, 0])), u(&[0xff, 1, 0, 0]));
+
+        // 0x050403 = 0x0102 * 0x04fa + 0x0f
+        let div = Gadget::BigDivMod { limb_bits: 8, limbs_num: 2, extra_limbs: 1 };
+        assert_eq!(div.eval(&u(&[3, 4, 5, 2, 1])), u(&[0xfa, 4, 0x0f, 0]));
+        assert_eq!(div.function_result_position(1, 200), 1);
+        assert_eq!(div.function_result_position(2, 200), 100);
+        assert_eq!(div.eval(&u(&[3, 4, 5, 0, 0])), u(&[0, 0, 0, 0]));
+
+        // 3^5 mod 0x0107 = 243, 3^(263-2) mod 263 = 88
+        let exp = Gadget::BigModExp { limb_bits: 8, limbs_num: 2 };
+        assert_eq!(exp.eval(&u(&[3, 0, 7, 1, 5, 0])), u(&[243, 0]));
+        let inv = Gadget::BigModInv { limb_bits: 8, limbs_num: 2 };
+        assert_eq!(inv.eval(&u(&[3, 0, 7, 1])), u(&[88, 0]));
+        assert_eq!(inv.eval(&u(&[3, 0, 0, 0])), u(&[0, 0]));
+
+        let args = u(&[3, 0, 7, 1]);
+        assert_eq!(
+            inv.eval_fr(&args.iter().map(|&x| Fr::new(x.into())).collect::<Vec<Fr>>())
+                .iter().map(|&x| x.into()).collect::<Vec<U256>>(),
+            inv.eval(&args));
+    }
+
+    #[test]
+    fn test_sha256_vectors() {
+        assert_eq!(
+            bits_to_hex(&sha256_bits(&[])),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(
+            bits_to_hex(&sha256_bits(&bytes_to_bits(b"abc"))),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        // two blocks
+        assert_eq!(
+            bits_to_hex(&sha256_bits(&bytes_to_bits(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"))),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+    }
+
+    // Expanded SHA-256 subgraph built from the elementary operations on
+    // 32-bit words, the same way as the circuit computes it.
+    struct Expanded {
+        nodes: Vec<Node>,
+    }
+
+    impl Expanded {
+        fn push(&mut self, node: Node) -> usize {
+            self.nodes.push(node);
+            self.nodes.len() - 1
+        }
+
+        fn c(&mut self, v: u64) -> usize {
+            self.push(Node::Constant(U256::from(v)))
+        }
+
+        fn op(&mut self, op: Operation, a: usize, b: usize) -> usize {
+            self.push(Node::Op(op, a, b))
+        }
+
+        fn add(&mut self, a: usize, b: usize) -> usize {
+            let s = self.op(Operation::Add, a, b);
+            let mask = self.c(0xffffffff);
+            self.op(Operation::Band, s, mask)
+        }
+
+        fn rotr(&mut self, x: usize, n: u64) -> usize {
+            let nn = self.c(n);
+            let r = self.op(Operation::Shr, x, nn);
+            let ln = self.c(32 - n);
+            let l = self.op(Operation::Shl, x, ln);
+            let lr = self.op(Operation::Bor, l, r);
+            let mask = self.c(0xffffffff);
+            self.op(Operation::Band, lr, mask)
+        }
+
+        fn shr(&mut self, x: usize, n: u64) -> usize {
+            let nn = self.c(n);
+            self.op(Operation::Shr, x, nn)
+        }
+
+        fn xor3(&mut self, a: usize, b: usize, c: usize) -> usize {
+            let ab = self.op(Operation::Bxor, a, b);
+            self.op(Operation::Bxor, ab, c)
+        }
+
+        // Returns the output bit nodes for the message bit nodes.
+        fn sha256(&mut self, msg: &[usize]) -> Vec<usize> {
+            let blocks_num = (msg.len() + 64) / 512 + 1;
+            let zero = self.c(0);
+            let one = self.c(1);
+            let mut padded = vec![zero; blocks_num * 512];
+            padded[..msg.len()].copy_from_slice(msg);
+            padded[msg.len()] = one;
+            for i in 0..64 {
+                if (msg.len() >> i) & 1 == 1 {
+                    padded[blocks_num * 512 - 1 - i] = one;
+                }
+            }
+
+            let mut h = SHA256_H.iter()
+                .map(|&x| self.c(x as u64))
+                .collect::<Vec<usize>>();
+            for block in padded.chunks(512) {
+                let mut w = Vec::with_capacity(64);
+                for word in block.chunks(32) {
+                    let two = self.c(2);
+                    let mut acc = zero;
+                    for &b in word {
+                        acc = self.op(Operation::Mul, acc, two);
+                        acc = self.op(Operation::Add, acc, b);
+                    }
+                    w.push(acc);
+                }
+                for i in 16..64 {
+                    let r7 = self.rotr(w[i - 15], 7);
+                    let r18 = self.rotr(w[i - 15], 18);
+                    let s3 = self.shr(w[i - 15], 3);
+                    let s0 = self.xor3(r7, r18, s3);
+                    let r17 = self.rotr(w[i - 2], 17);
+                    let r19 = self.rotr(w[i - 2], 19);
+                    let s10 = self.shr(w[i - 2], 10);
+                    let s1 = self.xor3(r17, r19, s10);
+                    let x = self.add(w[i - 16], s0);
+                    let x = self.add(x, w[i - 7]);
+                    let x = self.add(x, s1);
+                    w.push(x);
+                }
+
+                let mut v = h.clone();
+                let not_mask = self.c(0xffffffff);
+                for i in 0..64 {
+                    let (a, b, c, d) = (v[0], v[1], v[2], v[3]);
+                    let (e, f, g, hh) = (v[4], v[5], v[6], v[7]);
+                    let r6 = self.rotr(e, 6);
+                    let r11 = self.rotr(e, 11);
+                    let r25 = self.rotr(e, 25);
+                    let s1 = self.xor3(r6, r11, r25);
+                    let ef = self.op(Operation::Band, e, f);
+                    let not_e = self.op(Operation::Bxor, e, not_mask);
+                    let neg = self.op(Operation::Band, not_e, g);
+                    let ch = self.op(Operation::Bxor, ef, neg);
+                    let k = self.c(SHA256_K[i] as u64);
+                    let t1 = self.add(hh, s1);
+                    let t1 = self.add(t1, ch);
+                    let t1 = self.add(t1, k);
+                    let t1 = self.add(t1, w[i]);
+                    let r2 = self.rotr(a, 2);
+                    let r13 = self.rotr(a, 13);
+                    let r22 = self.rotr(a, 22);
+                    let s0 = self.xor3(r2, r13, r22);
+                    let ab = self.op(Operation::Band, a, b);
+                    let ac = self.op(Operation::Band, a, c);
+                    let bc = self.op(Operation::Band, b, c);
+                    let maj = self.xor3(ab, ac, bc);
+                    let t2 = self.add(s0, maj);
+                    let e = self.add(d, t1);
+                    let a = self.add(t1, t2);
+                    v = vec![a, v[0], v[1], v[2], e, v[4], v[5], v[6]];
+                }
+                h = (0..8).map(|i| self.add(h[i], v[i])).collect();
+            }
+
+            let mut out = Vec::with_capacity(256);
+            for &x in h.iter() {
+                for i in (0..32).rev() {
+                    let s = self.shr(x, i);
+                    out.push(self.op(Operation::Band, s, one));
+                }
+            }
+            out
+        }
+    }
+
+    #[test]
+    fn test_sha256_expanded() {
+        let mut rng = StdRng::seed_from_u64(42);
+        // a single block, the length that leaves no space for the padding in
+        // the first block and two blocks
+        for n_bits in [32, 447, 520] {
+            let mut expanded = Expanded {
+                nodes: (0..=n_bits).map(Node::Input).collect(),
+            };
+            let msg = (1..=n_bits).collect::<Vec<usize>>();
+            let want_outputs = expanded.sha256(&msg);
+
+            let mut nodes = (0..=n_bits).map(Node::Input).collect::<Vec<Node>>();
+            nodes.push(Node::Gadget(Gadget::Sha256, msg.clone()));
+            let g = nodes.len() - 1;
+            let outputs = (0..256)
+                .map(|k| {
+                    nodes.push(Node::Extract(g, k));
+                    nodes.len() - 1
+                })
+                .collect::<Vec<usize>>();
+
+            let mut inputs = vec![U256::from(1)];
+            inputs.extend((0..n_bits).map(|_| U256::from(rng.gen::<bool>())));
+            assert_eq!(
+                evaluate(&nodes, &inputs, &outputs),
+                evaluate(&expanded.nodes, &inputs, &want_outputs),
+                "n_bits = {}", n_bits);
+        }
+    }
+
+    #[test]
+    fn test_num2bits_expanded() {
+        // circomlib Num2Bits(n) computes out[i] <-- (in >> i) & 1
+        let n = 254;
+        let mut expanded = vec![Node::Input(0), Node::Input(1), Node::Constant(U256::from(1))];
+        let want_outputs = (0..n)
+            .map(|i| {
+                expanded.push(Node::Constant(U256::from(i)));
+                expanded.push(Node::Op(Operation::Shr, 1, expanded.len() - 1));
+                expanded.push(Node::Op(Operation::Band, expanded.len() - 1, 2));
+                expanded.len() - 1
+            })
+            .collect::<Vec<usize>>();
+
+        let gadget = Gadget::for_template("Num2Bits", 1, n, 0, 0).unwrap();
+        let mut nodes = vec![Node::Input(0), Node::Input(1), Node::Gadget(gadget, vec![1])];
+        let outputs = (0..n)
+            .map(|k| {
+                nodes.push(Node::Extract(2, k));
+                nodes.len() - 1
+            })
+            .collect::<Vec<usize>>();
+
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..10 {
+            let inputs = vec![U256::from(1), rng.gen::<U256>() % crate::field::M];
+            assert_eq!(
+                evaluate(&nodes, &inputs, &outputs),
+                evaluate(&expanded, &inputs, &want_outputs));
+        }
+    }
+}
@@ -2,13 +2,18 @@ use std::{
     collections::HashMap,
     ops::{BitAnd, Shl, Shr},
 };
+use std::cell::RefCell;
 use std::cmp::Ordering;
+use std::time::{Duration, Instant};
 use std::error::Error;
-use std::ops::{BitOr, BitXor, Deref};
+use std::ops::{BitOr, BitXor};
+use crate::compact::CompactNodes;
 use crate::field::M;
+use crate::gadgets::Gadget;
 use ark_bn254::Fr;
-use ark_ff::{BigInt, PrimeField, BigInteger, Zero, One};
-use rand::Rng;
+use ark_ff::{Field, PrimeField, BigInteger, Zero, One};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use ruint::aliases::U256;
 use serde::{Deserialize, Serialize};
 
@@ -59,6 +64,12 @@ pub enum Operation {
 }
 
 impl Operation {
+    /// True if the result does not depend on the order of operands.
+    pub fn is_commutative(&self) -> bool {
+        use Operation::*;
+        matches!(self, Add | Mul | Eq | Neq | Land | Lor | Band | Bor | Bxor)
+    }
+
     // TODO: rewrite to &U256 type
     pub fn eval(&self, a: U256, b: U256) -> U256 {
         use Operation::*;
@@ -77,7 +88,7 @@ impl Operation {
             Add => a.add_mod(b, M),
             Sub => a.add_mod(M - b, M),
             Pow => a.pow_mod(b, M),
-            Mod => a.div_rem(b).1,
+            Mod => if b == U256::ZERO { U256::ZERO } else { a.div_rem(b).1 },
             Eq => U256::from(a == b),
             Neq => U256::from(a != b),
             Lt => u_lt(&a, &b),
@@ -86,16 +97,12 @@ impl Operation {
             Geq => u_gte(&a, &b),
             Land => U256::from(a != U256::ZERO && b != U256::ZERO),
             Lor => U256::from(a != U256::ZERO || b != U256::ZERO),
-            Shl => compute_shl_uint(a, b),
-            Shr => compute_shr_uint(a, b),
-            // TODO test with conner case when it is possible to get the number
-            //      bigger then modulus
-            Bor => a.bitor(b),
-            Band => a.bitand(b),
-            // TODO test with conner case when it is possible to get the number
-            //      bigger then modulus
-            Bxor => a.bitxor(b),
-            Idiv => a / b,
+            Shl => shl_uint(a, b),
+            Shr => shr_uint(a, b),
+            Bor => reduce_int(a.bitor(b)),
+            Band => reduce_int(a.bitand(b)),
+            Bxor => reduce_int(a.bitxor(b)),
+            Idiv => if b == U256::ZERO { U256::ZERO } else { a / b },
         }
     }
 
@@ -109,6 +116,7 @@ impl Operation {
             Div => if b.is_zero() { Fr::zero() } else { a / b },
             Add => a + b,
             Sub => a - b,
+            Pow => a.pow(b.into_bigint()),
             Idiv => if b.is_zero() {
                 Fr::zero()
             } else {
@@ -138,10 +146,138 @@ impl Operation {
             Bor => bit_or(a, b),
             Band => bit_and(a, b),
             Bxor => bit_xor(a, b),
-            // TODO implement other operators
-            _ => unimplemented!("operator {:?} not implemented for Montgomery", self),
         }
     }
+
+    /// The same as `eval_fr`, but comparisons, shifts, bitwise operations,
+    /// integer division and modulo are calculated on canonical integers and
+    /// return them, so that the chains of integer operations do not convert
+    /// their values to Montgomery form and back.
+    pub(crate) fn eval_value(&self, a: Value, b: Value) -> Value {
+        use Operation::*;
+        match (self, a, b) {
+            (Lt | Gt | Leq | Geq | Idiv | Mod | Shl | Shr | Bor | Band | Bxor, ..) => {
+                Value::Int(self.eval(a.int(), b.int()))
+            }
+            (Eq | Neq | Land | Lor, Value::Int(a), Value::Int(b)) => {
+                Value::Int(self.eval(a, b))
+            }
+            _ => Value::Mont(self.eval_fr(a.fr(), b.fr())),
+        }
+    }
+}
+
+/// Results of the gadget nodes by the gadget node index. Each entry is kept
+/// until the last `Extract` node of the gadget is evaluated, so the evaluators
+/// do not hold all gadget results to the end of the graph.
+pub(crate) struct GadgetValues<T> {
+    values: HashMap<usize, Vec<T>>,
+    // gadget node index -> index of its last Extract node
+    last_extract: HashMap<usize, usize>,
+}
+
+impl<T> GadgetValues<T> {
+    /// The extracts are `(extract node, gadget node)` pairs of the nodes that
+    /// are going to be evaluated.
+    pub(crate) fn new(extracts: impl IntoIterator<Item = (usize, usize)>) -> Self {
+        let mut last_extract = HashMap::new();
+        for (i, g) in extracts {
+            let last = last_extract.entry(g).or_insert(i);
+            *last = (*last).max(i);
+        }
+        GadgetValues { values: HashMap::new(), last_extract }
+    }
+
+    pub(crate) fn from_nodes(nodes: &[Node]) -> Self {
+        GadgetValues::new(nodes.iter().enumerate().filter_map(|(i, n)| match *n {
+            Node::Extract(g, _) => Some((i, g)),
+            _ => None,
+        }))
+    }
+
+    /// Store the results of the gadget node g. Results that are never
+    /// extracted are dropped.
+    pub(crate) fn insert(&mut self, g: usize, values: Vec<T>) {
+        if self.last_extract.contains_key(&g) {
+            self.values.insert(g, values);
+        }
+    }
+
+    pub(crate) fn get(&self, g: usize) -> &[T] {
+        &self.values[&g]
+    }
+
+    /// Release the results of the gadget g if the extract node i is the last
+    /// one using them.
+    pub(crate) fn extracted(&mut self, i: usize, g: usize) {
+        if self.last_extract.get(&g) == Some(&i) {
+            self.values.remove(&g);
+        }
+    }
+}
+
+/// Value of the node in the form it was calculated in: Montgomery form for
+/// the field operations or the canonical integer less than the modulus for
+/// the integer operations. It is converted to the other form only when used
+/// by the operation of the other kind.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Value {
+    Mont(Fr),
+    Int(U256),
+}
+
+impl Value {
+    pub(crate) fn fr(self) -> Fr {
+        match self {
+            Value::Mont(v) => v,
+            Value::Int(v) => Fr::new(v.into()),
+        }
+    }
+
+    pub(crate) fn int(self) -> U256 {
+        match self {
+            Value::Mont(v) => v.into(),
+            Value::Int(v) => v,
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        match self {
+            Value::Mont(v) => v.is_zero(),
+            Value::Int(v) => *v == U256::ZERO,
+        }
+    }
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Value::Int(U256::ZERO)
+    }
+}
+
+// Shifts and bitwise operations on the canonical integers, shared by all
+// the evaluators and the code generators. As in circom, the result of shl is
+// truncated to the bit size of the modulus and then reduced, shifts by the
+// modulus bit size or more give 0.
+pub(crate) fn shl_uint(a: U256, b: U256) -> U256 {
+    if b >= U256::from(Fr::MODULUS_BIT_SIZE) {
+        return U256::ZERO;
+    }
+    let mask = (U256::from(1u64) << Fr::MODULUS_BIT_SIZE as usize) - U256::from(1u64);
+    reduce_int(a.shl(b.as_limbs()[0] as usize).bitand(mask))
+}
+
+pub(crate) fn shr_uint(a: U256, b: U256) -> U256 {
+    if b >= U256::from(Fr::MODULUS_BIT_SIZE) {
+        return U256::ZERO;
+    }
+    a.shr(b.as_limbs()[0] as usize)
+}
+
+// Results of the bitwise operations on the integers less than the modulus
+// are less than 2^254, so one subtraction is enough.
+fn reduce_int(a: U256) -> U256 {
+    if a >= M { a - M } else { a }
 }
 
 impl From<&Operation> for crate::proto::DuoOp {
@@ -192,7 +328,14 @@ impl UnoOperation {
                 x.sub_with_borrow(&a.into_bigint());
                 Fr::from_bigint(x).unwrap()
             },
-            _ => unimplemented!("uno operator {:?} not implemented for Montgomery", self),
+            UnoOperation::Id => a,
+        }
+    }
+
+    pub(crate) fn eval_value(&self, a: Value) -> Value {
+        match self {
+            UnoOperation::Neg => Value::Mont(self.eval_fr(a.fr())),
+            UnoOperation::Id => a,
         }
     }
 }
@@ -223,6 +366,12 @@ impl TresOperation {
             TresOperation::TernCond => if a.is_zero() { c } else { b },
         }
     }
+
+    pub(crate) fn eval_value(&self, a: Value, b: Value, c: Value) -> Value {
+        match self {
+            TresOperation::TernCond => if a.is_zero() { c } else { b },
+        }
+    }
 }
 
 impl From<&TresOperation> for crate::proto::TresOp {
@@ -233,7 +382,7 @@ impl From<&TresOperation> for crate::proto::TresOp {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Node {
     Input(usize),
     Constant(U256),
@@ -242,57 +391,230 @@ pub enum Node {
     UnoOp(UnoOperation, usize),
     Op(Operation, usize, usize),
     TresOp(TresOperation, usize, usize, usize),
+    /// Native gadget over the operands. The value of the node itself is not
+    /// used, its results are referenced with `Extract`.
+    Gadget(Gadget, Vec<usize>),
+    /// The k-th result of the gadget node.
+    Extract(usize, usize),
+}
+
+impl Node {
+    /// Call f for each operand of the node.
+    pub fn for_each_operand(&self, mut f: impl FnMut(usize)) {
+        match self {
+            Node::Input(_) | Node::Constant(_) | Node::MontConstant(_) => {}
+            Node::UnoOp(_, a) | Node::Extract(a, _) => f(*a),
+            Node::Op(_, a, b) => {
+                f(*a);
+                f(*b);
+            }
+            Node::TresOp(_, a, b, c) => {
+                f(*a);
+                f(*b);
+                f(*c);
+            }
+            Node::Gadget(_, args) => args.iter().for_each(|&a| f(a)),
+        }
+    }
+
+    /// Replace each operand of the node with f(operand).
+    pub fn map_operands(&mut self, mut f: impl FnMut(usize) -> usize) {
+        match self {
+            Node::Input(_) | Node::Constant(_) | Node::MontConstant(_) => {}
+            Node::UnoOp(_, a) | Node::Extract(a, _) => *a = f(*a),
+            Node::Op(_, a, b) => {
+                *a = f(*a);
+                *b = f(*b);
+            }
+            Node::TresOp(_, a, b, c) => {
+                *a = f(*a);
+                *b = f(*b);
+                *c = f(*c);
+            }
+            Node::Gadget(_, args) => args.iter_mut().for_each(|a| *a = f(*a)),
+        }
+    }
 }
 
-// TODO remove pub from Vec<Node>
-pub struct Nodes(pub Vec<Node>);
+/// Nodes of the graph being built, stored in the compact layout.
+/// Optimization passes work on `Vec<Node>`, use `into_nodes` when the graph
+/// is built. `Nodes` used to deref to the public `Vec<Node>`; the nodes are
+/// now decoded on access, use `get`, `iter` or `to_vec` instead.
+pub struct Nodes {
+    nodes: CompactNodes,
+    // Value of each node that does not depend on input signals, so that
+    // `to_const` is O(1). Updated for the nodes appended to the end.
+    consts: RefCell<Vec<Option<U256>>>,
+    // Pushed gadget nodes by value, so that equal gadgets are not duplicated.
+    gadget_idx: HashMap<Node, usize>,
+    // Push the bit and limb extractions as the decomposition, see `push`.
+    decompose: bool,
+}
 
 impl Nodes {
     pub fn new() -> Self {
-        Nodes(Vec::new())
+        Nodes {
+            nodes: CompactNodes::new(),
+            consts: RefCell::new(Vec::new()),
+            gadget_idx: HashMap::new(),
+            decompose: false,
+        }
+    }
+
+    /// Push the extractions of bits and limbs as the results of the
+    /// decomposition gadget, see `push`. Disabled by default, as it adds
+    /// gadget nodes to the graph.
+    pub fn set_decompose(&mut self, decompose: bool) {
+        self.decompose = decompose;
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
     }
 
     pub fn to_const(&self, idx: NodeIdx) -> Result<U256, NodeConstErr> {
-        let me = self.0.get(idx.0).ok_or(NodeConstErr::EmptyNode(idx))?;
-        match me {
-            Node::Constant(v) => Ok(v.clone()),
-            Node::UnoOp(op, a) => {
-                Ok(op.eval(
-                    self.to_const(NodeIdx(*a))?))
-            }
-            Node::Op(op, a, b) => {
-                Ok(op.eval(
-                    self.to_const(NodeIdx(*a))?,
-                    self.to_const(NodeIdx(*b))?))
-            }
-            Node::TresOp(op, a, b, c) => {
-                Ok(op.eval(
-                    self.to_const(NodeIdx(*a))?,
-                    self.to_const(NodeIdx(*b))?,
-                    self.to_const(NodeIdx(*c))?))
+        if idx.0 >= self.nodes.len() {
+            return Err(NodeConstErr::EmptyNode(idx));
+        }
+        let mut consts = self.consts.borrow_mut();
+        update_consts(&mut consts, &self.nodes);
+        consts[idx.0].ok_or(NodeConstErr::InputSignal)
+    }
+
+    /// Push the node to the end of the graph. A gadget node equal to the
+    /// already pushed one is not duplicated. If the decomposition is enabled,
+    /// extraction of a bit or a limb `(a >> s) & (2^k - 1)` with constant s
+    /// multiple of k is pushed as the result of the decomposition of a, so
+    /// that loops like `Num2Bits` share a single decomposition node. Panics
+    /// if the graph outgrows the `u32` indexes of [CompactNodes], as on out of
+    /// memory: the nodes of such graph take more than 64 GiB.
+    pub fn push(&mut self, n: Node) -> NodeIdx {
+        if let Some((a, limb_bits, k)) = self.limb_extract(&n) {
+            let limbs_num = M.bit_len().div_ceil(limb_bits);
+            let gadget = if limb_bits == 1 {
+                Gadget::Bits(limbs_num)
+            } else {
+                Gadget::Limbs { limb_bits, limbs_num }
+            };
+            let g = self.push(Node::Gadget(gadget, vec![a]));
+            return self.push(Node::Extract(g.0, k));
+        }
+
+        if let Node::Gadget(..) = n {
+            if let Some(&i) = self.gadget_idx.get(&n) {
+                return NodeIdx(i);
             }
-            Node::Input(_) => Err(NodeConstErr::InputSignal),
-            Node::MontConstant(_) => {
-                panic!("MontConstant should not be used here")
+            self.gadget_idx.insert(n.clone(), self.nodes.len());
+        }
+
+        let i = self.nodes.push(&n).unwrap_or_else(|e| panic!("graph is too large: {}", e));
+        update_consts(self.consts.get_mut(), &self.nodes);
+        NodeIdx(i)
+    }
+
+    // If the node is `(a >> s) & (2^k - 1)` with constant s multiple of k and
+    // non-constant a, return a, k and s / k.
+    fn limb_extract(&self, n: &Node) -> Option<(usize, usize, usize)> {
+        if !self.decompose {
+            return None;
+        }
+        let (x, mask) = match *n {
+            Node::Op(Operation::Band, a, b) => {
+                match (self.to_const(NodeIdx(a)), self.to_const(NodeIdx(b))) {
+                    (Err(_), Ok(mask)) => (a, mask),
+                    (Ok(mask), Err(_)) => (b, mask),
+                    _ => return None,
+                }
             }
+            _ => return None,
+        };
+        let (a, shift) = match self.nodes.get(x) {
+            Node::Op(Operation::Shr, a, s) => (a, self.to_const(NodeIdx(s)).ok()?),
+            _ => return None,
+        };
+
+        let limb_bits = (mask + U256::from(1)).trailing_zeros();
+        if mask == U256::ZERO || mask != (U256::from(1) << limb_bits) - U256::from(1) {
+            return None;
+        }
+        if shift >= U256::from(M.bit_len()) {
+            return None;
         }
+        let shift = shift.as_limbs()[0] as usize;
+        let (limb, rem) = (shift / limb_bits, shift % limb_bits);
+        if rem != 0 {
+            return None;
+        }
+        Some((a, limb_bits, limb))
     }
 
-    pub fn push(&mut self, n: Node) -> NodeIdx {
-        self.0.push(n);
-        NodeIdx(self.0.len() - 1)
+    pub fn extend<I: IntoIterator<Item = Node>>(&mut self, iter: I) {
+        for n in iter {
+            self.nodes.push(&n).unwrap_or_else(|e| panic!("graph is too large: {}", e));
+        }
+        update_consts(self.consts.get_mut(), &self.nodes);
     }
-    
-    pub fn get(&self, idx: NodeIdx) -> Option<&Node> {
-        self.0.get(idx.0)
+
+    pub fn get(&self, idx: NodeIdx) -> Option<Node> {
+        (idx.0 < self.nodes.len()).then(|| self.nodes.get(idx.0))
+    }
+
+    /// Decode the nodes in order.
+    pub fn iter(&self) -> impl Iterator<Item = Node> + '_ {
+        self.nodes.iter()
+    }
+
+    /// Decode the nodes, the same as the `Vec<Node>` the nodes used to deref
+    /// to.
+    pub fn to_vec(&self) -> Vec<Node> {
+        self.nodes.to_vec()
+    }
+
+    /// Decode the built graph for the optimization passes.
+    pub fn into_nodes(self) -> Vec<Node> {
+        self.nodes.to_vec()
     }
 }
 
-impl Deref for Nodes {
-    type Target = Vec<Node>;
+impl Default for Nodes {
+    fn default() -> Self {
+        Nodes::new()
+    }
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+// Compute constant values for the nodes appended after the last update.
+fn update_consts(consts: &mut Vec<Option<U256>>, nodes: &CompactNodes) {
+    for i in consts.len()..nodes.len() {
+        let c = match nodes.get(i) {
+            Node::Constant(v) => Some(v),
+            Node::MontConstant(v) => Some(v.into()),
+            Node::Input(_) => None,
+            Node::UnoOp(op, a) => consts[a].map(|va| op.eval(va)),
+            Node::Op(op, a, b) => match (consts[a], consts[b]) {
+                (Some(va), Some(vb)) => Some(op.eval(va, vb)),
+                _ => None,
+            },
+            Node::TresOp(op, a, b, c) => match (consts[a], consts[b], consts[c]) {
+                (Some(va), Some(vb), Some(vc)) => Some(op.eval(va, vb, vc)),
+                _ => None,
+            },
+            // The gadget itself has no value. Its results are constant if all
+            // its operands are, they are recalculated for each result as it
+            // is rare in practice.
+            Node::Gadget(..) => None,
+            Node::Extract(g, k) => match nodes.get(g) {
+                Node::Gadget(gadget, ref args) => args.iter()
+                    .map(|&a| consts[a])
+                    .collect::<Option<Vec<U256>>>()
+                    .map(|args| gadget.eval(&args)[k]),
+                _ => panic!("extract from the non-gadget node {}", g),
+            },
+        };
+        consts.push(c);
     }
 }
 
@@ -327,76 +649,425 @@ impl std::fmt::Display for NodeConstErr {
 impl Error for NodeConstErr {}
 
 
-fn compute_shl_uint(a: U256, b: U256) -> U256 {
-    debug_assert!(b.lt(&U256::from(256)));
-    let ls_limb = b.as_limbs()[0];
-    a.shl(ls_limb as usize)
+/// All references must be backwards.
+/// Results can be extracted only from gadget nodes.
+fn assert_valid(nodes: &[Node]) {
+    for (i, node) in nodes.iter().enumerate() {
+        node.for_each_operand(|a| assert!(a < i));
+        if let Node::Extract(g, k) = *node {
+            match nodes[g] {
+                Node::Gadget(gadget, ref args) => {
+                    assert!(k < gadget.outputs_num(args.len()));
+                }
+                _ => panic!("extract from the non-gadget node {}", g),
+            }
+        }
+    }
+}
+
+/// Optimize the graph. Probabilistic constants determination is unsound for
+/// non-algebraic operations, so it is used only if probabilistic_constants is
+/// true. Otherwise, a conservative constant folding is used.
+pub fn optimize(
+    nodes: &mut Vec<Node>, outputs: &mut [usize],
+    probabilistic_constants: bool) -> Vec<PassStats> {
+
+    PassManager::with_level(OptLevel::O2, probabilistic_constants)
+        .run(nodes, outputs)
 }
 
-fn compute_shr_uint(a: U256, b: U256) -> U256 {
-    debug_assert!(b.lt(&U256::from(256)));
-    let ls_limb = b.as_limbs()[0];
-    a.shr(ls_limb as usize)
+/// Optimization pass of the graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pass {
+    TreeShake,
+    Propagate,
+    ReducePow,
+    Simplify,
+    Cse,
+    ValueNumbering,
+    Constants,
+    ProbabilisticConstants,
+    MontgomeryForm,
 }
 
-/// All references must be backwards.
-fn assert_valid(nodes: &[Node]) {
-    for (i, &node) in nodes.iter().enumerate() {
-        if let Node::Op(_, a, b) = node {
-            assert!(a < i);
-            assert!(b < i);
-        } else if let Node::UnoOp(_, a) = node {
-            assert!(a < i);
-        } else if let Node::TresOp(_, a, b, c) = node {
-            assert!(a < i);
-            assert!(b < i);
-            assert!(c < i);
+impl Pass {
+    /// Run the pass and return the number of changes it made. The meaning of
+    /// the change is pass specific: removed nodes, found constants, etc.
+    pub fn run(&self, nodes: &mut Vec<Node>, outputs: &mut [usize]) -> usize {
+        match self {
+            Pass::TreeShake => tree_shake(nodes, outputs),
+            Pass::Propagate => propagate(nodes),
+            Pass::ReducePow => reduce_pow(nodes, outputs),
+            Pass::Simplify => simplify(nodes, outputs),
+            Pass::Cse => cse(nodes, outputs),
+            Pass::ValueNumbering => value_numbering(nodes, outputs),
+            Pass::Constants => constants(nodes),
+            Pass::ProbabilisticConstants => probabilistic_constants(nodes),
+            Pass::MontgomeryForm => montgomery_form(nodes),
+        }
+    }
+}
+
+/// Optimization level presets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptLevel {
+    /// Only remove unused nodes.
+    O0,
+    /// Exact and fast passes only.
+    O1,
+    /// All passes including random value numbering.
+    O2,
+}
+
+/// Statistics of the single pass run.
+#[derive(Debug, Clone)]
+pub struct PassStats {
+    pub pass: Pass,
+    pub changes: usize,
+    pub nodes_before: usize,
+    pub nodes_after: usize,
+    pub duration: Duration,
+}
+
+impl std::fmt::Display for PassStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f, "{:?}: {} changes, nodes {} -> {}, {:?}",
+            self.pass, self.changes, self.nodes_before, self.nodes_after,
+            self.duration)
+    }
+}
+
+/// Runs the ordered list of passes over the graph.
+#[derive(Debug, Clone, Default)]
+pub struct PassManager {
+    passes: Vec<Pass>,
+    verify: bool,
+}
+
+impl PassManager {
+    pub fn new() -> Self {
+        PassManager::default()
+    }
+
+    pub fn with_level(level: OptLevel, probabilistic_constants: bool) -> Self {
+        use Pass::*;
+        let constants = if probabilistic_constants {
+            ProbabilisticConstants
+        } else {
+            Constants
+        };
+        let passes = match level {
+            OptLevel::O0 => vec![TreeShake, MontgomeryForm],
+            OptLevel::O1 => vec![
+                TreeShake, Propagate, ReducePow, Simplify, Cse, constants,
+                TreeShake, MontgomeryForm],
+            OptLevel::O2 => vec![
+                TreeShake, Propagate, ReducePow, Simplify, Cse, TreeShake,
+                ValueNumbering, constants, TreeShake, MontgomeryForm],
+        };
+        PassManager { passes, verify: false }
+    }
+
+    pub fn add(&mut self, pass: Pass) -> &mut Self {
+        self.passes.push(pass);
+        self
+    }
+
+    pub fn passes(&self) -> &[Pass] {
+        &self.passes
+    }
+
+    /// Keep only the first n passes. Useful to find the pass that breaks the
+    /// graph by bisecting.
+    pub fn truncate(&mut self, n: usize) -> &mut Self {
+        self.passes.truncate(n);
+        self
+    }
+
+    /// If enabled, the graph is checked after each pass: all references
+    /// should be valid and the outputs evaluated on random inputs should not
+    /// change. Panics on the first pass that breaks the graph.
+    pub fn verify(&mut self, verify: bool) -> &mut Self {
+        self.verify = verify;
+        self
+    }
+
+    pub fn run(&self, nodes: &mut Vec<Node>, outputs: &mut [usize]) -> Vec<PassStats> {
+        let verifier = if self.verify {
+            Some(Verifier::new(nodes, outputs))
+        } else {
+            None
+        };
+
+        let mut stats = Vec::with_capacity(self.passes.len());
+        for pass in self.passes.iter() {
+            let nodes_before = nodes.len();
+            let start = Instant::now();
+            let changes = pass.run(nodes, outputs);
+            let duration = start.elapsed();
+            if let Some(verifier) = &verifier {
+                verifier.check(pass, nodes, outputs);
+            }
+            stats.push(PassStats {
+                pass: *pass,
+                changes,
+                nodes_before,
+                nodes_after: nodes.len(),
+                duration,
+            });
+        }
+        stats
+    }
+}
+
+// Number of random input sets used to verify the graph between passes.
+const VERIFY_ROUNDS: usize = 2;
+
+// Expected outputs of the graph on random inputs.
+struct Verifier {
+    inputs: Vec<Vec<U256>>,
+    expected: Vec<Vec<U256>>,
+}
+
+impl Verifier {
+    fn new(nodes: &[Node], outputs: &[usize]) -> Self {
+        assert_valid(nodes);
+        let inputs_num = nodes.iter()
+            .filter_map(|n| match n {
+                Node::Input(i) => Some(*i + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+
+        let mut rng = ChaCha8Rng::seed_from_u64(RANDOM_EVAL_SEED);
+        let inputs = (0..VERIFY_ROUNDS)
+            .map(|_| (0..inputs_num).map(|_| rng.gen::<U256>() % M).collect())
+            .collect::<Vec<Vec<U256>>>();
+        let expected = inputs.iter()
+            .map(|inputs| evaluate_reference(nodes, inputs, outputs))
+            .collect();
+        Verifier { inputs, expected }
+    }
+
+    fn check(&self, pass: &Pass, nodes: &[Node], outputs: &[usize]) {
+        assert_valid(nodes);
+        for (i, &o) in outputs.iter().enumerate() {
+            assert!(
+                o < nodes.len(),
+                "pass {:?}: output #{} references missing node {}", pass, i, o);
+        }
+        for (inputs, expected) in self.inputs.iter().zip(self.expected.iter()) {
+            let got = evaluate_reference(nodes, inputs, outputs);
+            if let Some(i) = (0..got.len()).find(|&i| got[i] != expected[i]) {
+                panic!(
+                    "pass {:?}: output #{} changed: expected {}, got {}",
+                    pass, i, expected[i], got[i]);
+            }
         }
     }
 }
 
-pub fn optimize(nodes: &mut Vec<Node>, outputs: &mut [usize]) {
-    tree_shake(nodes, outputs);
-    propagate(nodes);
-    value_numbering(nodes, outputs);
-    constants(nodes);
-    tree_shake(nodes, outputs);
-    montgomery_form(nodes);
+/// Evaluate the graph on plain U256 values, without Montgomery form. It is
+/// slower than `evaluate`, but works on the unoptimized graph, so it is used
+/// as a reference to check the optimized one.
+pub fn evaluate_reference(nodes: &[Node], inputs: &[U256], outputs: &[usize]) -> Vec<U256> {
+    let mut values = Vec::with_capacity(nodes.len());
+    let mut gadget_values: HashMap<usize, Vec<U256>> = HashMap::new();
+    for (i, node) in nodes.iter().enumerate() {
+        let value = match *node {
+            Node::Constant(c) => c,
+            Node::MontConstant(c) => c.into(),
+            Node::Input(i) => inputs[i],
+            Node::Op(op, a, b) => op.eval(values[a], values[b]),
+            Node::UnoOp(op, a) => op.eval(values[a]),
+            Node::TresOp(op, a, b, c) => op.eval(values[a], values[b], values[c]),
+            Node::Gadget(gadget, ref args) => {
+                let args = args.iter().map(|&a| values[a]).collect::<Vec<U256>>();
+                gadget_values.insert(i, gadget.eval(&args));
+                U256::ZERO
+            }
+            Node::Extract(g, k) => gadget_values[&g][k],
+        };
+        values.push(value);
+    }
+    outputs.iter().map(|&i| values[i]).collect()
 }
 
 pub fn evaluate(nodes: &[Node], inputs: &[U256], outputs: &[usize]) -> Vec<U256> {
     // assert_valid(nodes);
 
     // Evaluate the graph.
-    let mut values = Vec::with_capacity(nodes.len());
-    for (_, &node) in nodes.iter().enumerate() {
-        let value = match node {
-            Node::Constant(c) => Fr::new(c.into()),
-            Node::MontConstant(c) => c,
-            Node::Input(i) => Fr::new(inputs[i].into()),
-            Node::Op(op, a, b) => op.eval_fr(values[a], values[b]),
-            Node::UnoOp(op, a) => op.eval_fr(values[a]),
-            Node::TresOp(op, a, b, c) => op.eval_fr(values[a], values[b], values[c]),
+    let mut values: Vec<Value> = Vec::with_capacity(nodes.len());
+    let mut gadget_values = GadgetValues::from_nodes(nodes);
+    for (i, node) in nodes.iter().enumerate() {
+        let value = match *node {
+            Node::Constant(c) => Value::Int(c % M),
+            Node::MontConstant(c) => Value::Mont(c),
+            Node::Input(i) => Value::Int(inputs[i] % M),
+            Node::Op(op, a, b) => op.eval_value(values[a], values[b]),
+            Node::UnoOp(op, a) => op.eval_value(values[a]),
+            Node::TresOp(op, a, b, c) => op.eval_value(values[a], values[b], values[c]),
+            Node::Gadget(gadget, ref args) => {
+                let args = args.iter().map(|&a| values[a].fr()).collect::<Vec<Fr>>();
+                gadget_values.insert(i, gadget.eval_fr(&args));
+                Value::default()
+            }
+            Node::Extract(g, k) => {
+                let v = gadget_values.get(g)[k];
+                gadget_values.extracted(i, g);
+                Value::Mont(v)
+            }
         };
         values.push(value);
     }
 
-    // Convert from Montgomery form and return the outputs.
+    outputs.iter().map(|&i| values[i].int()).collect()
+}
+
+/// Evaluate only the witness entries at the `wanted` indexes. `signals` maps
+/// the witness index to the graph node, as `outputs` in [evaluate]. Only the
+/// backward cone of the wanted nodes is calculated, so getting a few outputs
+/// of the circuit is much cheaper than the full witness. The nodes are
+/// converted to [CompactNodes], use [CompactNodes::evaluate_subset] for the
+/// loaded graph. Panics if an index does not fit into `u32`.
+pub fn evaluate_subset(
+    nodes: &[Node], inputs: &[U256], signals: &[usize],
+    wanted: &[usize]) -> Vec<U256> {
+
+    CompactNodes::try_from(nodes).unwrap().evaluate_subset(inputs, signals, wanted)
+}
+
+// Sentinel for the node that is not used by any other node.
+const NO_USE: usize = usize::MAX;
+
+/// Assign each node a slot in the evaluator memory, so that the nodes with
+/// non-overlapping lifetimes share the same slot. The lifetime of the node
+/// ends at its last use; the output values are copied out as soon as they are
+/// calculated, so they don't need to stay alive. Returns the slot of each
+/// node, the number of slots is the maximum number of live values.
+pub fn allocate_slots(nodes: &[Node]) -> Vec<usize> {
+    assert_valid(nodes);
+    allocate_slots_by(nodes.len(), |i, f| nodes[i].for_each_operand(f))
+}
+
+// allocate_slots for the graph of n nodes, for_each_operand(i, f) calls f for
+// each operand of the node i.
+pub(crate) fn allocate_slots_by(
+    n: usize, for_each_operand: impl Fn(usize, &mut dyn FnMut(usize))) -> Vec<usize> {
+
+    let mut last_use = vec![NO_USE; n];
+    for i in 0..n {
+        for_each_operand(i, &mut |a| last_use[a] = i);
+    }
+
+    let mut slots = Vec::with_capacity(n);
+    let mut free: Vec<usize> = Vec::new();
+    let mut slots_num = 0_usize;
+    for i in 0..n {
+        // Release the slots of the operands used for the last time. The
+        // operands are read before the result is written, so the result may
+        // reuse the slot of the operand.
+        for_each_operand(i, &mut |a| {
+            if last_use[a] == i {
+                free.push(slots[a]);
+                // do not release the slot twice if the operand is repeated
+                last_use[a] = NO_USE;
+            }
+        });
+
+        let slot = free.pop().unwrap_or_else(|| {
+            slots_num += 1;
+            slots_num - 1
+        });
+        slots.push(slot);
+
+        // The node without uses is alive only while it is copied to outputs.
+        if last_use[i] == NO_USE {
+            free.push(slot);
+        }
+    }
+
+    slots
+}
+
+/// Evaluate the graph using the slots from `allocate_slots` to store the
+/// intermediate values. Memory usage is proportional to the number of slots
+/// instead of the number of nodes.
+pub fn evaluate_with_slots(
+    nodes: &[Node], slots: &[usize], inputs: &[U256],
+    outputs: &[usize]) -> Vec<U256> {
+
+    assert_eq!(nodes.len(), slots.len());
+
+    // Outputs sorted by the node index, to copy them out as soon as the node
+    // is calculated.
+    let mut outputs_order = (0..outputs.len()).collect::<Vec<usize>>();
+    outputs_order.sort_by_key(|&i| outputs[i]);
+    let mut next_output = 0;
+
+    let slots_num = slots.iter().max().map_or(0, |&m| m + 1);
+    let mut values = vec![Value::default(); slots_num];
     let mut out = vec![U256::ZERO; outputs.len()];
-    for i in 0..outputs.len() {
-        out[i] = U256::try_from(values[outputs[i]].into_bigint()).unwrap();
+    // Gadget results are stored apart from the slots by the gadget node index.
+    let mut gadget_values = GadgetValues::from_nodes(nodes);
+    for (i, node) in nodes.iter().enumerate() {
+        let value = match *node {
+            Node::Constant(c) => Value::Int(c % M),
+            Node::MontConstant(c) => Value::Mont(c),
+            Node::Input(i) => Value::Int(inputs[i] % M),
+            Node::Op(op, a, b) => op.eval_value(values[slots[a]], values[slots[b]]),
+            Node::UnoOp(op, a) => op.eval_value(values[slots[a]]),
+            Node::TresOp(op, a, b, c) => op.eval_value(
+                values[slots[a]], values[slots[b]], values[slots[c]]),
+            Node::Gadget(gadget, ref args) => {
+                let args = args.iter()
+                    .map(|&a| values[slots[a]].fr())
+                    .collect::<Vec<Fr>>();
+                gadget_values.insert(i, gadget.eval_fr(&args));
+                Value::default()
+            }
+            Node::Extract(g, k) => {
+                let v = gadget_values.get(g)[k];
+                gadget_values.extracted(i, g);
+                Value::Mont(v)
+            }
+        };
+        values[slots[i]] = value;
+
+        while next_output < outputs_order.len()
+            && outputs[outputs_order[next_output]] == i {
+
+            out[outputs_order[next_output]] = value.int();
+            next_output += 1;
+        }
     }
 
     out
 }
 
+/// Evaluate the graph for many input sets at once. The graph is walked once
+/// and each operation is applied to the whole batch. Values are stored in the
+/// structure-of-arrays layout: the batch values of a node are contiguous.
+/// Slots from `allocate_slots` are used, so memory usage is proportional to
+/// the maximum number of live values times the batch size. Returns the
+/// outputs for each input set. The nodes are converted to [CompactNodes], use
+/// [CompactNodes::evaluate_batch] for the loaded graph. Panics if an index
+/// does not fit into `u32`.
+pub fn evaluate_batch(
+    nodes: &[Node], inputs: &[Vec<U256>], outputs: &[usize]) -> Vec<Vec<U256>> {
+
+    CompactNodes::try_from(nodes).unwrap().evaluate_batch(inputs, outputs)
+}
+
 /// Constant propagation
-pub fn propagate(nodes: &mut [Node]) {
+pub fn propagate(nodes: &mut [Node]) -> usize {
     assert_valid(nodes);
     let mut constants = 0_usize;
     for i in 0..nodes.len() {
         if let Node::Op(op, a, b) = nodes[i] {
-            if let (Node::Constant(va), Node::Constant(vb)) = (nodes[a], nodes[b]) {
+            if let (&Node::Constant(va), &Node::Constant(vb)) = (&nodes[a], &nodes[b]) {
                 nodes[i] = Node::Constant(op.eval(va, vb));
                 constants += 1;
             } else if a == b {
@@ -417,18 +1088,211 @@ pub fn propagate(nodes: &mut [Node]) {
                 constants += 1;
             }
         } else if let Node::TresOp(op, a, b, c) = nodes[i] {
-            if let (Node::Constant(va), Node::Constant(vb), Node::Constant(vc)) = (nodes[a], nodes[b], nodes[c]) {
+            if let (&Node::Constant(va), &Node::Constant(vb), &Node::Constant(vc)) = (&nodes[a], &nodes[b], &nodes[c]) {
                 nodes[i] = Node::Constant(op.eval(va, vb, vc));
                 constants += 1;
             }
         }
     }
 
-    eprintln!("Propagated {constants} constants");
+    constants
+}
+
+// Exponents longer than this are left for the generic exponentiation, as the
+// multiplication chain would be too long.
+const REDUCE_POW_MAX_BITS: usize = 64;
+
+/// Strength reduction of `Pow` with a constant exponent: replace it with the
+/// chain of multiplications (binary exponentiation), so `x ** 5` becomes
+/// three `Mul` nodes. Nodes that become unused are not removed, run
+/// `tree_shake` after it.
+pub fn reduce_pow(nodes: &mut Vec<Node>, outputs: &mut [usize]) -> usize {
+    assert_valid(nodes);
+
+    let mut reduced = 0_usize;
+    let mut new_nodes = Vec::with_capacity(nodes.len());
+    let mut renumber = Vec::with_capacity(nodes.len());
+    for node in nodes.iter() {
+        let mut node = node.clone();
+        node.map_operands(|a| renumber[a]);
+
+        if let Node::Op(Operation::Pow, a, b) = node {
+            if let Node::Constant(e) = new_nodes[b] {
+                if e.bit_len() <= REDUCE_POW_MAX_BITS {
+                    renumber.push(pow_chain(&mut new_nodes, a, e));
+                    reduced += 1;
+                    continue;
+                }
+            }
+        }
+
+        new_nodes.push(node);
+        renumber.push(new_nodes.len() - 1);
+    }
+
+    for output in outputs.iter_mut() {
+        *output = renumber[*output];
+    }
+    *nodes = new_nodes;
+
+    reduced
+}
+
+// Append the nodes to calculate a^e and return the index of the result.
+fn pow_chain(nodes: &mut Vec<Node>, a: usize, e: U256) -> usize {
+    if e == U256::ZERO {
+        nodes.push(Node::Constant(U256::from(1)));
+        return nodes.len() - 1;
+    }
+
+    let mut r = a;
+    for i in (0..e.bit_len() - 1).rev() {
+        nodes.push(Node::Op(Operation::Mul, r, r));
+        r = nodes.len() - 1;
+        if e.bit(i) {
+            nodes.push(Node::Op(Operation::Mul, r, a));
+            r = nodes.len() - 1;
+        }
+    }
+    r
+}
+
+// Result of the peephole rule applied to a node.
+enum Rewrite {
+    // The node is equal to the other (earlier) node.
+    Alias(usize),
+    // The node is replaced with the new one.
+    Replace(Node),
+}
+
+/// Algebraic simplification. Applies peephole rules like `x * 1 = x`,
+/// `x - x = 0`, `Neg(Neg(x)) = x` or `Id(x) = x` until no more rules apply.
+/// Nodes that become unused are not removed, run `tree_shake` after it.
+pub fn simplify(nodes: &mut [Node], outputs: &mut [usize]) -> usize {
+    assert_valid(nodes);
+
+    let mut total = 0_usize;
+    loop {
+        let changes = simplify_pass(nodes, outputs);
+        if changes == 0 {
+            break;
+        }
+        total += changes;
+    }
+
+    total
+}
+
+// Single forward pass of the simplification. Returns the number of changed
+// references and replaced nodes, so that it returns 0 on the fixed point.
+fn simplify_pass(nodes: &mut [Node], outputs: &mut [usize]) -> usize {
+    let mut changes = 0_usize;
+    let mut renumber: Vec<usize> = Vec::with_capacity(nodes.len());
+    // is_bool[i] is true if the node i is known to be 0 or 1.
+    let mut is_bool: Vec<bool> = Vec::with_capacity(nodes.len());
+
+    let update = |x: &mut usize, renumber: &[usize]| -> usize {
+        if renumber[*x] == *x {
+            return 0;
+        }
+        *x = renumber[*x];
+        1
+    };
+
+    for i in 0..nodes.len() {
+        // Operands of the node are already simplified.
+        nodes[i].map_operands(|mut a| {
+            changes += update(&mut a, &renumber);
+            a
+        });
+
+        match simplify_node(nodes, &is_bool, &nodes[i]) {
+            Some(Rewrite::Alias(j)) => {
+                renumber.push(j);
+                is_bool.push(is_bool[j]);
+            }
+            Some(Rewrite::Replace(n)) => {
+                is_bool.push(node_is_bool(nodes, &is_bool, &n));
+                nodes[i] = n;
+                renumber.push(i);
+                changes += 1;
+            }
+            None => {
+                renumber.push(i);
+                is_bool.push(node_is_bool(nodes, &is_bool, &nodes[i]));
+            }
+        }
+    }
+
+    for output in outputs.iter_mut() {
+        changes += update(output, &renumber);
+    }
+
+    changes
+}
+
+fn node_is_bool(nodes: &[Node], is_bool: &[bool], node: &Node) -> bool {
+    use Operation::*;
+    match *node {
+        Node::Constant(c) => c == U256::ZERO || c == U256::from(1),
+        Node::Op(Eq | Neq | Lt | Gt | Leq | Geq | Land | Lor, _, _) => true,
+        Node::Op(Mul | Band, a, b) => is_bool[a] && is_bool[b],
+        Node::TresOp(TresOperation::TernCond, _, b, c) => is_bool[b] && is_bool[c],
+        Node::Extract(g, _) => {
+            matches!(nodes[g], Node::Gadget(gadget, _) if gadget.is_bool())
+        }
+        _ => false,
+    }
+}
+
+fn simplify_node(nodes: &[Node], is_bool: &[bool], node: &Node) -> Option<Rewrite> {
+    use Operation::*;
+    let zero = Node::Constant(U256::ZERO);
+    let one = Node::Constant(U256::from(1));
+
+    match *node {
+        Node::UnoOp(UnoOperation::Id, a) => Some(Rewrite::Alias(a)),
+        Node::UnoOp(UnoOperation::Neg, a) => match nodes[a] {
+            Node::UnoOp(UnoOperation::Neg, b) => Some(Rewrite::Alias(b)),
+            _ => None,
+        },
+        Node::TresOp(TresOperation::TernCond, a, b, c) => {
+            if b == c {
+                Some(Rewrite::Alias(b))
+            } else if is_bool[a] && nodes[b] == one && nodes[c] == zero {
+                // b ? 1 : 0 = b
+                Some(Rewrite::Alias(a))
+            } else {
+                None
+            }
+        }
+        Node::Op(op, a, b) => {
+            let (na, nb) = (&nodes[a], &nodes[b]);
+            match op {
+                Mul if *na == zero || *nb == zero => Some(Rewrite::Replace(zero)),
+                Mul if *na == one => Some(Rewrite::Alias(b)),
+                Mul | Div | Idiv if *nb == one => Some(Rewrite::Alias(a)),
+                Add if *na == zero => Some(Rewrite::Alias(b)),
+                Add | Sub if *nb == zero => Some(Rewrite::Alias(a)),
+                Sub | Bxor if a == b => Some(Rewrite::Replace(zero)),
+                // Comparison of a boolean value with 0 or 1
+                Eq | Land | Band if is_bool[a] && *nb == one => Some(Rewrite::Alias(a)),
+                Eq | Land | Band if is_bool[b] && *na == one => Some(Rewrite::Alias(b)),
+                Neq | Lor if is_bool[a] && *nb == zero => Some(Rewrite::Alias(a)),
+                Neq | Lor if is_bool[b] && *na == zero => Some(Rewrite::Alias(b)),
+                // b != 1 = 1 - b
+                Neq if is_bool[a] && *nb == one => Some(Rewrite::Replace(Node::Op(Sub, b, a))),
+                Neq if is_bool[b] && *na == one => Some(Rewrite::Replace(Node::Op(Sub, a, b))),
+                _ => None,
+            }
+        }
+        Node::Gadget(..) | Node::Extract(..) => None,
+        Node::Input(_) | Node::Constant(_) | Node::MontConstant(_) => None,
+    }
 }
 
 /// Remove unused nodes
-pub fn tree_shake(nodes: &mut Vec<Node>, outputs: &mut [usize]) {
+pub fn tree_shake(nodes: &mut Vec<Node>, outputs: &mut [usize]) -> usize {
     assert_valid(nodes);
 
     // Mark all nodes that are used.
@@ -440,18 +1304,7 @@ pub fn tree_shake(nodes: &mut Vec<Node>, outputs: &mut [usize]) {
     // Work backwards from end as all references are backwards.
     for i in (0..nodes.len()).rev() {
         if used[i] {
-            if let Node::Op(_, a, b) = nodes[i] {
-                used[a] = true;
-                used[b] = true;
-            }
-            if let Node::UnoOp(_, a) = nodes[i] {
-                used[a] = true;
-            }
-            if let Node::TresOp(_, a, b, c) = nodes[i] {
-                used[a] = true;
-                used[b] = true;
-                used[c] = true;
-            }
+            nodes[i].for_each_operand(|a| used[a] = true);
         }
     }
 
@@ -477,34 +1330,63 @@ pub fn tree_shake(nodes: &mut Vec<Node>, outputs: &mut [usize]) {
 
     // Renumber references.
     for node in nodes.iter_mut() {
-        if let Node::Op(_, a, b) = node {
-            *a = renumber[*a].unwrap();
-            *b = renumber[*b].unwrap();
-        }
-        if let Node::UnoOp(_, a) = node {
-            *a = renumber[*a].unwrap();
+        node.map_operands(|a| renumber[a].unwrap());
+    }
+    for output in outputs.iter_mut() {
+        *output = renumber[*output].unwrap();
+    }
+
+    removed
+}
+
+/// Common subexpression elimination. Unlike `value_numbering` it is exact: two
+/// nodes are merged only if they have the same operation and the same operands
+/// (in any order for commutative operations). Nodes that become unused are not
+/// removed, run `tree_shake` after it.
+pub fn cse(nodes: &mut [Node], outputs: &mut [usize]) -> usize {
+    assert_valid(nodes);
+
+    let mut seen: HashMap<Node, usize> = HashMap::with_capacity(nodes.len());
+    let mut renumber = Vec::with_capacity(nodes.len());
+    let mut merged = 0_usize;
+
+    for (i, node) in nodes.iter_mut().enumerate() {
+        node.map_operands(|a| renumber[a]);
+        if let Node::Op(op, a, b) = node {
+            if op.is_commutative() && *a > *b {
+                std::mem::swap(a, b);
+            }
         }
-        if let Node::TresOp(_, a, b, c) = node {
-            *a = renumber[*a].unwrap();
-            *b = renumber[*b].unwrap();
-            *c = renumber[*c].unwrap();
+
+        let j = *seen.entry(node.clone()).or_insert(i);
+        if j != i {
+            merged += 1;
         }
+        renumber.push(j);
     }
+
     for output in outputs.iter_mut() {
-        *output = renumber[*output].unwrap();
+        *output = renumber[*output];
     }
 
-    eprintln!("Removed {removed} unused nodes");
+    merged
 }
 
+// Seed for the random evaluation of the graph. We use a fixed seed to have
+// reproducible optimizations: the same circuit should always produce the same
+// graph file. The seed is used with ChaCha8Rng and not StdRng, because the
+// StdRng algorithm may change between rand versions.
+const RANDOM_EVAL_SEED: u64 = 0x7769746e65737363;
+
 /// Randomly evaluate the graph
-fn random_eval(nodes: &mut Vec<Node>) -> Vec<U256> {
-    let mut rng = rand::thread_rng();
+fn random_eval(nodes: &[Node], rng: &mut impl Rng) -> Vec<U256> {
     let mut values = Vec::with_capacity(nodes.len());
     let mut inputs = HashMap::new();
     let mut prfs = HashMap::new();
     let mut prfs_uno = HashMap::new();
     let mut prfs_tres = HashMap::new();
+    let mut prfs_gadget = HashMap::new();
+    let mut prfs_extract = HashMap::new();
     for node in nodes.iter() {
         use Operation::*;
         let value = match node {
@@ -530,6 +1412,12 @@ fn random_eval(nodes: &mut Vec<Node>) -> Vec<U256> {
             Node::TresOp(op, a, b, c) => *prfs_tres
                 .entry((*op, values[*a], values[*b], values[*c]))
                 .or_insert_with(|| rng.gen::<U256>() % M),
+            Node::Gadget(gadget, args) => *prfs_gadget
+                .entry((*gadget, args.iter().map(|&a| values[a]).collect::<Vec<U256>>()))
+                .or_insert_with(|| rng.gen::<U256>() % M),
+            Node::Extract(g, k) => *prfs_extract
+                .entry((values[*g], *k))
+                .or_insert_with(|| rng.gen::<U256>() % M),
         };
         values.push(value);
     }
@@ -537,11 +1425,12 @@ fn random_eval(nodes: &mut Vec<Node>) -> Vec<U256> {
 }
 
 /// Value numbering
-pub fn value_numbering(nodes: &mut Vec<Node>, outputs: &mut [usize]) {
+pub fn value_numbering(nodes: &mut [Node], outputs: &mut [usize]) -> usize {
     assert_valid(nodes);
 
     // Evaluate the graph in random field elements.
-    let values = random_eval(nodes);
+    let mut rng = ChaCha8Rng::seed_from_u64(RANDOM_EVAL_SEED);
+    let values = random_eval(nodes, &mut rng);
 
     // Find all nodes with the same value.
     let mut value_map = HashMap::new();
@@ -551,44 +1440,106 @@ pub fn value_numbering(nodes: &mut Vec<Node>, outputs: &mut [usize]) {
 
     // For nodes that are the same, pick the first index.
     let mut renumber = Vec::with_capacity(nodes.len());
-    for value in values {
-        renumber.push(value_map[&value][0]);
+    let mut merged = 0_usize;
+    for (i, value) in values.iter().enumerate() {
+        let j = value_map[value][0];
+        if j != i {
+            merged += 1;
+        }
+        renumber.push(j);
     }
 
     // Renumber references.
     for node in nodes.iter_mut() {
-        if let Node::Op(_, a, b) = node {
-            *a = renumber[*a];
-            *b = renumber[*b];
-        }
-        if let Node::UnoOp(_, a) = node {
-            *a = renumber[*a];
-        }
-        if let Node::TresOp(_, a, b, c) = node {
-            *a = renumber[*a];
-            *b = renumber[*b];
-            *c = renumber[*c];
-        }
+        node.map_operands(|a| renumber[a]);
     }
     for output in outputs.iter_mut() {
         *output = renumber[*output];
     }
 
-    eprintln!("Global value numbering applied");
+    merged
 }
 
-/// Probabilistic constant determination
-pub fn constants(nodes: &mut Vec<Node>) {
+/// Sound constant determination. The node is replaced with a constant only if
+/// all its operands are constants (transitively, as nodes are processed in
+/// order) or the result does not depend on the non-constant operand, like
+/// `x * 0` or `x && 0`.
+pub fn constants(nodes: &mut [Node]) -> usize {
+    assert_valid(nodes);
+
+    let mut constants = 0;
+    for i in 0..nodes.len() {
+        if let Some(c) = fold_constant(nodes, &nodes[i]) {
+            nodes[i] = Node::Constant(c);
+            constants += 1;
+        }
+    }
+    constants
+}
+
+// Return the value of the node if it is a constant regardless of the values of
+// the input signals.
+fn fold_constant(nodes: &[Node], node: &Node) -> Option<U256> {
+    let c = |i: usize| match nodes[i] {
+        Node::Constant(c) => Some(c),
+        _ => None,
+    };
+
+    match *node {
+        Node::Op(op, a, b) => {
+            use Operation::*;
+            match (op, c(a), c(b)) {
+                (_, Some(va), Some(vb)) => Some(op.eval(va, vb)),
+                (Mul | Land | Band, Some(U256::ZERO), _) => Some(U256::ZERO),
+                (Mul | Land | Band, _, Some(U256::ZERO)) => Some(U256::ZERO),
+                // division by zero returns zero
+                (Div | Idiv | Mod | Shl | Shr, Some(U256::ZERO), _) => Some(U256::ZERO),
+                (Lor, Some(v), _) | (Lor, _, Some(v)) if v != U256::ZERO => {
+                    Some(U256::from(1))
+                }
+                _ => None,
+            }
+        }
+        Node::UnoOp(op, a) => c(a).map(|va| op.eval(va)),
+        Node::TresOp(op @ TresOperation::TernCond, a, b, cc) => {
+            match (c(a), c(b), c(cc)) {
+                (Some(va), Some(vb), Some(vc)) => Some(op.eval(va, vb, vc)),
+                (Some(va), vb, vc) => if va == U256::ZERO { vc } else { vb },
+                (None, Some(vb), Some(vc)) if vb == vc => Some(vb),
+                _ => None,
+            }
+        }
+        // The gadget is recalculated for each of its constant results, it
+        // is rare in practice.
+        Node::Extract(g, k) => match nodes[g] {
+            Node::Gadget(gadget, ref args) => args.iter()
+                .map(|&a| c(a))
+                .collect::<Option<Vec<U256>>>()
+                .map(|args| gadget.eval(&args)[k]),
+            _ => None,
+        },
+        Node::Input(_) | Node::Constant(_) | Node::MontConstant(_)
+        | Node::Gadget(..) => None,
+    }
+}
+
+/// Probabilistic constant determination. The node is considered constant if
+/// two random evaluations of the graph give the same value. It is unsound:
+/// non-algebraic operations (comparisons, bit operations, integer division)
+/// are modeled as random functions, so use it only as an explicit opt-in.
+pub fn probabilistic_constants(nodes: &mut [Node]) -> usize {
     assert_valid(nodes);
 
     // Evaluate the graph in random field elements.
-    let values_a = random_eval(nodes);
-    let values_b = random_eval(nodes);
+    let mut rng = ChaCha8Rng::seed_from_u64(RANDOM_EVAL_SEED);
+    let values_a = random_eval(nodes, &mut rng);
+    let values_b = random_eval(nodes, &mut rng);
 
     // Find all nodes with the same value.
     let mut constants = 0;
     for i in 0..nodes.len() {
-        if let Node::Constant(_) = nodes[i] {
+        // Gadget nodes have no value, only their results can be constants.
+        if let Node::Constant(_) | Node::Gadget(..) = nodes[i] {
             continue;
         }
         if values_a[i] == values_b[i] {
@@ -596,124 +1547,48 @@ pub fn constants(nodes: &mut Vec<Node>) {
             constants += 1;
         }
     }
-    eprintln!("Found {} constants", constants);
+    constants
 }
 
 /// Convert to Montgomery form
-pub fn montgomery_form(nodes: &mut [Node]) {
+pub fn montgomery_form(nodes: &mut [Node]) -> usize {
+    let mut converted = 0_usize;
     for node in nodes.iter_mut() {
         use Node::*;
-        use Operation::*;
         match node {
-            Constant(c) => *node = MontConstant(Fr::new((*c).into())),
+            Constant(c) => {
+                *node = MontConstant(Fr::new((*c).into()));
+                converted += 1;
+            }
             MontConstant(..) => (),
             Input(..) => (),
-            Op(Mul | Div | Add | Sub | Idiv | Mod | Eq | Neq | Lt | Gt | Leq | Geq | Land | Lor | Shl | Shr | Bor | Band | Bxor , ..) => (),
-            Op(op @ Pow, ..) => unimplemented!("Operators Montgomery form: {:?}", op),
-            UnoOp(UnoOperation::Neg, ..) => (),
-            UnoOp(op, ..) => unimplemented!("Uno Operators Montgomery form: {:?}", op),
+            Op(..) => (),
+            UnoOp(UnoOperation::Neg | UnoOperation::Id, ..) => (),
             TresOp(TresOperation::TernCond, ..) => (),
+            Gadget(..) | Extract(..) => (),
         }
     }
-    eprintln!("Converted to Montgomery form");
+    converted
 }
 
 fn shl(a: Fr, b: Fr) -> Fr {
-    if b.is_zero() {
-        return a;
-    }
-
-    if b.cmp(&Fr::from(Fr::MODULUS_BIT_SIZE)).is_ge() {
-        return Fr::zero();
-    }
-
-    let n = b.into_bigint().0[0] as u32;
-
-    let mut a = a.into_bigint();
-    a.muln(n);
-    return Fr::from_bigint(a).unwrap();
+    Fr::new(shl_uint(a.into(), b.into()).into())
 }
 
 fn shr(a: Fr, b: Fr) -> Fr {
-    if b.is_zero() {
-        return a;
-    }
-
-    match b.cmp(&Fr::from(254u64)) {
-        Ordering::Equal  => {return Fr::zero()},
-        Ordering::Greater => {return Fr::zero()},
-        _ => (),
-    };
-
-    let mut n = b.into_bigint().to_bytes_le()[0];
-    let mut result = a.into_bigint();
-    let c = result.as_mut();
-    while n >= 64 {
-        for i in 0..3 {
-            c[i as usize] = c[(i + 1) as usize];
-        }
-        c[3] = 0;
-        n -= 64;
-    }
-
-    if n == 0 {
-        return Fr::from_bigint(result).unwrap();
-    }
-
-    let mask:u64 = (1<<n) - 1;
-    let mut carrier: u64 = c[3] & mask;
-    c[3] >>= n;
-    for i in (0..3).rev() {
-        let new_carrier = c[i] & mask;
-        c[i] = (c[i] >> n) | (carrier << (64 - n));
-        carrier = new_carrier;
-    }
-    Fr::from_bigint(result).unwrap()
+    Fr::new(shr_uint(a.into(), b.into()).into())
 }
 
 fn bit_and(a: Fr, b: Fr) -> Fr {
-    let a = a.into_bigint();
-    let b = b.into_bigint();
-    let mut c: [u64; 4] = [0; 4];
-    for i in 0..4 {
-        c[i] = a.0[i] & b.0[i];
-    }
-    let mut d: BigInt<4> = BigInt::new(c);
-    if d > Fr::MODULUS {
-        d.sub_with_borrow(&Fr::MODULUS);
-    }
-
-    Fr::from_bigint(d).unwrap()
+    Fr::new(Operation::Band.eval(a.into(), b.into()).into())
 }
 
 fn bit_or(a: Fr, b: Fr) -> Fr {
-    let a = a.into_bigint();
-    let b = b.into_bigint();
-    let mut c: [u64; 4] = [0; 4];
-    for i in 0..4 {
-        c[i] = a.0[i] | b.0[i];
-    }
-    let mut d: BigInt<4> = BigInt::new(c);
-    if d > Fr::MODULUS {
-        d.sub_with_borrow(&Fr::MODULUS);
-    }
-
-    Fr::from_bigint(d).unwrap()
+    Fr::new(Operation::Bor.eval(a.into(), b.into()).into())
 }
 
 fn bit_xor(a: Fr, b: Fr) -> Fr {
-    let a = a.into_bigint();
-    let b = b.into_bigint();
-    let mut c: [u64; 4] = [0; 4];
-    for i in 0..4 {
-        c[i] = a.0[i] ^ b.0[i];
-    }
-    let mut d: BigInt<4> = BigInt::new(c);
-    if d > Fr::MODULUS {
-        d.sub_with_borrow(&Fr::MODULUS);
-    }
-
-    Fr::from_bigint(d).unwrap()
+    Fr::new(Operation::Bxor.eval(a.into(), b.into()).into())
 }
 
 // M / 2
@@ -784,6 +1659,54 @@ mod tests {
         assert_eq!(c.cmp(&Fr::from(16u64)), Ordering::Equal)
     }
 
+    #[test]
+    fn test_shl_overflow() {
+        use num_bigint::BigUint;
+        let m = BigUint::from_bytes_le(&M.to_le_bytes::<32>());
+        let mask = (BigUint::from(1u64) << 254) - 1u64;
+        for a in [uint!(3_U256), M - uint!(7_U256), M >> 1, uint!(1_U256)] {
+            for b in [0u64, 1, 10, 100, 200, 253, 254, 1000] {
+                // a * 2^b truncated to 254 bits and reduced
+                let want = if b >= 254 {
+                    U256::ZERO
+                } else {
+                    let a = BigUint::from_bytes_le(&a.to_le_bytes::<32>());
+                    let mut r = (a << b) & &mask;
+                    if r >= m {
+                        r -= &m;
+                    }
+                    U256::from_le_slice(&r.to_bytes_le())
+                };
+                let b = U256::from(b);
+                assert_eq!(Operation::Shl.eval(a, b), want, "{} << {}", a, b);
+                assert_eq!(
+                    Operation::Shl.eval_fr(Fr::new(a.into()), Fr::new(b.into())),
+                    Fr::new(want.into()));
+
+                let nodes = vec![
+                    Node::Input(0), Node::Input(1), Node::Op(Operation::Shl, 0, 1)];
+                assert_eq!(evaluate(&nodes, &[a, b], &[2]), vec![want]);
+                assert_eq!(evaluate_reference(&nodes, &[a, b], &[2]), vec![want]);
+                let mut mont = nodes.clone();
+                montgomery_form(&mut mont);
+                assert_eq!(evaluate(&mont, &[a, b], &[2]), vec![want]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bitwise_reduce() {
+        // both operands are less than the modulus, but the result is equal
+        // to it and is reduced to 0
+        let a = uint!(1_U256) << 253;
+        let b = M - a;
+        for op in [Operation::Bor, Operation::Bxor] {
+            assert_eq!(op.eval(a, b), U256::ZERO);
+            assert_eq!(op.eval_fr(Fr::new(a.into()), Fr::new(b.into())), Fr::zero());
+        }
+        assert_eq!(Operation::Bor.eval(a, b - uint!(1_U256)), M - uint!(1_U256));
+    }
+
     #[test]
     fn test_div() {
         assert_eq!(
@@ -890,11 +1813,602 @@ mod tests {
         println!("x: {}", M);
     }
 
+    #[test]
+    fn test_optimize_deterministic() {
+        let build = || vec![
+            Node::Input(0),
+            Node::Input(1),
+            Node::Constant(uint!(3_U256)),
+            Node::Op(Operation::Mul, 0, 2),
+            Node::Op(Operation::Mul, 2, 0),
+            Node::Op(Operation::Lt, 3, 1),
+            Node::Op(Operation::Lt, 4, 1),
+            Node::Op(Operation::Add, 5, 6),
+        ];
+
+        let mut nodes_a = build();
+        let mut outputs_a = vec![7, 3, 4];
+        optimize(&mut nodes_a, &mut outputs_a, true);
+
+        let mut nodes_b = build();
+        let mut outputs_b = vec![7, 3, 4];
+        optimize(&mut nodes_b, &mut outputs_b, true);
+
+        assert_eq!(nodes_a, nodes_b);
+        assert_eq!(outputs_a, outputs_b);
+        // Mul is commutative, so the outputs 3 and 4 should be merged
+        assert_eq!(outputs_a[1], outputs_a[2]);
+    }
+
+    #[test]
+    fn test_constants() {
+        let mut nodes = vec![
+            Node::Input(0),                            // 0
+            Node::Constant(uint!(0_U256)),             // 1
+            Node::Constant(uint!(3_U256)),             // 2
+            Node::Op(Operation::Mul, 0, 1),            // 3: x * 0 = 0
+            Node::Op(Operation::Lt, 2, 3),             // 4: 3 < 0 = 0
+            Node::Op(Operation::Lt, 0, 2),             // 5: x < 3
+            Node::Op(Operation::Lor, 5, 2),            // 6: (x < 3) || 3 = 1
+            Node::TresOp(TresOperation::TernCond, 6, 2, 0), // 7: 1 ? 3 : x = 3
+            Node::TresOp(TresOperation::TernCond, 5, 4, 1), // 8: (x < 3) ? 0 : 0 = 0
+            Node::Op(Operation::Add, 0, 2),            // 9: x + 3
+        ];
+        constants(&mut nodes);
+
+        assert_eq!(nodes[3], Node::Constant(uint!(0_U256)));
+        assert_eq!(nodes[4], Node::Constant(uint!(0_U256)));
+        assert_eq!(nodes[5], Node::Op(Operation::Lt, 0, 2));
+        assert_eq!(nodes[6], Node::Constant(uint!(1_U256)));
+        assert_eq!(nodes[7], Node::Constant(uint!(3_U256)));
+        assert_eq!(nodes[8], Node::Constant(uint!(0_U256)));
+        assert_eq!(nodes[9], Node::Op(Operation::Add, 0, 2));
+    }
+
+    #[test]
+    fn test_constants_zero_divisor() {
+        let mut nodes = vec![
+            Node::Input(0),                          // 0
+            Node::Constant(uint!(0_U256)),           // 1
+            Node::Constant(uint!(7_U256)),           // 2
+            Node::Op(Operation::Div, 2, 1),          // 3: 7 / 0
+            Node::Op(Operation::Idiv, 2, 1),         // 4: 7 \ 0
+            Node::Op(Operation::Mod, 2, 1),          // 5: 7 % 0
+            Node::Op(Operation::Mod, 0, 1),          // 6: x % 0
+            Node::UnoOp(UnoOperation::Id, 2),        // 7
+            Node::UnoOp(UnoOperation::Id, 0),        // 8
+        ];
+        let outputs = vec![3, 4, 5, 6, 7, 8];
+        let inputs = [uint!(5_U256)];
+        let want = vec![
+            uint!(0_U256), uint!(0_U256), uint!(0_U256), uint!(0_U256),
+            uint!(7_U256), uint!(5_U256)];
+        assert_eq!(evaluate_reference(&nodes, &inputs, &outputs), want);
+
+        constants(&mut nodes);
+        for i in [3, 4, 5] {
+            assert_eq!(nodes[i], Node::Constant(uint!(0_U256)));
+        }
+        assert_eq!(nodes[7], Node::Constant(uint!(7_U256)));
+        assert_eq!(evaluate(&nodes, &inputs, &outputs), want);
+
+        montgomery_form(&mut nodes);
+        assert_eq!(evaluate(&nodes, &inputs, &outputs), want);
+
+        for op in [Operation::Div, Operation::Idiv, Operation::Mod] {
+            assert_eq!(op.eval(uint!(7_U256), U256::ZERO), U256::ZERO);
+            assert_eq!(op.eval_fr(Fr::from(7u64), Fr::zero()), Fr::zero());
+        }
+        assert_eq!(UnoOperation::Id.eval_fr(Fr::from(7u64)), Fr::from(7u64));
+    }
+
+    #[test]
+    fn test_simplify() {
+        let mut nodes = vec![
+            Node::Input(0),                                 // 0: x
+            Node::Input(1),                                 // 1: y
+            Node::Constant(uint!(0_U256)),                  // 2
+            Node::Constant(uint!(1_U256)),                  // 3
+            Node::UnoOp(UnoOperation::Id, 0),               // 4: x
+            Node::Op(Operation::Mul, 4, 3),                 // 5: x * 1 = x
+            Node::Op(Operation::Add, 2, 5),                 // 6: 0 + x = x
+            Node::UnoOp(UnoOperation::Neg, 6),              // 7: -x
+            Node::UnoOp(UnoOperation::Neg, 7),              // 8: -(-x) = x
+            Node::Op(Operation::Sub, 8, 0),                 // 9: x - x = 0
+            Node::Op(Operation::Mul, 1, 9),                 // 10: y * 0 = 0
+            Node::Op(Operation::Lt, 0, 1),                  // 11: x < y
+            Node::Op(Operation::Eq, 11, 3),                 // 12: (x < y) == 1
+            Node::Op(Operation::Neq, 11, 3),                // 13: (x < y) != 1
+            Node::TresOp(TresOperation::TernCond, 1, 8, 4), // 14: y ? x : x = x
+            Node::Op(Operation::Eq, 0, 3),                  // 15: x == 1
+            Node::TresOp(TresOperation::TernCond, 12, 3, 2), // 16: b ? 1 : 0 = b
+            Node::Op(Operation::Band, 3, 11),               // 17: 1 & (x < y)
+            Node::Op(Operation::Band, 0, 3),                // 18: x & 1
+        ];
+        let mut outputs = vec![5, 6, 8, 10, 12, 13, 14, 15, 16, 17, 18];
+        simplify(&mut nodes, &mut outputs);
+
+        assert_eq!(outputs, vec![0, 0, 0, 10, 11, 13, 0, 15, 11, 11, 18]);
+        assert_eq!(nodes[10], Node::Constant(uint!(0_U256)));
+        assert_eq!(nodes[13], Node::Op(Operation::Sub, 3, 11));
+        // x is not known to be boolean
+        assert_eq!(nodes[15], Node::Op(Operation::Eq, 0, 3));
+        assert_eq!(nodes[18], Node::Op(Operation::Band, 0, 3));
+
+        tree_shake(&mut nodes, &mut outputs);
+        assert!(!nodes.iter().any(|n| matches!(n, Node::UnoOp(UnoOperation::Id, _))));
+    }
+
+    #[test]
+    fn test_cse() {
+        let mut nodes = vec![
+            Node::Input(0),                     // 0: x
+            Node::Input(1),                     // 1: y
+            Node::Input(0),                     // 2: x
+            Node::Op(Operation::Add, 0, 1),     // 3: x + y
+            Node::Op(Operation::Add, 1, 2),     // 4: y + x
+            Node::Op(Operation::Sub, 0, 1),     // 5: x - y
+            Node::Op(Operation::Sub, 1, 0),     // 6: y - x
+            Node::Op(Operation::Mul, 3, 5),     // 7: (x + y) * (x - y)
+            Node::Op(Operation::Mul, 6, 4),     // 8: (y - x) * (y + x)
+            Node::Op(Operation::Mul, 5, 4),     // 9: (x - y) * (y + x)
+        ];
+        let mut outputs = vec![7, 8, 9];
+        cse(&mut nodes, &mut outputs);
+
+        assert_eq!(outputs, vec![7, 8, 7]);
+        assert_eq!(nodes[4], Node::Op(Operation::Add, 0, 1));
+        assert_eq!(nodes[6], Node::Op(Operation::Sub, 1, 0));
+        assert_eq!(nodes[8], Node::Op(Operation::Mul, 3, 6));
+
+        tree_shake(&mut nodes, &mut outputs);
+        assert_eq!(nodes.len(), 7);
+    }
+
+    #[test]
+    fn test_gadget_passes() {
+        let build = || vec![
+            Node::Input(0),                                   // 0
+            Node::Input(1),                                   // 1
+            Node::Constant(uint!(1_U256)),                    // 2
+            Node::Op(Operation::Mul, 1, 2),                   // 3: y * 1
+            Node::Gadget(Gadget::Sha256, vec![0, 1]),         // 4
+            Node::Gadget(Gadget::Sha256, vec![0, 3]),         // 5: same as 4
+            Node::Extract(4, 0),                              // 6
+            Node::Extract(5, 0),                              // 7: same as 6
+            Node::Extract(5, 200),                            // 8
+            Node::Gadget(Gadget::Sha256, vec![2, 2]),         // 9: constant
+            Node::Extract(9, 3),                              // 10
+            Node::Op(Operation::Add, 7, 10),                  // 11
+        ];
+        let outputs = vec![6, 8, 11];
+        let inputs = vec![uint!(1_U256), uint!(0_U256)];
+        let want = evaluate(&build(), &inputs, &outputs);
+        assert_eq!(
+            evaluate_reference(&build(), &inputs, &outputs),
+            want);
+
+        for level in [OptLevel::O1, OptLevel::O2] {
+            let mut nodes = build();
+            let mut outputs = outputs.clone();
+            PassManager::with_level(level, false)
+                .verify(true)
+                .run(&mut nodes, &mut outputs);
+            assert_eq!(evaluate(&nodes, &inputs, &outputs), want);
+            // the duplicate and the constant gadgets are removed
+            assert_eq!(
+                nodes.iter().filter(|n| matches!(n, Node::Gadget(..))).count(),
+                1, "{:?}", level);
+            let slots = allocate_slots(&nodes);
+            assert_eq!(
+                evaluate_with_slots(&nodes, &slots, &inputs, &outputs), want);
+            assert_eq!(
+                evaluate_batch(&nodes, std::slice::from_ref(&inputs), &outputs),
+                vec![want.clone()]);
+        }
+    }
+
+    #[test]
+    fn test_nodes_push_decomposition() {
+        // bits and 64-bit limbs of x and a shift not multiple of the limb
+        let mut ops = (0..254).map(|i| (i, 1)).collect::<Vec<(usize, usize)>>();
+        ops.extend((0..4).map(|i| (i * 64, 64)));
+        ops.push((3, 8));
+
+        let mut plain = vec![Node::Input(0)];
+        let mut plain_outputs = Vec::new();
+        for &(shift, limb_bits) in ops.iter() {
+            let mask = (U256::from(1) << limb_bits) - U256::from(1);
+            plain.push(Node::Constant(U256::from(shift)));
+            plain.push(Node::Op(Operation::Shr, 0, plain.len() - 1));
+            plain.push(Node::Constant(mask));
+            plain.push(Node::Op(Operation::Band, plain.len() - 2, plain.len() - 1));
+            plain_outputs.push(plain.len() - 1);
+        }
+
+        let build = |decompose: bool| {
+            let mut nodes = Nodes::new();
+            nodes.set_decompose(decompose);
+            let mut renumber = Vec::new();
+            for node in plain.iter() {
+                let mut node = node.clone();
+                node.map_operands(|a| renumber[a]);
+                renumber.push(nodes.push(node).0);
+            }
+            let outputs = plain_outputs.iter()
+                .map(|&i| renumber[i])
+                .collect::<Vec<usize>>();
+            assert_eq!(nodes.iter().collect::<Vec<Node>>(), nodes.to_vec());
+            (nodes.into_nodes(), outputs)
+        };
+
+        // disabled by default
+        let (nodes, outputs) = build(false);
+        assert_eq!(nodes, plain);
+        assert_eq!(outputs, plain_outputs);
+
+        let (nodes, outputs) = build(true);
+
+        let gadgets = nodes.iter()
+            .filter_map(|n| match n {
+                Node::Gadget(g, _) => Some(*g),
+                _ => None,
+            })
+            .collect::<Vec<Gadget>>();
+        assert_eq!(
+            gadgets,
+            vec![Gadget::Bits(254), Gadget::Limbs { limb_bits: 64, limbs_num: 4 }]);
+        assert!(matches!(nodes[outputs[3]], Node::Extract(_, 3)));
+        assert!(matches!(nodes[outputs[255]], Node::Extract(_, 1)));
+        assert!(matches!(nodes[*outputs.last().unwrap()], Node::Op(Operation::Band, ..)));
+
+        let inputs = vec![M - U256::from(12345)];
+        let want = evaluate_reference(&plain, &inputs, &plain_outputs);
+        assert_eq!(evaluate_reference(&nodes, &inputs, &outputs), want);
+        assert_eq!(evaluate(&nodes, &inputs, &outputs), want);
+    }
+
+    #[test]
+    fn test_pass_manager() {
+        let build = || vec![
+            Node::Input(0),                     // 0: x
+            Node::Input(1),                     // 1: y
+            Node::Constant(uint!(1_U256)),      // 2
+            Node::Op(Operation::Mul, 0, 2),     // 3: x * 1
+            Node::Op(Operation::Add, 3, 1),     // 4: x + y
+            Node::Op(Operation::Add, 1, 0),     // 5: y + x
+            Node::Op(Operation::Mul, 4, 5),     // 6
+            Node::Op(Operation::Lt, 0, 1),      // 7
+            Node::UnoOp(UnoOperation::Id, 7),   // 8
+        ];
+
+        for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2] {
+            let mut nodes = build();
+            let mut outputs = vec![6, 8];
+            let mut pm = PassManager::with_level(level, false);
+            let stats = pm.verify(true).run(&mut nodes, &mut outputs);
+            assert_eq!(stats.len(), pm.passes().len());
+            assert_eq!(stats.last().unwrap().pass, Pass::MontgomeryForm);
+
+            let inputs = [uint!(3_U256), uint!(5_U256)];
+            assert_eq!(
+                evaluate(&nodes, &inputs, &outputs),
+                vec![uint!(64_U256), uint!(1_U256)]);
+        }
+
+        // constant Pow is not folded at O0 and is evaluated in Montgomery
+        // form
+        for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2] {
+            let mut nodes = vec![
+                Node::Input(0),
+                Node::Constant(uint!(3_U256)),
+                Node::Constant(uint!(5_U256)),
+                Node::Op(Operation::Pow, 1, 2),
+                Node::Op(Operation::Pow, 0, 2),
+                Node::Op(Operation::Add, 3, 4),
+            ];
+            let mut outputs = vec![3, 5];
+            PassManager::with_level(level, false).run(&mut nodes, &mut outputs);
+            assert_eq!(
+                evaluate(&nodes, &[uint!(2_U256)], &outputs),
+                vec![uint!(243_U256), uint!(275_U256)]);
+        }
+
+        let mut nodes = build();
+        let mut outputs = vec![6, 8];
+        let stats = PassManager::with_level(OptLevel::O2, false)
+            .truncate(4)
+            .run(&mut nodes, &mut outputs);
+        assert_eq!(stats.len(), 4);
+        assert_eq!(stats[3].pass, Pass::Simplify);
+        assert!(matches!(nodes[0], Node::Input(_)));
+    }
+
+    #[test]
+    #[should_panic(expected = "output #0 changed")]
+    fn test_pass_manager_verify() {
+        let nodes = vec![
+            Node::Input(0),
+            Node::Input(1),
+            Node::Op(Operation::Sub, 0, 1),
+            Node::Op(Operation::Add, 0, 0),
+        ];
+        let mut outputs = vec![2];
+        let verifier = Verifier::new(&nodes, &outputs);
+        // replace x - y with x + x
+        outputs[0] = 3;
+        verifier.check(&Pass::TreeShake, &nodes, &outputs);
+    }
+
+    #[test]
+    fn test_evaluate_reference() {
+        let mut nodes = vec![
+            Node::Input(0),
+            Node::Input(1),
+            Node::Constant(uint!(2_U256)),
+            Node::Op(Operation::Div, 0, 2),
+            Node::Op(Operation::Idiv, 1, 2),
+            Node::Op(Operation::Lt, 3, 4),
+            Node::UnoOp(UnoOperation::Neg, 3),
+            Node::TresOp(TresOperation::TernCond, 5, 6, 4),
+            Node::Op(Operation::Mod, 0, 7),
+            Node::Constant(uint!(200_U256)),
+            Node::Op(Operation::Shl, 6, 9),
+            Node::Op(Operation::Shr, 10, 2),
+            Node::Op(Operation::Bxor, 10, 6),
+            Node::Op(Operation::Bor, 12, 1),
+        ];
+        let mut outputs = vec![3, 4, 5, 6, 7, 8, 10, 11, 12, 13];
+        let inputs = [uint!(3_U256), uint!(7_U256)];
+        let want = evaluate_reference(&nodes, &inputs, &outputs);
+        assert_eq!(evaluate(&nodes, &inputs, &outputs), want);
+
+        optimize(&mut nodes, &mut outputs, false);
+        assert_eq!(evaluate(&nodes, &inputs, &outputs), want);
+        assert_eq!(evaluate_reference(&nodes, &inputs, &outputs), want);
+    }
+
+    #[test]
+    fn test_nodes_to_const() {
+        let mut nodes = Nodes::new();
+        let x = nodes.push(Node::Input(0));
+        let mut c = nodes.push(Node::Constant(uint!(1_U256)));
+        // Deep chain of shared subexpressions: exponential without caching
+        // and overflows the stack if evaluated recursively.
+        for _ in 0..100_000 {
+            c = nodes.push(Node::Op(Operation::Add, c.0, c.0));
+        }
+        let y = nodes.push(Node::Op(Operation::Mul, c.0, x.0));
+        nodes.extend([Node::Constant(uint!(5_U256))]);
+        let z = nodes.push(Node::Op(Operation::Add, nodes.len() - 1, c.0));
+
+        let want = uint!(2_U256).pow_mod(U256::from(100_000), M);
+        assert_eq!(nodes.to_const(c).unwrap(), want);
+        assert_eq!(nodes.to_const(z).unwrap(), want.add_mod(uint!(5_U256), M));
+        assert!(matches!(nodes.to_const(y), Err(NodeConstErr::InputSignal)));
+        assert!(matches!(nodes.to_const(x), Err(NodeConstErr::InputSignal)));
+        assert!(matches!(
+            nodes.to_const(NodeIdx(nodes.len())), Err(NodeConstErr::EmptyNode(_))));
+    }
+
+    #[test]
+    fn test_reduce_pow() {
+        let big_exp = uint!(0x10000000000000000_U256);
+        let mut nodes = vec![
+            Node::Input(0),
+            Node::Constant(uint!(5_U256)),
+            Node::Constant(uint!(0_U256)),
+            Node::Constant(uint!(1_U256)),
+            Node::Constant(big_exp),
+            Node::Op(Operation::Pow, 0, 1),
+            Node::Op(Operation::Pow, 0, 2),
+            Node::Op(Operation::Pow, 0, 3),
+            Node::Op(Operation::Pow, 0, 4),
+            Node::Op(Operation::Pow, 1, 0),
+            Node::Op(Operation::Add, 5, 6),
+        ];
+        let mut outputs = vec![5, 6, 7, 8, 9, 10];
+        let inputs = [uint!(3_U256)];
+        let want = evaluate_reference(&nodes, &inputs, &outputs);
+
+        assert_eq!(reduce_pow(&mut nodes, &mut outputs), 3);
+        assert_eq!(
+            nodes.iter().filter(|n| matches!(n, Node::Op(Operation::Pow, ..))).count(),
+            2);
+        // x^5 = (x^2)^2 * x
+        assert_eq!(nodes[outputs[0]], Node::Op(Operation::Mul, outputs[0] - 1, 0));
+        assert_eq!(nodes[outputs[1]], Node::Constant(uint!(1_U256)));
+        assert_eq!(outputs[2], 0);
+        assert_eq!(evaluate_reference(&nodes, &inputs, &outputs), want);
+        assert_eq!(evaluate(&nodes, &inputs, &outputs), want);
+        assert_eq!(want[0], uint!(243_U256));
+    }
+
+    #[test]
+    fn test_pow_eval_fr() {
+        let mut rng = ChaCha8Rng::seed_from_u64(RANDOM_EVAL_SEED);
+        for _ in 0..20 {
+            let a = rng.gen::<U256>() % M;
+            let b = rng.gen::<U256>() % M;
+            assert_eq!(
+                Operation::Pow.eval_fr(Fr::new(a.into()), Fr::new(b.into())),
+                Fr::new(Operation::Pow.eval(a, b).into()));
+        }
+        assert_eq!(
+            Operation::Pow.eval_fr(Fr::zero(), Fr::zero()), Fr::one());
+        assert_eq!(
+            Operation::Pow.eval_fr(Fr::from(2u64), Fr::from(10u64)),
+            Fr::from(1024u64));
+    }
+
+    #[test]
+    fn test_gadget_values_release() {
+        let nodes = vec![
+            Node::Input(0),
+            Node::Gadget(Gadget::Bits(254), vec![0]),
+            Node::Extract(1, 0),
+            Node::Gadget(Gadget::Bits(254), vec![0]),  // not extracted
+            Node::Extract(1, 5),
+            Node::Op(Operation::Add, 2, 4),
+        ];
+        let mut gv = GadgetValues::from_nodes(&nodes);
+        gv.insert(1, vec![1, 2, 3, 4, 5, 6]);
+        gv.insert(3, vec![7]);
+        assert_eq!(gv.values.len(), 1);
+        assert_eq!(gv.get(1)[0], 1);
+        gv.extracted(2, 1);
+        assert_eq!(gv.get(1)[5], 6);
+        gv.extracted(4, 1);
+        assert!(gv.values.is_empty());
+
+        // the same result for all the evaluators releasing the gadget values
+        let inputs = [uint!(0x21_U256)];
+        let outputs = vec![2, 4, 5];
+        let want = vec![uint!(1_U256), uint!(1_U256), uint!(2_U256)];
+        assert_eq!(evaluate(&nodes, &inputs, &outputs), want);
+        assert_eq!(evaluate_subset(&nodes, &inputs, &outputs, &[0, 2]), vec![want[0], want[2]]);
+        assert_eq!(
+            evaluate_with_slots(&nodes, &allocate_slots(&nodes), &inputs, &outputs),
+            want);
+        assert_eq!(evaluate_batch(&nodes, &[inputs.to_vec()], &outputs), vec![want.clone()]);
+        let compact = CompactNodes::try_from(nodes.as_slice()).unwrap();
+        assert_eq!(compact.evaluate(&inputs, &outputs), want);
+    }
+
+    #[test]
+    fn test_allocate_slots() {
+        let mut nodes = vec![Node::Input(0), Node::Input(1)];
+        // long chain: every value is used by the next node only
+        for i in 0..100 {
+            let op = if i % 2 == 0 { Operation::Add } else { Operation::Mul };
+            let prev = nodes.len() - 1;
+            nodes.push(Node::Op(op, prev, 1));
+        }
+        // repeated operand and an unused node
+        let last = nodes.len() - 1;
+        nodes.push(Node::Op(Operation::Mul, last, last));
+        nodes.push(Node::Op(Operation::Lt, 0, 1));
+        nodes.push(Node::TresOp(TresOperation::TernCond, 0, last + 1, 1));
+        montgomery_form(&mut nodes);
+
+        let slots = allocate_slots(&nodes);
+        let slots_num = slots.iter().max().unwrap() + 1;
+        assert!(slots_num <= 4, "slots_num: {}", slots_num);
+
+        let outputs = vec![2, 50, last, last + 1, last + 2, last + 3, 50];
+        let inputs = [uint!(3_U256), uint!(5_U256)];
+        assert_eq!(
+            evaluate_with_slots(&nodes, &slots, &inputs, &outputs),
+            evaluate(&nodes, &inputs, &outputs));
+    }
+
+    #[test]
+    fn test_evaluate_batch() {
+        let mut nodes = vec![
+            Node::Input(0),
+            Node::Input(1),
+            Node::Constant(uint!(7_U256)),
+            Node::Op(Operation::Mul, 0, 1),
+            Node::Op(Operation::Add, 3, 2),
+            Node::Op(Operation::Lt, 4, 1),
+            Node::UnoOp(UnoOperation::Neg, 5),
+            Node::TresOp(TresOperation::TernCond, 5, 0, 6),
+        ];
+        montgomery_form(&mut nodes);
+        let outputs = vec![7, 4, 0, 6, 4];
+        let inputs = (0..10_u64)
+            .map(|i| vec![U256::from(i * 3), U256::from(100 - i * i)])
+            .collect::<Vec<Vec<U256>>>();
+
+        let got = evaluate_batch(&nodes, &inputs, &outputs);
+        assert_eq!(got.len(), inputs.len());
+        for (inputs, got) in inputs.iter().zip(got.iter()) {
+            assert_eq!(got, &evaluate(&nodes, inputs, &outputs));
+        }
+        assert!(evaluate_batch(&nodes, &[], &outputs).is_empty());
+    }
+
+    #[test]
+    fn test_evaluate_subset() {
+        let mut nodes = vec![
+            Node::Input(0),
+            Node::Input(1),
+            Node::Constant(uint!(7_U256)),
+            Node::Op(Operation::Mul, 0, 1),
+            Node::Op(Operation::Add, 3, 2),
+            Node::Gadget(Gadget::Bits(254), vec![4]),
+            Node::Extract(5, 1),
+            Node::Op(Operation::Div, 0, 1),
+            Node::Op(Operation::Sub, 6, 0),
+        ];
+        montgomery_form(&mut nodes);
+        let signals = vec![0, 8, 4, 7, 6];
+        let inputs = vec![U256::from(1), U256::from(6)];
+        let witness = evaluate(&nodes, &inputs, &signals);
+
+        assert_eq!(
+            evaluate_subset(&nodes, &inputs, &signals, &[1, 4]),
+            vec![witness[1], witness[4]]);
+        assert_eq!(evaluate_subset(&nodes, &inputs, &signals, &[2]), vec![uint!(13_U256)]);
+        assert!(evaluate_subset(&nodes, &inputs, &signals, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_evaluate_values() {
+        // Mixed chains of the field and integer operations give the same
+        // results as eval_fr on Montgomery form.
+        let mut rng = ChaCha8Rng::seed_from_u64(42);
+        let ops = [
+            Operation::Mul, Operation::Add, Operation::Sub, Operation::Eq,
+            Operation::Lt, Operation::Geq, Operation::Land, Operation::Idiv,
+            Operation::Mod, Operation::Shr, Operation::Band, Operation::Bor,
+            Operation::Bxor];
+        let mut nodes = (0..4).map(Node::Input).collect::<Vec<Node>>();
+        nodes.push(Node::MontConstant(Fr::from(3u64)));
+        nodes.push(Node::Constant(uint!(255_U256)));
+        nodes.push(Node::Op(Operation::Band, 0, 5));
+        nodes.push(Node::Op(Operation::Shl, 6, 4));
+        for _ in 0..2000 {
+            let a = rng.gen_range(0..nodes.len());
+            let b = rng.gen_range(0..nodes.len());
+            let node = match rng.gen_range(0..12) {
+                0 => Node::UnoOp(UnoOperation::Neg, a),
+                1 => Node::TresOp(TresOperation::TernCond, b, a, b),
+                _ => Node::Op(ops[rng.gen_range(0..ops.len())], a, b),
+            };
+            nodes.push(node);
+        }
+
+        let inputs = (0..4).map(|_| rng.gen::<U256>() % M).collect::<Vec<U256>>();
+        let mut want: Vec<Fr> = Vec::new();
+        for node in nodes.iter() {
+            let v = match *node {
+                Node::Input(i) => Fr::new(inputs[i].into()),
+                Node::Constant(c) => Fr::new(c.into()),
+                Node::MontConstant(c) => c,
+                Node::Op(op, a, b) => op.eval_fr(want[a], want[b]),
+                Node::UnoOp(op, a) => op.eval_fr(want[a]),
+                Node::TresOp(op, a, b, c) => op.eval_fr(want[a], want[b], want[c]),
+                _ => unreachable!(),
+            };
+            want.push(v);
+        }
+        let outputs = (0..nodes.len()).collect::<Vec<usize>>();
+        let want = want.iter().map(|&v| v.into()).collect::<Vec<U256>>();
+
+        assert_eq!(evaluate(&nodes, &inputs, &outputs), want);
+        let slots = allocate_slots(&nodes);
+        assert_eq!(evaluate_with_slots(&nodes, &slots, &inputs, &outputs), want);
+        assert_eq!(
+            evaluate_batch(&nodes, std::slice::from_ref(&inputs), &outputs),
+            vec![want]);
+    }
+
     #[test]
     fn test_2() {
         let nodes: Vec<Node> = vec![];
         // let node = nodes[0];
-        let node = nodes.get(0);
+        let node = nodes.first();
         println!("{:?}", node);
     }
 }
\ No newline at end of file
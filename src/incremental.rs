@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use ruint::aliases::U256;
+use crate::compact::{CompactNodes, OpCode};
+use crate::graph::{GadgetValues, Node, Value};
+use crate::{Error, InputSignalsInfo};
+
+/// Evaluator that keeps the values of all the nodes between the calls. When
+/// some inputs change, only the forward cone of the changed inputs (the nodes
+/// depending on them) is recalculated. The cone is found on each update from
+/// the users of the nodes, so the memory is linear in the graph size.
+pub struct IncrementalEvaluator {
+    nodes: CompactNodes,
+    outputs: Vec<usize>,
+    // users of each node in the compressed sparse row layout: the nodes
+    // using the node i are users[users_start[i]..users_start[i + 1]]
+    users_start: Vec<usize>,
+    users: Vec<u32>,
+    // Input nodes by the index in the inputs buffer.
+    input_nodes: HashMap<usize, Vec<usize>>,
+    // The last cone search the node was visited by, so that the marks are
+    // not cleared for each search.
+    visited: Vec<u32>,
+    stamp: u32,
+    inputs: Vec<U256>,
+    values: Vec<Value>,
+}
+
+impl IncrementalEvaluator {
+    /// Evaluate the whole graph on the inputs.
+    pub fn new(nodes: CompactNodes, outputs: Vec<usize>, inputs: Vec<U256>) -> Self {
+        let mut users_start = vec![0_usize; nodes.len() + 1];
+        let mut input_nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+        for i in 0..nodes.len() {
+            nodes.for_each_operand(i, |a| users_start[a + 1] += 1);
+            // only the input nodes are decoded, get allocates for gadgets
+            if nodes.opcode(i) == OpCode::Input {
+                if let Node::Input(n) = nodes.get(i) {
+                    input_nodes.entry(n).or_default().push(i);
+                }
+            }
+        }
+        for i in 0..nodes.len() {
+            users_start[i + 1] += users_start[i];
+        }
+        let mut next = users_start.clone();
+        let mut users = vec![0_u32; users_start[nodes.len()]];
+        for i in 0..nodes.len() {
+            nodes.for_each_operand(i, |a| {
+                // CompactNodes indexes fit into u32
+                users[next[a]] = i as u32;
+                next[a] += 1;
+            });
+        }
+
+        let mut values = Vec::with_capacity(nodes.len());
+        let mut gadget_values = GadgetValues::new(nodes.extracts());
+        for i in 0..nodes.len() {
+            let v = nodes.eval_step(
+                i, &inputs, |a| values[a as usize], &mut gadget_values);
+            values.push(v);
+        }
+
+        IncrementalEvaluator {
+            visited: vec![0; nodes.len()],
+            stamp: 0,
+            nodes,
+            outputs,
+            users_start,
+            users,
+            input_nodes,
+            inputs,
+            values,
+        }
+    }
+
+    pub fn inputs(&self) -> &[U256] {
+        &self.inputs
+    }
+
+    /// Outputs for the current inputs.
+    pub fn outputs(&self) -> Vec<U256> {
+        self.outputs.iter().map(|&i| self.values[i].int()).collect()
+    }
+
+    /// Number of nodes depending on the input.
+    pub fn cone_size(&mut self, input: usize) -> usize {
+        self.cone(&[input]).len()
+    }
+
+    /// Set the inputs by their indexes in the inputs buffer, recalculate the
+    /// nodes depending on them and return the updated outputs. Returns an
+    /// error if an index is out of the inputs buffer, the inputs are not
+    /// changed then.
+    pub fn update(&mut self, changed: &[(usize, U256)]) -> Result<Vec<U256>, Error> {
+        if let Some(&(i, _)) = changed.iter().find(|(i, _)| *i >= self.inputs.len()) {
+            return Err(Error::InputsUnmarshal(format!(
+                "input {} is out of the inputs buffer of size {}", i,
+                self.inputs.len())));
+        }
+
+        let mut changed_inputs: Vec<usize> = Vec::new();
+        for &(i, v) in changed.iter() {
+            if self.inputs[i] != v {
+                self.inputs[i] = v;
+                changed_inputs.push(i);
+            }
+        }
+
+        let to_eval = self.cone(&changed_inputs);
+        // A gadget and its extracts are either both in the cone or not, so
+        // the gadget results are needed during the update only.
+        let mut gadget_values = GadgetValues::new(to_eval.iter()
+            .filter_map(|&i| self.nodes.extract_gadget(i).map(|g| (i, g))));
+        for i in to_eval {
+            let v = self.nodes.eval_step(
+                i, &self.inputs, |a| self.values[a as usize], &mut gadget_values);
+            self.values[i] = v;
+        }
+
+        Ok(self.outputs())
+    }
+
+    /// Set the input signals by name, as in the inputs JSON, and return the
+    /// updated outputs. See [IncrementalEvaluator::update]. Returns an error
+    /// if a signal is unknown or has the wrong length.
+    pub fn update_signals(
+        &mut self, signals: &HashMap<String, Vec<U256>>,
+        input_mapping: &InputSignalsInfo) -> Result<Vec<U256>, Error> {
+
+        let mut changed: Vec<(usize, U256)> = Vec::new();
+        for (name, values) in signals.iter() {
+            let &(offset, len) = input_mapping.get(name).ok_or_else(
+                || Error::InputsUnmarshal(format!("unknown input signal {}", name)))?;
+            if len != values.len() {
+                return Err(Error::InputsUnmarshal(format!(
+                    "input signal {} has {} values, {} given", name, len,
+                    values.len())));
+            }
+            changed.extend(values.iter().enumerate().map(|(i, &v)| (offset + i, v)));
+        }
+        self.update(&changed)
+    }
+
+    // The union of the forward cones of the inputs, sorted in the order of
+    // evaluation.
+    fn cone(&mut self, inputs: &[usize]) -> Vec<usize> {
+        if self.stamp == u32::MAX {
+            self.visited.fill(0);
+            self.stamp = 0;
+        }
+        self.stamp += 1;
+
+        let mut cone = Vec::new();
+        let mut stack = inputs.iter()
+            .filter_map(|i| self.input_nodes.get(i))
+            .flatten()
+            .copied()
+            .collect::<Vec<usize>>();
+        while let Some(j) = stack.pop() {
+            if self.visited[j] == self.stamp {
+                continue;
+            }
+            self.visited[j] = self.stamp;
+            cone.push(j);
+            stack.extend(self.users[self.users_start[j]..self.users_start[j + 1]]
+                .iter()
+                .map(|&u| u as usize));
+        }
+        cone.sort_unstable();
+        cone
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::field::M;
+    use crate::gadgets::Gadget;
+    use crate::graph::{evaluate, Operation, TresOperation, UnoOperation};
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn test_incremental_evaluator() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let ops = [
+            Operation::Mul, Operation::Add, Operation::Sub, Operation::Lt,
+            Operation::Band, Operation::Shr];
+
+        let mut nodes = (0..6).map(Node::Input).collect::<Vec<Node>>();
+        // input 5 is used by nothing but itself
+        for _ in 0..3000 {
+            let a = rng.gen_range(0..nodes.len());
+            let b = rng.gen_range(0..nodes.len());
+            let (a, b) = (if a == 5 { 0 } else { a }, if b == 5 { 1 } else { b });
+            let node = match rng.gen_range(0..10) {
+                0 => Node::UnoOp(UnoOperation::Neg, a),
+                1 => Node::TresOp(TresOperation::TernCond, b, a, b),
+                _ => Node::Op(ops[rng.gen_range(0..ops.len())], a, b),
+            };
+            nodes.push(node);
+        }
+        nodes.push(Node::Gadget(Gadget::Bits(254), vec![nodes.len() - 1]));
+        nodes.push(Node::Extract(nodes.len() - 1, 7));
+        // node depending on input 4 only
+        nodes.push(Node::Op(Operation::Mul, 4, 4));
+
+        let outputs = (0..nodes.len()).step_by(3).chain([nodes.len() - 2])
+            .collect::<Vec<usize>>();
+        let mut inputs = (0..6).map(|_| rng.gen::<U256>() % M).collect::<Vec<U256>>();
+        let mut evaluator = IncrementalEvaluator::new(
+            CompactNodes::try_from(nodes.as_slice()).unwrap(), outputs.clone(),
+            inputs.clone());
+        assert_eq!(evaluator.outputs(), evaluate(&nodes, &inputs, &outputs));
+        assert_eq!(evaluator.cone_size(5), 1);
+        assert!(evaluator.cone_size(4) >= 2);
+        assert!(evaluator.cone_size(0) < nodes.len());
+
+        for step in 0..20 {
+            let changed = if step % 5 == 0 {
+                vec![(1, rng.gen::<U256>() % M), (3, U256::from(step))]
+            } else {
+                vec![(rng.gen_range(0..6), rng.gen::<U256>() % M)]
+            };
+            for &(i, v) in changed.iter() {
+                inputs[i] = v;
+            }
+            assert_eq!(
+                evaluator.update(&changed).unwrap(), evaluate(&nodes, &inputs, &outputs),
+                "step {}", step);
+        }
+        assert_eq!(evaluator.inputs(), inputs.as_slice());
+
+        let mut input_mapping: InputSignalsInfo = HashMap::new();
+        input_mapping.insert("a".to_string(), (2, 2));
+        let signals = HashMap::from([
+            ("a".to_string(), vec![U256::from(5), U256::from(6)])]);
+        inputs[2] = U256::from(5);
+        inputs[3] = U256::from(6);
+        assert_eq!(
+            evaluator.update_signals(&signals, &input_mapping).unwrap(),
+            evaluate(&nodes, &inputs, &outputs));
+
+        // errors do not change the inputs
+        let bad_signals = [
+            HashMap::from([("b".to_string(), vec![U256::from(1)])]),
+            HashMap::from([("a".to_string(), vec![U256::from(1)])]),
+        ];
+        for signals in bad_signals.iter() {
+            assert!(matches!(
+                evaluator.update_signals(signals, &input_mapping),
+                Err(Error::InputsUnmarshal(_))));
+        }
+        assert!(matches!(
+            evaluator.update(&[(0, U256::from(1)), (6, U256::from(1))]),
+            Err(Error::InputsUnmarshal(_))));
+        assert_eq!(evaluator.inputs(), inputs.as_slice());
+        assert_eq!(evaluator.outputs(), evaluate(&nodes, &inputs, &outputs));
+    }
+}
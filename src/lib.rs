@@ -3,18 +3,31 @@
 #![allow(non_snake_case)]
 // #[allow(dead_code)]
 mod field;
+pub mod gadgets;
 pub mod graph;
+pub mod compact;
 pub mod storage;
+pub mod symbols;
+pub mod rust_codegen;
+pub mod c_codegen;
+pub mod incremental;
+pub mod specialize;
+#[cfg(feature = "parallel")]
+pub mod parallel;
+#[cfg(test)]
+mod test_graphs;
 
 use std::collections::HashMap;
 use std::ffi::{c_void, c_char, c_int, CStr};
 use std::slice::from_raw_parts;
+use std::sync::OnceLock;
 use ruint::aliases::U256;
 use ruint::ParseError;
-use crate::graph::Node;
+use serde::ser::{Serialize, SerializeMap, Serializer};
 use wtns_file::FieldElement;
 use crate::field::M;
-use crate::storage::deserialize_witnesscalc_graph;
+use crate::compact::CompactNodes;
+use crate::storage::{deserialize_witnesscalc_graph_compact, node_slots_from_metadata, signals_from_metadata};
 
 pub type InputSignalsInfo = HashMap<String, (usize, usize)>;
 
@@ -32,7 +45,7 @@ fn prepare_status(status: *mut gw_status_t, code: GW_ERROR_CODE, error_msg: &str
             (*status).code = code;
             (*status).error_msg = libc::malloc(bs.len()+1) as *mut c_char;
             libc::memcpy((*status).error_msg as *mut c_void, bs.as_ptr() as *mut c_void, bs.len());
-            *((*status).error_msg.offset(bs.len() as isize)) = 0;
+            *((*status).error_msg.add(bs.len())) = 0;
         }
     }
 }
@@ -95,7 +108,7 @@ pub unsafe extern "C" fn gw_calc_witness(
 
     unsafe {
         *wtns_len = witness_data.len();
-        *wtns_data = libc::malloc(witness_data.len()) as *mut c_void;
+        *wtns_data = libc::malloc(witness_data.len());
         if (*wtns_data).is_null() {
             prepare_status(status, GW_ERROR_CODE_ERROR, "Failed to allocate memory for wtns_data");
             return 1;
@@ -107,12 +120,12 @@ pub unsafe extern "C" fn gw_calc_witness(
 
     println!("OK");
 
-    return 0;
+    0
 }
 
 // create a wtns file bytes from witness (array of field elements)
 pub fn wtns_from_witness(witness: Vec<U256>) -> Vec<u8> {
-    let vec_witness: Vec<FieldElement<32>> = witness.iter().map(|a| u256_to_field_element(a)).collect();
+    let vec_witness: Vec<FieldElement<32>> = witness.iter().map(u256_to_field_element).collect();
     let mut buf = Vec::new();
     let mut wtns_f = wtns_file::WtnsFile::from_vec(vec_witness, u256_to_field_element(&M));
     wtns_f.version = 2;
@@ -122,47 +135,201 @@ pub fn wtns_from_witness(witness: Vec<U256>) -> Vec<u8> {
     buf
 }
 
+/// Witness calculation graph loaded from the graph file. Deserialize it once
+/// to calculate witnesses for many inputs: the nodes, signals, evaluator
+/// slots and, with the `parallel` feature, the level schedule are prepared
+/// at load time.
+pub struct Graph {
+    nodes: CompactNodes,
+    metadata: proto::GraphMetadata,
+    signals: Vec<usize>,
+    input_mapping: InputSignalsInfo,
+    slots: Option<Vec<usize>>,
+    // Slots for the batch evaluation if the graph has no node slots,
+    // allocated on the first batch.
+    batch_slots: OnceLock<Vec<usize>>,
+    #[cfg(feature = "parallel")]
+    schedule: parallel::LevelSchedule,
+}
+
+impl Graph {
+    pub fn deserialize(graph_data: &[u8]) -> Result<Graph, Error> {
+        let invalid = |e: std::io::Error| Error::InvalidGraph(e.to_string());
+        let (nodes, metadata) = deserialize_witnesscalc_graph_compact(
+            std::io::Cursor::new(graph_data)).map_err(invalid)?;
+        let (signals, input_mapping) =
+            signals_from_metadata(&metadata).map_err(invalid)?;
+        let slots = node_slots_from_metadata(&metadata, nodes.len())
+            .map_err(invalid)?;
+        #[cfg(feature = "parallel")]
+        let schedule = parallel::LevelSchedule::new(&nodes);
+        Ok(Graph {
+            nodes, metadata, signals, input_mapping, slots,
+            batch_slots: OnceLock::new(),
+            #[cfg(feature = "parallel")]
+            schedule,
+        })
+    }
+
+    pub fn metadata(&self) -> &proto::GraphMetadata {
+        &self.metadata
+    }
+
+    fn inputs_buffer(&self, inputs: &str) -> Result<Vec<U256>, Error> {
+        let inputs = deserialize_inputs(inputs.as_bytes())?;
+        let mut inputs_buffer = get_inputs_buffer(self.nodes.inputs_size());
+        populate_inputs(&inputs, &self.input_mapping, &mut inputs_buffer);
+        Ok(inputs_buffer)
+    }
+
+    pub fn calc_witness(&self, inputs: &str) -> Result<Vec<U256>, Error> {
+        let inputs_buffer = self.inputs_buffer(inputs)?;
+
+        // Level schedule reorders the nodes, so slots are used by the
+        // sequential evaluation only.
+        #[cfg(feature = "parallel")]
+        {
+            let threads = parallel::default_threads();
+            if threads > 1 {
+                return Ok(parallel::evaluate_parallel(
+                    &self.nodes, &self.schedule, &inputs_buffer, &self.signals,
+                    threads));
+            }
+        }
+
+        match self.slots {
+            Some(ref slots) => Ok(self.nodes.evaluate_with_slots(
+                slots, &inputs_buffer, &self.signals)),
+            None => Ok(self.nodes.evaluate(&inputs_buffer, &self.signals)),
+        }
+    }
+
+    /// Calculate witnesses for many input sets at once. See
+    /// [graph::evaluate_batch].
+    pub fn calc_witness_batch(&self, inputs: &[&str]) -> Result<Vec<Vec<U256>>, Error> {
+        let inputs_buffers = inputs.iter()
+            .map(|inputs| self.inputs_buffer(inputs))
+            .collect::<Result<Vec<Vec<U256>>, Error>>()?;
+        let slots = match self.slots {
+            Some(ref slots) => slots,
+            None => self.batch_slots.get_or_init(|| self.nodes.allocate_slots()),
+        };
+        Ok(self.nodes.evaluate_batch_with_slots(slots, &inputs_buffers, &self.signals))
+    }
+
+    /// Calculate only the public outputs of the circuit without the full
+    /// witness. Returns the outputs with the signal names from the symbol
+    /// table, or with the witness indexes as names if the graph was built
+    /// without symbols. Returns an error if the graph was built without the
+    /// public signals layout.
+    pub fn calc_outputs(&self, inputs: &str) -> Result<Vec<(String, U256)>, Error> {
+        let layout = self.metadata.public_signals.as_ref().ok_or_else(|| Error::InvalidGraph(
+            "graph has no public signals layout, rebuild it with the latest build-circuit".to_string()))?;
+        let n_pub_out = usize::try_from(layout.n_pub_out).ok()
+            .filter(|&n| n < self.signals.len())
+            .ok_or_else(|| Error::InvalidGraph(format!(
+                "{} public outputs do not fit the witness of length {}",
+                layout.n_pub_out, self.signals.len())))?;
+        // the first element of the witness is always the constant 1
+        let wanted = (1..1 + n_pub_out).collect::<Vec<usize>>();
+
+        let inputs_buffer = self.inputs_buffer(inputs)?;
+        let values = self.nodes.evaluate_subset(&inputs_buffer, &self.signals, &wanted);
+
+        let symbols = symbols::SymbolTable::from_metadata(&self.metadata);
+        Ok(wanted.iter().zip(values).map(|(&i, v)| {
+            let name = symbols.as_ref()
+                .and_then(|s| s.witness_name(i))
+                .map_or_else(|| i.to_string(), |n| n.to_string());
+            (name, v)
+        }).collect())
+    }
+}
+
 pub fn calc_witness(inputs: &str, graph_data: &[u8]) -> Result<Vec<U256>, Error> {
+    Graph::deserialize(graph_data)?.calc_witness(inputs)
+}
 
-    let inputs = deserialize_inputs(inputs.as_bytes())?;
+/// Calculate witnesses for many input sets with the same graph at once. See
+/// [graph::evaluate_batch].
+pub fn calc_witness_batch(
+    inputs: &[&str], graph_data: &[u8]) -> Result<Vec<Vec<U256>>, Error> {
 
-    let (nodes, signals, input_mapping): (Vec<Node>, Vec<usize>, InputSignalsInfo) =
-        deserialize_witnesscalc_graph(std::io::Cursor::new(graph_data)).unwrap();
+    Graph::deserialize(graph_data)?.calc_witness_batch(inputs)
+}
 
-    let mut inputs_buffer = get_inputs_buffer(get_inputs_size(&nodes));
-    populate_inputs(&inputs, &input_mapping, &mut inputs_buffer);
+/// Public signals of the witness in the same order as snarkjs writes them to
+/// the public.json file: public outputs followed by public inputs. Returns an
+/// error if the graph was built without the public signals layout or the
+/// layout does not fit the witness.
+pub fn public_signals<'a>(
+    witness: &'a [U256],
+    metadata: &proto::GraphMetadata) -> Result<&'a [U256], Error> {
+
+    let layout = metadata.public_signals.as_ref().ok_or_else(|| Error::InvalidGraph(
+        "graph has no public signals layout, rebuild it with the latest build-circuit".to_string()))?;
+    let n = layout.n_pub_out.checked_add(layout.n_pub_in)
+        .and_then(|n| usize::try_from(n).ok())
+        .ok_or_else(|| Error::InvalidGraph(format!(
+            "invalid public signals layout: {} outputs, {} inputs",
+            layout.n_pub_out, layout.n_pub_in)))?;
+    // the first element of the witness is always the constant 1
+    n.checked_add(1)
+        .and_then(|end| witness.get(1..end))
+        .ok_or_else(|| Error::InvalidGraph(format!(
+            "{} public signals do not fit the witness of length {}",
+            n, witness.len())))
+}
 
-    Ok(graph::evaluate(&nodes, inputs_buffer.as_slice(), &signals))
+/// Serialize public signals to JSON compatible with snarkjs public.json file.
+pub fn public_signals_json(public_signals: &[U256]) -> String {
+    let signals = public_signals.iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<String>>();
+    // We serialize a vector of strings, so we should not have any errors here.
+    serde_json::to_string_pretty(&signals).unwrap()
 }
 
-fn get_inputs_size(nodes: &Vec<Node>) -> usize {
-    let mut start = false;
-    let mut max_index = 0usize;
-    for &node in nodes.iter() {
-        if let Node::Input(i) = node {
-            if i > max_index {
-                max_index = i;
-            }
-            start = true
-        } else if start {
-            break;
+/// Calculate only the public outputs of the circuit without the full witness.
+/// See [Graph::calc_outputs].
+pub fn calc_outputs(
+    inputs: &str, graph_data: &[u8]) -> Result<Vec<(String, U256)>, Error> {
+
+    Graph::deserialize(graph_data)?.calc_outputs(inputs)
+}
+
+/// Serialize named outputs to JSON object with decimal string values. The
+/// keys are in the order of the outputs, i.e. in the witness order.
+pub fn outputs_json(outputs: &[(String, U256)]) -> String {
+    // We serialize a map of strings, so we should not have any errors here.
+    serde_json::to_string_pretty(&OrderedOutputs(outputs)).unwrap()
+}
+
+// serde_json::Map sorts the keys, serialize the outputs as the map directly.
+struct OrderedOutputs<'a>(&'a [(String, U256)]);
+
+impl Serialize for OrderedOutputs<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (name, v) in self.0.iter() {
+            map.serialize_entry(name, &v.to_string())?;
         }
+        map.end()
     }
-    max_index + 1
 }
 
 fn populate_inputs(
     input_list: &HashMap<String, Vec<U256>>, inputs_info: &InputSignalsInfo,
-    input_buffer: &mut Vec<U256>) {
+    input_buffer: &mut [U256]) {
     for (key, value) in input_list {
         let (offset, len) = inputs_info[key];
         if len != value.len() {
             panic!("Invalid input length for {}", key);
         }
-        println!("input {}, offset {}, len {}", key, offset, len);
+        eprintln!("input {}, offset {}, len {}", key, offset, len);
 
         for (i, v) in value.iter().enumerate() {
-            input_buffer[offset + i] = v.clone();
+            input_buffer[offset + i] = *v;
         }
     }
 }
@@ -183,7 +350,20 @@ fn get_inputs_buffer(size: usize) -> Vec<U256> {
 #[derive(Debug)]
 pub enum Error {
     InputsUnmarshal(String),
-    InputFieldNumberParseError(ParseError)
+    InputFieldNumberParseError(ParseError),
+    InvalidGraph(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InputsUnmarshal(msg) => write!(f, "invalid inputs: {}", msg),
+            Error::InputFieldNumberParseError(e) => {
+                write!(f, "invalid input field number: {}", e)
+            }
+            Error::InvalidGraph(msg) => write!(f, "invalid graph: {}", msg),
+        }
+    }
 }
 
 impl From<ParseError> for Error {
@@ -279,6 +459,187 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_public_signals() {
+        let witness = vec![
+            uint!(1_U256), uint!(10_U256), uint!(20_U256), uint!(30_U256),
+            uint!(40_U256)];
+
+        let mut md = crate::proto::GraphMetadata::default();
+        assert!(matches!(
+            super::public_signals(&witness, &md), Err(super::Error::InvalidGraph(_))));
+
+        md.public_signals = Some(crate::proto::PublicSignals {
+            n_pub_out: 1, n_pub_in: 2, n_prv_in: 1 });
+        let public = super::public_signals(&witness, &md).unwrap();
+        assert_eq!(public, &[uint!(10_U256), uint!(20_U256), uint!(30_U256)]);
+
+        // corrupt layouts are errors, not panics
+        for (n_pub_out, n_pub_in) in [(u64::MAX, 1), (u64::MAX, 0), (2, 3)] {
+            md.public_signals = Some(crate::proto::PublicSignals {
+                n_pub_out, n_pub_in, n_prv_in: 0 });
+            assert!(matches!(
+                super::public_signals(&witness, &md),
+                Err(super::Error::InvalidGraph(_))));
+        }
+
+        assert_eq!(
+            super::public_signals_json(public),
+            "[\n  \"10\",\n  \"20\",\n  \"30\"\n]");
+    }
+
+    #[test]
+    fn test_calc_witness_batch() {
+        use crate::graph::{Node, Operation};
+        use ark_bn254::Fr;
+
+        let nodes = vec![
+            Node::Input(0),
+            Node::Input(1),
+            Node::Input(2),
+            Node::MontConstant(Fr::from(5u64)),
+            Node::Op(Operation::Mul, 1, 2),
+            Node::Op(Operation::Add, 4, 3),
+        ];
+        let mut input_signals: crate::InputSignalsInfo = HashMap::new();
+        input_signals.insert("a".to_string(), (1, 2));
+        let mut graph_data = Vec::new();
+        crate::storage::serialize_witnesscalc_graph(
+            &mut graph_data, &nodes, &[0, 5, 1], &input_signals).unwrap();
+
+        let inputs = [r#"{"a": ["2", "3"]}"#, r#"{"a": ["4", "5"]}"#];
+        let witnesses = super::calc_witness_batch(&inputs, &graph_data).unwrap();
+        assert_eq!(witnesses, vec![
+            vec![uint!(1_U256), uint!(11_U256), uint!(2_U256)],
+            vec![uint!(1_U256), uint!(25_U256), uint!(4_U256)],
+        ]);
+        for (inputs, witness) in inputs.iter().zip(witnesses.iter()) {
+            assert_eq!(&super::calc_witness(inputs, &graph_data).unwrap(), witness);
+        }
+    }
+
+    #[test]
+    fn test_graph_reuse() {
+        use ark_bn254::Fr;
+        use crate::graph::{allocate_slots, Node, Operation};
+
+        let nodes = vec![
+            Node::Input(0),
+            Node::Input(1),
+            Node::Input(2),
+            Node::MontConstant(Fr::from(5u64)),
+            Node::Op(Operation::Mul, 1, 2),
+            Node::Op(Operation::Add, 4, 3),
+            Node::Op(Operation::Mul, 5, 4),
+        ];
+        let mut input_signals: crate::InputSignalsInfo = HashMap::new();
+        input_signals.insert("a".to_string(), (1, 2));
+        let mut md = crate::storage::graph_metadata(&[0, 6, 5, 1, 2], &input_signals);
+        md.node_slots = allocate_slots(&nodes).iter().map(|&s| s as u64).collect();
+        let mut graph_data = Vec::new();
+        crate::storage::serialize_witnesscalc_graph_with_metadata(
+            &mut graph_data, &nodes, &md).unwrap();
+
+        let graph = super::Graph::deserialize(&graph_data).unwrap();
+        assert_eq!(graph.metadata(), &md);
+        for (a, b) in [(2u64, 3u64), (4, 5), (0, 7)] {
+            let inputs = format!(r#"{{"a": ["{}", "{}"]}}"#, a, b);
+            let p = U256::from(a * b);
+            assert_eq!(
+                graph.calc_witness(&inputs).unwrap(),
+                vec![uint!(1_U256), (p + uint!(5_U256)) * p, p + uint!(5_U256),
+                     U256::from(a), U256::from(b)]);
+        }
+        // batches use the node slots of the graph
+        let inputs = [r#"{"a": ["2", "3"]}"#, r#"{"a": ["0", "7"]}"#];
+        let want = inputs.iter()
+            .map(|inputs| graph.calc_witness(inputs).unwrap())
+            .collect::<Vec<Vec<U256>>>();
+        assert_eq!(graph.calc_witness_batch(&inputs).unwrap(), want);
+        assert_eq!(graph.calc_witness_batch(&inputs).unwrap(), want);
+
+        assert!(matches!(
+            super::Graph::deserialize(&graph_data[..graph_data.len() / 2]),
+            Err(super::Error::InvalidGraph(_))));
+    }
+
+    #[test]
+    fn test_calc_outputs() {
+        use crate::graph::{Node, Operation};
+        use crate::proto::{PublicSignals, SignalSymbol};
+
+        let nodes = vec![
+            Node::Input(0),
+            Node::Input(1),
+            Node::Input(2),
+            Node::Op(Operation::Mul, 1, 2),
+            Node::Op(Operation::Add, 1, 2),
+        ];
+        let mut input_signals: crate::InputSignalsInfo = HashMap::new();
+        input_signals.insert("a".to_string(), (1, 2));
+        let mut md = crate::storage::graph_metadata(&[0, 3, 4, 1, 2], &input_signals);
+        md.public_signals = Some(PublicSignals { n_pub_out: 2, n_pub_in: 0, n_prv_in: 2 });
+        let mut graph_data = Vec::new();
+        crate::storage::serialize_witnesscalc_graph_with_metadata(
+            &mut graph_data, &nodes, &md).unwrap();
+
+        let inputs = r#"{"a": ["2", "3"]}"#;
+        let outputs = super::calc_outputs(inputs, &graph_data).unwrap();
+        assert_eq!(outputs, vec![
+            ("1".to_string(), uint!(6_U256)), ("2".to_string(), uint!(5_U256))]);
+
+        md.symbols = vec![
+            SignalSymbol { witness_idx: 1, signal_idx: 1, component_idx: 0, name: "main.prod".to_string() },
+            SignalSymbol { witness_idx: 2, signal_idx: 2, component_idx: 0, name: "main.sum".to_string() },
+        ];
+        let mut graph_data = Vec::new();
+        crate::storage::serialize_witnesscalc_graph_with_metadata(
+            &mut graph_data, &nodes, &md).unwrap();
+        let outputs = super::calc_outputs(inputs, &graph_data).unwrap();
+        assert_eq!(
+            super::outputs_json(&outputs),
+            "{\n  \"main.prod\": \"6\",\n  \"main.sum\": \"5\"\n}");
+
+        md.public_signals = None;
+        let mut graph_data = Vec::new();
+        crate::storage::serialize_witnesscalc_graph_with_metadata(
+            &mut graph_data, &nodes, &md).unwrap();
+        assert!(matches!(
+            super::calc_outputs(inputs, &graph_data),
+            Err(super::Error::InvalidGraph(_))));
+    }
+
+    #[test]
+    fn test_outputs_json_order() {
+        use crate::graph::{Node, Operation};
+        use crate::proto::PublicSignals;
+
+        // 12 outputs x + i, the names are witness indexes 1..=12 which are
+        // not sorted as strings
+        let mut nodes = vec![Node::Input(0), Node::Input(1)];
+        let mut signals = vec![0];
+        for i in 0..12u64 {
+            nodes.push(Node::MontConstant(ark_bn254::Fr::from(i)));
+            nodes.push(Node::Op(Operation::Add, 1, nodes.len() - 1));
+            signals.push(nodes.len() - 1);
+        }
+        signals.push(1);
+        let mut input_signals: crate::InputSignalsInfo = HashMap::new();
+        input_signals.insert("x".to_string(), (1, 1));
+        let mut md = crate::storage::graph_metadata(&signals, &input_signals);
+        md.public_signals = Some(PublicSignals { n_pub_out: 12, n_pub_in: 0, n_prv_in: 1 });
+        let mut graph_data = Vec::new();
+        crate::storage::serialize_witnesscalc_graph_with_metadata(
+            &mut graph_data, &nodes, &md).unwrap();
+
+        let outputs = super::calc_outputs(r#"{"x": "100"}"#, &graph_data).unwrap();
+        let want = (1..=12)
+            .map(|i| format!("  \"{}\": \"{}\"", i, 99 + i))
+            .collect::<Vec<String>>()
+            .join(",\n");
+        assert_eq!(super::outputs_json(&outputs), format!("{{\n{}\n}}", want));
+    }
+
     #[test]
     fn test_ok2() {
         let i: InputNode = InputNode {
@@ -0,0 +1,279 @@
+use std::cell::UnsafeCell;
+use std::sync::Barrier;
+use std::thread;
+use ark_bn254::Fr;
+use ruint::aliases::U256;
+use crate::compact::{CompactNodes, OpCode};
+use crate::graph::Value;
+
+// Levels smaller than this number of nodes per thread are evaluated by one
+// worker, as synchronization of the threads costs more than the evaluation.
+const MIN_NODES_PER_THREAD: usize = 512;
+
+/// Nodes of the graph grouped by dependency levels. Inputs and constants are
+/// on level 0, every other node is one level above its deepest operand, so
+/// the nodes of the same level do not depend on each other and can be
+/// evaluated concurrently.
+pub struct LevelSchedule {
+    // node indexes ordered by level
+    order: Vec<usize>,
+    // order[level_starts[l]..level_starts[l+1]] are the nodes of level l
+    level_starts: Vec<usize>,
+}
+
+impl LevelSchedule {
+    pub fn new(nodes: &CompactNodes) -> Self {
+        let mut levels: Vec<usize> = Vec::with_capacity(nodes.len());
+        let mut levels_num = 0_usize;
+        for i in 0..nodes.len() {
+            let mut level = 0_usize;
+            nodes.for_each_operand(i, |a| level = level.max(levels[a] + 1));
+            levels.push(level);
+            levels_num = levels_num.max(level + 1);
+        }
+
+        // counting sort by level, keeping the original order inside the level
+        let mut level_starts = vec![0_usize; levels_num + 1];
+        for &l in levels.iter() {
+            level_starts[l + 1] += 1;
+        }
+        for l in 0..levels_num {
+            level_starts[l + 1] += level_starts[l];
+        }
+        let mut next = level_starts.clone();
+        let mut order = vec![0_usize; nodes.len()];
+        for (i, &l) in levels.iter().enumerate() {
+            order[next[l]] = i;
+            next[l] += 1;
+        }
+
+        LevelSchedule { order, level_starts }
+    }
+
+    pub fn levels_num(&self) -> usize {
+        self.level_starts.len() - 1
+    }
+
+    /// Indexes of the nodes on the level.
+    pub fn level(&self, l: usize) -> &[usize] {
+        &self.order[self.level_starts[l]..self.level_starts[l + 1]]
+    }
+}
+
+/// Number of threads for [evaluate_parallel] if 0 is requested: the available
+/// parallelism of the system.
+pub fn default_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+// Cell of the value shared by the workers without locking. The level
+// schedule makes the accesses disjoint: a node is written once by the worker
+// that evaluates it, and read only by the nodes of the levels above, after
+// the barrier that ends its level.
+struct SharedCell<T>(UnsafeCell<T>);
+
+unsafe impl<T: Send + Sync> Sync for SharedCell<T> {}
+
+impl<T> SharedCell<T> {
+    fn new(v: T) -> Self {
+        SharedCell(UnsafeCell::new(v))
+    }
+
+    // Safety: no thread writes the cell while the reference is alive.
+    unsafe fn get(&self) -> &T {
+        &*self.0.get()
+    }
+
+    // Safety: no other thread accesses the cell concurrently.
+    unsafe fn set(&self, v: T) {
+        *self.0.get() = v;
+    }
+}
+
+/// Evaluate the graph in multiple threads level by level. The result is the
+/// same as of [crate::graph::evaluate]. If threads is 0, the number of
+/// threads is [default_threads]. The worker threads are started once for the
+/// evaluation. Each worker evaluates its chunk of the level, gadgets
+/// included, and writes the values directly to the cells of its nodes, so
+/// the workers synchronize with a single barrier per level and take no locks.
+/// Small levels are evaluated by the first worker alone without
+/// synchronization.
+pub fn evaluate_parallel(
+    nodes: &CompactNodes, schedule: &LevelSchedule, inputs: &[U256],
+    outputs: &[usize], threads: usize) -> Vec<U256> {
+
+    let threads = if threads == 0 { default_threads() } else { threads };
+
+    let values = (0..nodes.len())
+        .map(|_| SharedCell::new(Value::default()))
+        .collect::<Vec<SharedCell<Value>>>();
+    // results by the index of the gadget in the gadgets pool
+    let gadget_values = (0..nodes.gadgets_num())
+        .map(|_| SharedCell::new(Vec::new()))
+        .collect::<Vec<SharedCell<Vec<Fr>>>>();
+    let barrier = Barrier::new(threads);
+
+    let levels_num = schedule.levels_num();
+    let chunk_size = |l: usize| {
+        schedule.level(l).len().div_ceil(threads).max(MIN_NODES_PER_THREAD)
+    };
+    let is_shared = |l: usize| schedule.level(l).len() > chunk_size(l);
+
+    let worker = |t: usize| {
+        // Gadgets evaluated by the worker on the previous levels and on the
+        // current one. All extracts of the gadget are on the level above it,
+        // so its results are released when that level is done.
+        let mut done_gadgets: Vec<usize> = Vec::new();
+        let mut level_gadgets: Vec<usize> = Vec::new();
+        for l in 0..levels_num {
+            let shared = is_shared(l);
+            if shared || t == 0 {
+                let level = schedule.level(l);
+                let chunk = if shared {
+                    level.chunks(chunk_size(l)).nth(t).unwrap_or(&[])
+                } else {
+                    level
+                };
+                for &i in chunk {
+                    // Safety: the operands are on the levels below, they are
+                    // not written any more, the node i is written by this
+                    // worker only.
+                    unsafe {
+                        let value = |a: u32| *values[a as usize].get();
+                        if nodes.opcode(i) == OpCode::Gadget {
+                            let g = nodes.gadget_idx(i);
+                            gadget_values[g].set(nodes.eval_gadget(i, value));
+                            level_gadgets.push(g);
+                        } else {
+                            let v = nodes.eval_node(
+                                i, inputs, value,
+                                |g, k| gadget_values[nodes.gadget_idx(g)].get()[k]);
+                            values[i].set(v);
+                        }
+                    }
+                }
+            }
+
+            // The values of the level must be visible to all workers before
+            // the next shared level.
+            let sync = shared || (l + 1 < levels_num && is_shared(l + 1));
+            if sync {
+                barrier.wait();
+            }
+            // After the barrier, or if the first worker evaluated the level
+            // alone, the extracts of the gadgets from the levels below are
+            // done.
+            if sync || t == 0 {
+                for g in done_gadgets.drain(..) {
+                    // Safety: the gadget is evaluated by this worker and its
+                    // extracts are done.
+                    unsafe { gadget_values[g].set(Vec::new()) };
+                }
+            }
+            done_gadgets.append(&mut level_gadgets);
+        }
+    };
+
+    thread::scope(|s| {
+        for t in 1..threads {
+            s.spawn(move || worker(t));
+        }
+        worker(0);
+    });
+
+    let mut values = values;
+    outputs.iter().map(|&i| values[i].0.get_mut().int()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{evaluate, Node, Operation, TresOperation, UnoOperation};
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn test_evaluate_parallel() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let ops = [
+            Operation::Mul, Operation::Add, Operation::Sub, Operation::Div,
+            Operation::Lt, Operation::Band, Operation::Shr];
+
+        let mut nodes = (0..8).map(Node::Input).collect::<Vec<Node>>();
+        for _ in 0..20_000 {
+            let a = rng.gen_range(0..nodes.len());
+            let b = rng.gen_range(0..nodes.len());
+            let node = match rng.gen_range(0..10) {
+                0 => Node::UnoOp(UnoOperation::Neg, a),
+                1 => Node::TresOp(TresOperation::TernCond, b, a, b),
+                _ => Node::Op(ops[rng.gen_range(0..ops.len())], a, b),
+            };
+            nodes.push(node);
+        }
+
+        let compact = CompactNodes::try_from(nodes.as_slice()).unwrap();
+        let schedule = LevelSchedule::new(&compact);
+        assert!(schedule.levels_num() > 1);
+        assert!((0..schedule.levels_num()).map(|l| schedule.level(l).len()).sum::<usize>() == nodes.len());
+
+        let inputs = (0..8).map(|_| rng.gen::<U256>() % crate::field::M)
+            .collect::<Vec<U256>>();
+        let outputs = (0..nodes.len()).step_by(7).collect::<Vec<usize>>();
+        let want = evaluate(&nodes, &inputs, &outputs);
+        for threads in [1, 3, 0] {
+            assert_eq!(
+                evaluate_parallel(&compact, &schedule, &inputs, &outputs, threads),
+                want);
+        }
+    }
+
+    #[test]
+    fn test_evaluate_parallel_gadgets() {
+        use crate::gadgets::Gadget;
+
+        let mut nodes = vec![Node::Input(0), Node::Input(1)];
+        for i in 0..2 {
+            nodes.push(Node::Gadget(Gadget::Bits(254), vec![i]));
+            let g = nodes.len() - 1;
+            for k in 0..8 {
+                nodes.push(Node::Extract(g, k));
+            }
+        }
+        nodes.push(Node::Op(Operation::Add, 3, 12));
+        let inputs = [U256::from(0x5a), U256::from(0xa5)];
+        let outputs = (0..nodes.len()).collect::<Vec<usize>>();
+        let compact = CompactNodes::try_from(nodes.as_slice()).unwrap();
+        let schedule = LevelSchedule::new(&compact);
+        assert_eq!(
+            evaluate_parallel(&compact, &schedule, &inputs, &outputs, 2),
+            evaluate(&nodes, &inputs, &outputs));
+
+        // levels of gadgets and extracts large enough to be shared by the
+        // workers
+        let n = 3 * MIN_NODES_PER_THREAD;
+        let mut nodes = (0..n).map(Node::Input).collect::<Vec<Node>>();
+        for i in 0..n {
+            nodes.push(Node::Gadget(Gadget::Bits(254), vec![i]));
+        }
+        for i in 0..n {
+            nodes.push(Node::Extract(n + i, i % 254));
+            nodes.push(Node::Extract(n + i, (i + 1) % 254));
+        }
+        for i in 0..n {
+            nodes.push(Node::Op(Operation::Add, 2 * n + 2 * i, 2 * n + 2 * i + 1));
+        }
+        let mut rng = StdRng::seed_from_u64(42);
+        let inputs = (0..n).map(|_| rng.gen::<U256>() % crate::field::M)
+            .collect::<Vec<U256>>();
+        let outputs = (0..nodes.len()).step_by(3).collect::<Vec<usize>>();
+        let compact = CompactNodes::try_from(nodes.as_slice()).unwrap();
+        let schedule = LevelSchedule::new(&compact);
+        assert_eq!(schedule.levels_num(), 4);
+        let want = evaluate(&nodes, &inputs, &outputs);
+        for threads in [2, 3] {
+            assert_eq!(
+                evaluate_parallel(&compact, &schedule, &inputs, &outputs, threads),
+                want);
+        }
+    }
+}
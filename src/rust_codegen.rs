@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use ruint::aliases::U256;
+use crate::field::M;
+use crate::gadgets::Gadget;
+use crate::graph::{Node, Operation, TresOperation, UnoOperation};
+use crate::InputSignalsInfo;
+
+const RUNTIME: &str = include_str!("../include/bn254_runtime.rs");
+
+// Number of nodes per generated function. rustc and LLVM slow down much
+// faster than linearly on huge functions.
+const CHUNK_NODES: usize = 1024;
+
+/// Generate the standalone Rust module that calculates the witness of the
+/// graph with straight-line code, one statement per node. The statements are
+/// split into functions of a fixed number of nodes that write the values to
+/// the shared array.
+///
+/// The module exports `fn witness(inputs: &[Fr]) -> Vec<Fr>`, where inputs
+/// are in the same layout as for [crate::graph::evaluate] (the first one is
+/// the constant 1), and constants with the input signals layout. It bundles
+/// the implementation of the operations and gadgets and depends only on
+/// `ark-bn254` and `ark-ff`. The result is the same as of
+/// [crate::graph::evaluate].
+pub fn graph_to_rust(
+    nodes: &[Node], witness_signals: &[usize],
+    input_signals: &InputSignalsInfo) -> String {
+
+    // Writing to a String never fails.
+    let mut w = String::new();
+    writeln!(w, "// Code generated by graph2rust. DO NOT EDIT.").unwrap();
+    writeln!(w).unwrap();
+    w.push_str(RUNTIME);
+    writeln!(w).unwrap();
+
+    let inputs_num = nodes.iter()
+        .filter_map(|n| match n {
+            Node::Input(i) => Some(*i + 1),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+    let mut inputs = input_signals.iter()
+        .map(|(name, (offset, len))| (name.as_str(), *offset, *len))
+        .collect::<Vec<(&str, usize, usize)>>();
+    inputs.sort_by_key(|(_, offset, _)| *offset);
+
+    writeln!(w, "/// Number of inputs, including the constant 1 at index 0.").unwrap();
+    writeln!(w, "pub const INPUTS_NUM: usize = {};", inputs_num).unwrap();
+    writeln!(w, "/// Number of the witness signals.").unwrap();
+    writeln!(w, "pub const WITNESS_NUM: usize = {};", witness_signals.len()).unwrap();
+    writeln!(w, "/// Input signals: name, offset in inputs and length.").unwrap();
+    writeln!(w, "pub const INPUT_SIGNALS: &[(&str, usize, usize)] = &[").unwrap();
+    for (name, offset, len) in inputs.iter() {
+        writeln!(w, "    ({:?}, {}, {}),", name, offset, len).unwrap();
+    }
+    writeln!(w, "];").unwrap();
+    writeln!(w).unwrap();
+
+    // Results of the gadget are written directly to the extract nodes.
+    let mut extracts: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+    for (i, node) in nodes.iter().enumerate() {
+        if let Node::Extract(g, k) = *node {
+            extracts.entry(g).or_default().push((i, k));
+        }
+    }
+
+    let chunks_num = nodes.len().div_ceil(CHUNK_NODES);
+    writeln!(w, "pub fn witness(inputs: &[Fr]) -> Vec<Fr> {{").unwrap();
+    writeln!(w, "    assert_eq!(inputs.len(), INPUTS_NUM);").unwrap();
+    writeln!(w, "    let mut v = vec![Fr::zero(); {}];", nodes.len()).unwrap();
+    for c in 0..chunks_num {
+        writeln!(w, "    calc_nodes_{}(inputs, &mut v);", c).unwrap();
+    }
+    let outputs = witness_signals.iter()
+        .map(|i| format!("v[{}]", i))
+        .collect::<Vec<String>>();
+    writeln!(w, "    vec![{}]", outputs.join(", ")).unwrap();
+    writeln!(w, "}}").unwrap();
+
+    for (c, chunk) in nodes.chunks(CHUNK_NODES).enumerate() {
+        let uses_inputs = chunk.iter().any(|n| matches!(n, Node::Input(_)));
+        writeln!(w).unwrap();
+        writeln!(
+            w, "fn calc_nodes_{}({}: &[Fr], v: &mut [Fr]) {{", c,
+            if uses_inputs { "inputs" } else { "_inputs" }).unwrap();
+        for (i, node) in chunk.iter().enumerate() {
+            let i = c * CHUNK_NODES + i;
+            let expr = match *node {
+                Node::Gadget(gadget, ref args) => {
+                    let args = args.iter()
+                        .map(|a| format!("v[{}]", a))
+                        .collect::<Vec<String>>();
+                    writeln!(
+                        w, "    let g: Vec<Fr> = {};",
+                        rust_gadget_call(gadget, &args.join(", "))).unwrap();
+                    for (e, k) in extracts.get(&i).map_or(&[][..], |v| v.as_slice()) {
+                        writeln!(w, "    v[{}] = g[{}];", e, k).unwrap();
+                    }
+                    continue;
+                }
+                Node::Extract(..) => continue,
+                Node::Input(n) => format!("inputs[{}]", n),
+                Node::Constant(c) => format!("MontFp!(\"{}\")", c % M),
+                Node::MontConstant(c) => format!("MontFp!(\"{}\")", Into::<U256>::into(c)),
+                Node::Op(op, a, b) => rust_op(op, a, b),
+                Node::UnoOp(UnoOperation::Neg, a) => format!("-v[{}]", a),
+                Node::UnoOp(UnoOperation::Id, a) => format!("v[{}]", a),
+                Node::TresOp(TresOperation::TernCond, a, b, c) => {
+                    format!("fr_tern_cond(v[{}], v[{}], v[{}])", a, b, c)
+                }
+            };
+            writeln!(w, "    v[{}] = {};", i, expr).unwrap();
+        }
+        writeln!(w, "}}").unwrap();
+    }
+
+    w
+}
+
+fn rust_gadget_call(gadget: Gadget, args: &str) -> String {
+    match gadget {
+        Gadget::Sha256 => format!("gadget_sha256(&[{}])", args),
+        Gadget::Bits(n) => format!("gadget_limbs({}, 1, {})", args, n),
+        Gadget::Limbs { limb_bits, limbs_num } => {
+            format!("gadget_limbs({}, {}, {})", args, limb_bits, limbs_num)
+        }
+        Gadget::BigMul { limb_bits, limbs_num } => {
+            format!("gadget_big_mul(&[{}], {}, {})", args, limb_bits, limbs_num)
+        }
+        Gadget::BigDivMod { limb_bits, limbs_num, extra_limbs } => {
+            format!(
+                "gadget_big_divmod(&[{}], {}, {}, {})", args, limb_bits,
+                limbs_num, extra_limbs)
+        }
+        Gadget::BigModExp { limb_bits, limbs_num } => {
+            format!("gadget_big_modexp(&[{}], {}, {})", args, limb_bits, limbs_num)
+        }
+        Gadget::BigModInv { limb_bits, limbs_num } => {
+            format!("gadget_big_modinv(&[{}], {}, {})", args, limb_bits, limbs_num)
+        }
+    }
+}
+
+// Field arithmetic is inlined, other operations call the runtime.
+fn rust_op(op: Operation, a: usize, b: usize) -> String {
+    let f = match op {
+        Operation::Mul => return format!("v[{}] * v[{}]", a, b),
+        Operation::Add => return format!("v[{}] + v[{}]", a, b),
+        Operation::Sub => return format!("v[{}] - v[{}]", a, b),
+        Operation::Div => "fr_div",
+        Operation::Pow => "fr_pow",
+        Operation::Idiv => "fr_idiv",
+        Operation::Mod => "fr_mod",
+        Operation::Eq => "fr_eq",
+        Operation::Neq => "fr_neq",
+        Operation::Lt => "fr_lt",
+        Operation::Gt => "fr_gt",
+        Operation::Leq => "fr_leq",
+        Operation::Geq => "fr_geq",
+        Operation::Land => "fr_land",
+        Operation::Lor => "fr_lor",
+        Operation::Shl => "fr_shl",
+        Operation::Shr => "fr_shr",
+        Operation::Bor => "fr_bor",
+        Operation::Band => "fr_band",
+        Operation::Bxor => "fr_bxor",
+    };
+    format!("{}(v[{}], v[{}])", f, a, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write as _;
+    use std::process::{Command, Stdio};
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use crate::graph::evaluate;
+
+    // Compiles the generated code with cargo, which needs the ark crates in
+    // the local registry. Run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_graph_to_rust() {
+        let (nodes, outputs, input_signals) = crate::test_graphs::all_operations();
+
+        // Random field elements, small values and small negative values.
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut random_value = || match rng.gen_range(0..3) {
+            0 => U256::from_limbs(rng.gen()) % M,
+            1 => U256::from(rng.gen_range(0u64..20)),
+            _ => M - U256::from(rng.gen_range(1u64..20)),
+        };
+        let inputs_sets = (0..8)
+            .map(|_| {
+                let mut inputs = vec![U256::from(1)];
+                inputs.extend((0..3).map(|_| random_value()));
+                inputs
+            })
+            .collect::<Vec<Vec<U256>>>();
+
+        let dir = std::env::temp_dir()
+            .join(format!("graph2rust_test_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("Cargo.toml"), r#"
+[package]
+name = "graph2rust_test"
+version = "0.1.0"
+edition = "2021"
+
+[dependencies]
+ark-bn254 = "0.4.0"
+ark-ff = "0.4.0"
+
+[workspace]
+"#).unwrap();
+        let code = graph_to_rust(&nodes, &outputs, &input_signals);
+        // the graph does not fit into one function
+        assert!(nodes.len() > CHUNK_NODES);
+        assert!(code.contains("calc_nodes_1(inputs, &mut v);"));
+        std::fs::write(dir.join("src/witness.rs"), code).unwrap();
+        std::fs::write(dir.join("src/main.rs"), r#"
+mod witness;
+
+use std::io::BufRead;
+use std::str::FromStr;
+use ark_bn254::Fr;
+use ark_ff::PrimeField;
+
+fn main() {
+    println!("{} {} {:?}", witness::INPUTS_NUM, witness::WITNESS_NUM, witness::INPUT_SIGNALS);
+    for line in std::io::stdin().lock().lines() {
+        let inputs = line.unwrap().split_whitespace()
+            .map(|x| Fr::from_str(x).unwrap())
+            .collect::<Vec<Fr>>();
+        let values = witness::witness(&inputs).iter()
+            .map(|v| v.into_bigint().to_string())
+            .collect::<Vec<String>>();
+        println!("{}", values.join(" "));
+    }
+}
+"#).unwrap();
+
+        let target = dir.join("target");
+        let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+        let compiled = Command::new(cargo)
+            .args(["build", "--offline", "--quiet", "--manifest-path"])
+            .arg(dir.join("Cargo.toml"))
+            .env("CARGO_TARGET_DIR", &target)
+            .status()
+            .expect("cargo is required for this test");
+        assert!(compiled.success(), "failed to compile generated Rust");
+
+        let mut child = Command::new(target.join("debug/graph2rust_test"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        {
+            let mut stdin = child.stdin.take().unwrap();
+            for inputs in inputs_sets.iter() {
+                let line = inputs.iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<String>>();
+                writeln!(stdin, "{}", line.join(" ")).unwrap();
+            }
+        }
+        let out = child.wait_with_output().unwrap();
+        assert!(out.status.success());
+        let out = String::from_utf8(out.stdout).unwrap();
+        let mut lines = out.lines();
+
+        assert_eq!(
+            lines.next().unwrap(),
+            format!(r#"4 {} [("a", 1, 2), ("b", 3, 1)]"#, outputs.len()));
+        for inputs in inputs_sets.iter() {
+            let want = evaluate(&nodes, inputs, &outputs);
+            let got = lines.next().unwrap().split(' ')
+                .map(|x| x.parse::<U256>().unwrap())
+                .collect::<Vec<U256>>();
+            assert_eq!(got.len(), want.len());
+            for (i, w) in want.iter().enumerate() {
+                assert_eq!(
+                    got[i], *w, "inputs {:?}, witness #{}, node {:?}", inputs, i,
+                    nodes[outputs[i]]);
+            }
+        }
+        assert_eq!(lines.next(), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
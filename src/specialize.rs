@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use ruint::aliases::U256;
+use crate::field::M;
+use crate::graph::{optimize, allocate_slots, Node, PassStats};
+use crate::proto::GraphMetadata;
+use crate::storage::{graph_metadata, signals_from_metadata};
+use crate::{Error, InputSignalsInfo};
+
+/// Bake the values of the fixed input signals into the graph. The inputs are
+/// replaced with constants and the graph is optimized again, so everything
+/// depending on the fixed inputs only is folded. The fixed signals are removed
+/// from `input_signals` and the rest of the inputs are packed in the inputs
+/// buffer without gaps. The witness keeps its layout, the fixed inputs become
+/// constant entries of it. Returns an error if a fixed input is unknown or
+/// has the wrong length, or if an input node is not in `input_signals`; the
+/// graph is not changed then.
+pub fn specialize(
+    nodes: &mut Vec<Node>, witness_signals: &mut [usize],
+    input_signals: &mut InputSignalsInfo,
+    fixed: &HashMap<String, Vec<U256>>) -> Result<Vec<PassStats>, Error> {
+
+    for (name, values) in fixed.iter() {
+        let &(_, len) = input_signals.get(name).ok_or_else(
+            || Error::InputsUnmarshal(format!("unknown input signal {}", name)))?;
+        if len != values.len() {
+            return Err(Error::InputsUnmarshal(format!(
+                "input signal {} has {} values, {} given", name, len,
+                values.len())));
+        }
+    }
+
+    // new position in the inputs buffer or the fixed value of the input
+    let mut inputs: HashMap<usize, Result<usize, U256>> = HashMap::new();
+    for (name, values) in fixed.iter() {
+        let (offset, _) = input_signals[name];
+        for (i, &v) in values.iter().enumerate() {
+            inputs.insert(offset + i, Err(v % M));
+        }
+        input_signals.remove(name);
+    }
+
+    let mut names = input_signals.keys().cloned().collect::<Vec<String>>();
+    names.sort_by_key(|name| input_signals[name].0);
+    // the first element of the inputs buffer is always the constant 1
+    let mut next_offset = 1;
+    for name in names.iter() {
+        let (offset, len) = input_signals[name];
+        for i in 0..len {
+            inputs.insert(offset + i, Ok(next_offset + i));
+        }
+        input_signals.insert(name.clone(), (next_offset, len));
+        next_offset += len;
+    }
+
+    if let Some(i) = nodes.iter().find_map(|n| match *n {
+        Node::Input(i) if i != 0 && !inputs.contains_key(&i) => Some(i),
+        _ => None,
+    }) {
+        return Err(Error::InvalidGraph(format!(
+            "input {} is not in the input signals", i)));
+    }
+
+    for node in nodes.iter_mut() {
+        match *node {
+            Node::Input(0) => (),
+            Node::Input(i) => {
+                *node = match inputs[&i] {
+                    Ok(i) => Node::Input(i),
+                    Err(v) => Node::Constant(v),
+                };
+            }
+            // Optimization passes work on the plain constants, they are
+            // converted to Montgomery form again at the end.
+            Node::MontConstant(c) => *node = Node::Constant(c.into()),
+            _ => (),
+        }
+    }
+
+    Ok(optimize(nodes, witness_signals, false))
+}
+
+/// The same as [specialize], but updates the graph metadata: witness signals,
+/// inputs and the evaluator slots if the graph had them.
+pub fn specialize_with_metadata(
+    nodes: &mut Vec<Node>, metadata: &mut GraphMetadata,
+    fixed: &HashMap<String, Vec<U256>>) -> Result<Vec<PassStats>, Error> {
+
+    let (mut witness_signals, mut input_signals) = signals_from_metadata(metadata)
+        .map_err(|e| Error::InvalidGraph(e.to_string()))?;
+
+    let stats = specialize(nodes, &mut witness_signals, &mut input_signals, fixed)?;
+
+    let md = graph_metadata(&witness_signals, &input_signals);
+    metadata.witness_signals = md.witness_signals;
+    metadata.inputs = md.inputs;
+    if !metadata.node_slots.is_empty() {
+        metadata.node_slots = allocate_slots(nodes).iter()
+            .map(|&s| s as u64)
+            .collect();
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ark_bn254::Fr;
+    use ruint::uint;
+    use crate::graph::{evaluate, evaluate_with_slots, montgomery_form, Operation};
+    use crate::storage::{node_slots_from_metadata, serialize_witnesscalc_graph_with_metadata};
+
+    #[test]
+    fn test_specialize() {
+        // inputs: a[2] at offset 1, b at offset 3, c at offset 4
+        let mut nodes = vec![
+            Node::Input(0),
+            Node::Input(1),
+            Node::Input(2),
+            Node::Input(3),
+            Node::Input(4),
+            Node::MontConstant(Fr::from(5u64)),
+            Node::Op(Operation::Mul, 1, 2),  // a[0] * a[1]
+            Node::Op(Operation::Add, 6, 5),  // a[0] * a[1] + 5
+            Node::Op(Operation::Mul, 7, 3),  // (a[0] * a[1] + 5) * b
+            Node::Op(Operation::Add, 8, 4),  // ... + c
+        ];
+        montgomery_form(&mut nodes);
+        let mut signals = vec![0, 9, 7, 1, 2, 3, 4];
+        let mut input_signals: InputSignalsInfo = HashMap::from([
+            ("a".to_string(), (1, 2)),
+            ("b".to_string(), (3, 1)),
+            ("c".to_string(), (4, 1)),
+        ]);
+        let fixed = HashMap::from([
+            ("a".to_string(), vec![uint!(2_U256), uint!(3_U256)])]);
+
+        let nodes_num = nodes.len();
+        let want = evaluate(&nodes, &[
+            uint!(1_U256), uint!(2_U256), uint!(3_U256), uint!(7_U256),
+            uint!(4_U256)], &signals);
+
+        specialize(&mut nodes, &mut signals, &mut input_signals, &fixed).unwrap();
+        assert!(nodes.len() < nodes_num);
+        assert_eq!(input_signals, HashMap::from([
+            ("b".to_string(), (1, 1)), ("c".to_string(), (2, 1))]));
+        assert_eq!(
+            evaluate(&nodes, &[uint!(1_U256), uint!(7_U256), uint!(4_U256)], &signals),
+            want);
+        assert!(!nodes.iter().any(|n| matches!(n, Node::Input(i) if *i > 2)));
+    }
+
+    #[test]
+    fn test_specialize_errors() {
+        let nodes = vec![
+            Node::Input(0),
+            Node::Input(1),
+            Node::Input(2),
+            Node::Op(Operation::Mul, 1, 2),
+        ];
+        let signals = vec![0, 3];
+        let input_signals: InputSignalsInfo = HashMap::from([
+            ("a".to_string(), (1, 1))]);
+        for (name, len) in [("b", 1), ("a", 2)] {
+            let fixed = HashMap::from([(name.to_string(), vec![uint!(3_U256); len])]);
+            let (mut nodes2, mut signals2, mut input_signals2) =
+                (nodes.clone(), signals.clone(), input_signals.clone());
+            assert!(matches!(
+                specialize(&mut nodes2, &mut signals2, &mut input_signals2, &fixed),
+                Err(Error::InputsUnmarshal(_))));
+            assert_eq!((nodes2, signals2, input_signals2),
+                       (nodes.clone(), signals.clone(), input_signals.clone()));
+        }
+
+        // input 2 is not in the input signals
+        let fixed = HashMap::from([("a".to_string(), vec![uint!(3_U256)])]);
+        let mut metadata = graph_metadata(&signals, &input_signals);
+        assert!(matches!(
+            specialize_with_metadata(&mut nodes.clone(), &mut metadata, &fixed),
+            Err(Error::InvalidGraph(_))));
+    }
+
+    #[test]
+    fn test_specialize_with_slots() {
+        // inputs: a at offset 1, b at offset 2
+        let mut nodes = vec![
+            Node::Input(0),
+            Node::Input(1),
+            Node::Input(2),
+            Node::Op(Operation::Mul, 1, 1),  // a * a
+            Node::Op(Operation::Add, 3, 2),  // a * a + b
+            Node::Op(Operation::Mul, 4, 2),  // (a * a + b) * b
+            Node::Op(Operation::Sub, 5, 1),  // ... - a
+        ];
+        montgomery_form(&mut nodes);
+        let signals = vec![0, 6, 4, 1, 2];
+        let input_signals: InputSignalsInfo = HashMap::from([
+            ("a".to_string(), (1, 1)), ("b".to_string(), (2, 1))]);
+        let mut metadata = graph_metadata(&signals, &input_signals);
+        metadata.node_slots = allocate_slots(&nodes).iter()
+            .map(|&s| s as u64)
+            .collect();
+        let want = evaluate(
+            &nodes, &[uint!(1_U256), uint!(3_U256), uint!(5_U256)], &signals);
+
+        let fixed = HashMap::from([("a".to_string(), vec![uint!(3_U256)])]);
+        specialize_with_metadata(&mut nodes, &mut metadata, &fixed).unwrap();
+
+        let slots = node_slots_from_metadata(&metadata, nodes.len())
+            .unwrap()
+            .unwrap();
+        let (signals, _) = signals_from_metadata(&metadata).unwrap();
+        let inputs = [uint!(1_U256), uint!(5_U256)];
+        assert_eq!(evaluate_with_slots(&nodes, &slots, &inputs, &signals), want);
+
+        let mut graph_data = Vec::new();
+        serialize_witnesscalc_graph_with_metadata(&mut graph_data, &nodes, &metadata)
+            .unwrap();
+        assert_eq!(crate::calc_witness(r#"{"b": "5"}"#, &graph_data).unwrap(), want);
+    }
+}
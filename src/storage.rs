@@ -1,14 +1,16 @@
-use std::io::{Write, Read};
+use std::collections::HashMap;
+use std::io::{Write, Read, Seek, SeekFrom};
 use ark_bn254::Fr;
 use ark_ff::{PrimeField};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use prost::Message;
+use crate::compact::CompactNodes;
 use crate::graph::{Operation, TresOperation, UnoOperation};
 use crate::InputSignalsInfo;
 
 // format of the wtns.graph file:
 // + magic line: wtns.graph.001
-// + 4 bytes unsigned LE 32-bit integer: number of nodes
+// + 8 bytes unsigned LE 64-bit integer: number of nodes
 // + series of protobuf serialized nodes. Each node prefixed by varint length
 // + protobuf serialized GraphMetadata
 // + 8 bytes unsigned LE 64-bit integer: offset of GraphMetadata message
@@ -17,33 +19,73 @@ const WITNESSCALC_GRAPH_MAGIC: &[u8] = b"wtns.graph.001";
 
 const MAX_VARINT_LENGTH: usize = 10;
 
-impl From<crate::proto::Node> for crate::graph::Node {
-    fn from(value: crate::proto::Node) -> Self {
-        match value.node.unwrap() {
+// Indexes are stored as 64-bit integers. On platforms with 32-bit usize
+// return an error instead of silent truncation of the index.
+fn idx_from_u64(v: u64) -> std::io::Result<usize> {
+    usize::try_from(v).map_err(|_| std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("index {} does not fit into usize", v)))
+}
+
+fn invalid_data(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+impl TryFrom<crate::proto::Node> for crate::graph::Node {
+    type Error = std::io::Error;
+
+    fn try_from(value: crate::proto::Node) -> std::io::Result<Self> {
+        let node = value.node
+            .ok_or_else(|| invalid_data("node is not set".to_string()))?;
+        let node = match node {
             crate::proto::node::Node::Input(input_node) => {
-                crate::graph::Node::Input(input_node.idx as usize)
+                crate::graph::Node::Input(idx_from_u64(input_node.idx)?)
             }
             crate::proto::node::Node::Constant(constant_node) => {
-                let i = constant_node.value.unwrap();
+                let i = constant_node.value.ok_or_else(
+                    || invalid_data("constant value is not set".to_string()))?;
                 crate::graph::Node::MontConstant(Fr::from_le_bytes_mod_order(i.value_le.as_slice()))
             }
             crate::proto::node::Node::UnoOp(uno_op_node) => {
-                let op = crate::proto::UnoOp::try_from(uno_op_node.op).unwrap();
-                crate::graph::Node::UnoOp(op.into(), uno_op_node.a_idx as usize)
+                let op = crate::proto::UnoOp::try_from(uno_op_node.op)
+                    .map_err(|e| invalid_data(e.to_string()))?;
+                crate::graph::Node::UnoOp(
+                    op.into(), idx_from_u64(uno_op_node.a_idx)?)
             }
             crate::proto::node::Node::DuoOp(duo_op_node) => {
-                let op = crate::proto::DuoOp::try_from(duo_op_node.op).unwrap();
+                let op = crate::proto::DuoOp::try_from(duo_op_node.op)
+                    .map_err(|e| invalid_data(e.to_string()))?;
                 crate::graph::Node::Op(
-                    op.into(), duo_op_node.a_idx as usize,
-                    duo_op_node.b_idx as usize)
+                    op.into(), idx_from_u64(duo_op_node.a_idx)?,
+                    idx_from_u64(duo_op_node.b_idx)?)
             }
             crate::proto::node::Node::TresOp(tres_op_node) => {
-                let op = crate::proto::TresOp::try_from(tres_op_node.op).unwrap();
+                let op = crate::proto::TresOp::try_from(tres_op_node.op)
+                    .map_err(|e| invalid_data(e.to_string()))?;
                 crate::graph::Node::TresOp(
-                    op.into(), tres_op_node.a_idx as usize,
-                    tres_op_node.b_idx as usize, tres_op_node.c_idx as usize)
+                    op.into(), idx_from_u64(tres_op_node.a_idx)?,
+                    idx_from_u64(tres_op_node.b_idx)?,
+                    idx_from_u64(tres_op_node.c_idx)?)
             }
-        }
+            crate::proto::node::Node::Gadget(gadget_node) => {
+                let args = gadget_node.args.iter()
+                    .map(|&a| idx_from_u64(a))
+                    .collect::<std::io::Result<Vec<usize>>>()?;
+                let gadget = crate::gadgets::Gadget::try_from(&gadget_node)?;
+                if gadget.inputs_num().is_some_and(|n| n != args.len()) {
+                    return Err(invalid_data(format!(
+                        "gadget {:?} takes {} operands, got {}",
+                        gadget, gadget.inputs_num().unwrap(), args.len())));
+                }
+                crate::graph::Node::Gadget(gadget, args)
+            }
+            crate::proto::node::Node::Extract(extract_node) => {
+                crate::graph::Node::Extract(
+                    idx_from_u64(extract_node.gadget_idx)?,
+                    idx_from_u64(extract_node.output)?)
+            }
+        };
+        Ok(node)
     }
 }
 
@@ -52,7 +94,7 @@ impl From<&crate::graph::Node> for crate::proto::node::Node {
         match node {
             crate::graph::Node::Input(i) => {
                 crate::proto::node::Node::Input (crate::proto::InputNode {
-                    idx: i.clone() as u32
+                    idx: *i as u64
                 })
             }
             crate::graph::Node::Constant(_) => {
@@ -63,29 +105,41 @@ impl From<&crate::graph::Node> for crate::proto::node::Node {
                 crate::proto::node::Node::UnoOp(
                     crate::proto::UnoOpNode {
                         op: op as i32,
-                        a_idx: a.clone() as u32 })
+                        a_idx: *a as u64 })
             }
             crate::graph::Node::Op(op, a, b) => {
                 crate::proto::node::Node::DuoOp(
                     crate::proto::DuoOpNode {
                         op: crate::proto::DuoOp::from(op) as i32,
-                        a_idx: a.clone() as u32,
-                        b_idx: b.clone() as u32 })
+                        a_idx: *a as u64,
+                        b_idx: *b as u64 })
             }
             crate::graph::Node::TresOp(op, a, b, c) => {
                 crate::proto::node::Node::TresOp(
                     crate::proto::TresOpNode {
                         op: crate::proto::TresOp::from(op) as i32,
-                        a_idx: a.clone() as u32,
-                        b_idx: b.clone() as u32,
-                        c_idx: c.clone() as u32 })
+                        a_idx: *a as u64,
+                        b_idx: *b as u64,
+                        c_idx: *c as u64 })
             }
             crate::graph::Node::MontConstant(c) => {
-                let bi = Into::<num_bigint::BigUint>::into(c.clone());
+                let bi = Into::<num_bigint::BigUint>::into(*c);
                 let i = crate::proto::BigUInt { value_le: bi.to_bytes_le() };
                 crate::proto::node::Node::Constant(
                     crate::proto::ConstantNode { value: Some(i) })
             }
+            crate::graph::Node::Gadget(gadget, args) => {
+                crate::proto::node::Node::Gadget(
+                    crate::proto::GadgetNode {
+                        args: args.iter().map(|&a| a as u64).collect(),
+                        ..gadget.into() })
+            }
+            crate::graph::Node::Extract(g, k) => {
+                crate::proto::node::Node::Extract(
+                    crate::proto::ExtractNode {
+                        gadget_idx: *g as u64,
+                        output: *k as u64 })
+            }
         }
     }
 }
@@ -135,9 +189,38 @@ impl From<crate::proto::TresOp> for TresOperation {
 }
 
 pub fn serialize_witnesscalc_graph<T: Write>(
-    mut w: T, nodes: &Vec<crate::graph::Node>, witness_signals: &Vec<usize>,
+    w: T, nodes: &[crate::graph::Node], witness_signals: &[usize],
     input_signals: &InputSignalsInfo) -> std::io::Result<()> {
 
+    let metadata = graph_metadata(witness_signals, input_signals);
+    serialize_witnesscalc_graph_with_metadata(w, nodes, &metadata)
+}
+
+/// Build the GraphMetadata message with the witness signals and inputs.
+/// Optional fields (like the symbol table) may be set by the caller before
+/// passing it to [serialize_witnesscalc_graph_with_metadata].
+pub fn graph_metadata(
+    witness_signals: &[usize],
+    input_signals: &InputSignalsInfo) -> crate::proto::GraphMetadata {
+
+    crate::proto::GraphMetadata {
+        witness_signals: witness_signals.iter().map(|x| *x as u64).collect::<Vec<u64>>(),
+        inputs: input_signals.iter().map(|(k, v)| {
+            let sig = crate::proto::SignalDescription {
+                offset: v.0 as u64,
+                len: v.1 as u64 };
+            (k.clone(), sig)
+        }).collect(),
+        symbols: Vec::new(),
+        public_signals: None,
+        node_slots: Vec::new(),
+    }
+}
+
+pub fn serialize_witnesscalc_graph_with_metadata<T: Write>(
+    mut w: T, nodes: &[crate::graph::Node],
+    metadata: &crate::proto::GraphMetadata) -> std::io::Result<()> {
+
     let mut ptr = 0usize;
     w.write_all(WITNESSCALC_GRAPH_MAGIC).unwrap();
     ptr += WITNESSCALC_GRAPH_MAGIC.len();
@@ -145,16 +228,6 @@ pub fn serialize_witnesscalc_graph<T: Write>(
     w.write_u64::<LittleEndian>(nodes.len() as u64)?;
     ptr += 8;
 
-    let metadata = crate::proto::GraphMetadata {
-        witness_signals: witness_signals.iter().map(|x| *x as u32).collect::<Vec<u32>>(),
-        inputs: input_signals.iter().map(|(k, v)| {
-            let sig = crate::proto::SignalDescription {
-                offset: v.0 as u32,
-                len: v.1 as u32 };
-            (k.clone(), sig)
-        }).collect()
-    };
-
     // capacity of buf should be enough to hold the largest message + 10 bytes
     // of varint length
     let mut buf =
@@ -184,14 +257,14 @@ pub fn serialize_witnesscalc_graph<T: Write>(
 
 fn read_message_length<R: Read>(rw: &mut WriteBackReader<R>) -> std::io::Result<usize> {
     let mut buf = [0u8; MAX_VARINT_LENGTH];
-    rw.read(&mut buf)?;
+    let bytes_read = rw.read(&mut buf)?;
 
-    let n = prost::decode_length_delimiter(buf.as_ref())?;
+    let n = prost::decode_length_delimiter(&buf[..bytes_read])?;
 
     let lnln = prost::length_delimiter_len(n);
 
-    if lnln < buf.len() {
-        rw.write(&buf[lnln..])?;
+    if lnln < bytes_read {
+        rw.write_all(&buf[lnln..bytes_read])?;
     }
 
     Ok(n)
@@ -214,6 +287,44 @@ fn read_message<R: Read, M: Message + std::default::Default>(rw: &mut WriteBackR
 pub fn deserialize_witnesscalc_graph(
     r: impl Read) -> std::io::Result<(Vec<crate::graph::Node>, Vec<usize>, InputSignalsInfo)> {
 
+    let (nodes, md) = deserialize_witnesscalc_graph_with_metadata(r)?;
+    let (witness_signals, input_signals) = signals_from_metadata(&md)?;
+    Ok((nodes, witness_signals, input_signals))
+}
+
+/// Read the nodes and the raw GraphMetadata message from the witnesscalc
+/// graph.
+pub fn deserialize_witnesscalc_graph_with_metadata(
+    r: impl Read) -> std::io::Result<(Vec<crate::graph::Node>, crate::proto::GraphMetadata)> {
+
+    let mut nodes = Vec::new();
+    let md = read_graph(r, |n| {
+        nodes.push(n);
+        Ok(())
+    })?;
+    Ok((nodes, md))
+}
+
+/// The same as [deserialize_witnesscalc_graph_with_metadata], but the nodes
+/// are read into the compact layout used by the evaluator. Fails with
+/// `InvalidData` if an index of the graph does not fit into `u32`.
+pub fn deserialize_witnesscalc_graph_compact(
+    r: impl Read) -> std::io::Result<(CompactNodes, crate::proto::GraphMetadata)> {
+
+    let mut nodes = CompactNodes::new();
+    let md = read_graph(r, |n| {
+        nodes.push(&n)?;
+        Ok(())
+    })?;
+    Ok((nodes, md))
+}
+
+// Read the graph passing each node to the push function, return the metadata.
+fn read_graph(
+    r: impl Read,
+    mut push: impl FnMut(crate::graph::Node) -> std::io::Result<()>,
+) -> std::io::Result<crate::proto::GraphMetadata> {
+
     let mut br = WriteBackReader::new(r);
     let mut magic = [0u8; WITNESSCALC_GRAPH_MAGIC.len()];
 
@@ -224,28 +335,107 @@ pub fn deserialize_witnesscalc_graph(
             std::io::ErrorKind::InvalidData, "Invalid magic"));
     }
 
-    let mut nodes = Vec::new();
+    // Number of results of the gadget nodes read so far, to check the
+    // Extract nodes that reference them.
+    let mut gadget_outputs = HashMap::new();
     let nodes_num = br.read_u64::<LittleEndian>()?;
-    for _ in 0..nodes_num {
+    for i in 0..nodes_num {
         let n: crate::proto::Node = read_message(&mut br)?;
-        let n2: crate::graph::Node = n.into();
-        nodes.push(n2);
+        let n2: crate::graph::Node = n.try_into()?;
+        match n2 {
+            crate::graph::Node::Gadget(ref g, ref args) => {
+                gadget_outputs.insert(i, g.outputs_num(args.len()));
+            }
+            crate::graph::Node::Extract(g, k) => {
+                match gadget_outputs.get(&(g as u64)) {
+                    Some(&outputs_num) if k < outputs_num => {}
+                    Some(&outputs_num) => return Err(invalid_data(format!(
+                        "node {}: result {} of the gadget node {} with {} results",
+                        i, k, g, outputs_num))),
+                    None => return Err(invalid_data(format!(
+                        "node {}: node {} is not a preceding gadget node", i, g))),
+                }
+            }
+            _ => {}
+        }
+        push(n2)?;
     }
 
-    let md: crate::proto::GraphMetadata = read_message(&mut br)?;
+    read_message(&mut br)
+}
+
+/// Witness signals and input signals info from the GraphMetadata.
+pub fn signals_from_metadata(
+    md: &crate::proto::GraphMetadata) -> std::io::Result<(Vec<usize>, InputSignalsInfo)> {
 
     let witness_signals = md.witness_signals
         .iter()
-        .map(|x| *x as usize)
-        .collect::<Vec<usize>>();
+        .map(|x| idx_from_u64(*x))
+        .collect::<std::io::Result<Vec<usize>>>()?;
 
     let input_signals = md.inputs.iter()
         .map(|(k, v)| {
-            (k.clone(), (v.offset as usize, v.len as usize))
+            Ok((k.clone(), (idx_from_u64(v.offset)?, idx_from_u64(v.len)?)))
         })
-        .collect::<InputSignalsInfo>();
+        .collect::<std::io::Result<InputSignalsInfo>>()?;
 
-    Ok((nodes, witness_signals, input_signals))
+    Ok((witness_signals, input_signals))
+}
+
+/// Evaluator slots of the nodes from the GraphMetadata. Returns None if the
+/// graph was built without slots allocation.
+pub fn node_slots_from_metadata(
+    md: &crate::proto::GraphMetadata,
+    nodes_num: usize) -> std::io::Result<Option<Vec<usize>>> {
+
+    if md.node_slots.is_empty() {
+        return Ok(None);
+    }
+
+    if md.node_slots.len() != nodes_num {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "number of node slots {} does not match number of nodes {}",
+                md.node_slots.len(), nodes_num)));
+    }
+
+    let slots = md.node_slots
+        .iter()
+        .map(|x| idx_from_u64(*x))
+        .collect::<std::io::Result<Vec<usize>>>()?;
+    Ok(Some(slots))
+}
+
+/// Read only the GraphMetadata message from the witnesscalc graph.
+///
+/// The offset of the metadata is stored in the last 8 bytes of the file, so
+/// we seek directly to it and do not decode the nodes. Useful to get the input
+/// signals and the witness size of a huge graph.
+pub fn read_graph_metadata<R: Read + Seek>(
+    mut r: R) -> std::io::Result<crate::proto::GraphMetadata> {
+
+    let mut magic = [0u8; WITNESSCALC_GRAPH_MAGIC.len()];
+    r.seek(SeekFrom::Start(0))?;
+    r.read_exact(&mut magic)?;
+
+    if !magic.eq(WITNESSCALC_GRAPH_MAGIC) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData, "Invalid magic"));
+    }
+
+    let end = r.seek(SeekFrom::End(-8))?;
+    let metadata_offset = r.read_u64::<LittleEndian>()?;
+
+    let min_offset = (WITNESSCALC_GRAPH_MAGIC.len() + 8) as u64;
+    if metadata_offset < min_offset || metadata_offset >= end {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData, "Invalid metadata offset"));
+    }
+
+    r.seek(SeekFrom::Start(metadata_offset))?;
+    let mut br = WriteBackReader::new(r.take(end - metadata_offset));
+    read_message(&mut br)
 }
 
 struct WriteBackReader<R: Read> {
@@ -264,7 +454,7 @@ impl <R: Read> WriteBackReader<R> {
 
 impl<R: Read> Read for WriteBackReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        if buf.len() == 0 {
+        if buf.is_empty() {
             return Ok(0)
         }
 
@@ -309,6 +499,7 @@ impl<R: Read> Write for WriteBackReader<R> {
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
+    use crate::gadgets::Gadget;
     use crate::graph::{Operation, TresOperation, UnoOperation};
     use core::str::FromStr;
     use super::*;
@@ -394,13 +585,13 @@ mod tests {
         let mut r = WriteBackReader::new(std::io::Cursor::new(&data));
 
         let buf = &mut [0u8; 5];
-        r.read(buf).unwrap();
+        assert_eq!(r.read(buf).unwrap(), 5);
         assert_eq!(buf, &[1, 2, 3, 4, 5]);
 
         // return [4, 5] to reader
-        r.write(&buf[3..]).unwrap();
+        assert_eq!(r.write(&buf[3..]).unwrap(), 2);
         // return [2, 3] to reader
-        r.write(&buf[1..3]).unwrap();
+        assert_eq!(r.write(&buf[1..3]).unwrap(), 2);
 
         buf.fill(0);
 
@@ -445,7 +636,7 @@ mod tests {
         assert_eq!(input_signals, input_signals_res);
         assert_eq!(witness_signals, witness_signals_res);
 
-        let metadata_start = LittleEndian::read_u64(&tmp[tmp.len() - 8..]);
+        let metadata_start = u64::from_le_bytes(tmp[tmp.len() - 8..].try_into().unwrap());
 
         let mt_reader = std::io::Cursor::new(&tmp[metadata_start as usize..]);
         let mut rw = WriteBackReader::new(mt_reader);
@@ -455,12 +646,238 @@ mod tests {
             witness_signals: vec![4, 1],
             inputs: input_signals.iter().map(|(k, v)| {
                 (k.clone(), crate::proto::SignalDescription {
-                    offset: v.0 as u32,
-                    len: v.1 as u32
+                    offset: v.0 as u64,
+                    len: v.1 as u64
+                })
+            }).collect(),
+            symbols: Vec::new(),
+            public_signals: None,
+            node_slots: Vec::new(),
+        };
+
+        assert_eq!(metadata, metadata_want);
+    }
+
+    #[test]
+    fn test_read_graph_metadata() {
+        let nodes = vec![
+            crate::graph::Node::Input(0),
+            crate::graph::Node::MontConstant(Fr::from_str("1").unwrap()),
+            crate::graph::Node::Op(Operation::Mul, 0, 1),
+        ];
+
+        let witness_signals = vec![2, 0];
+
+        let mut input_signals: InputSignalsInfo = HashMap::new();
+        input_signals.insert("sig1".to_string(), (1, 3));
+
+        let mut tmp = Vec::new();
+        serialize_witnesscalc_graph(&mut tmp, &nodes, &witness_signals, &input_signals).unwrap();
+
+        let metadata = read_graph_metadata(std::io::Cursor::new(&tmp)).unwrap();
+
+        let metadata_want = crate::proto::GraphMetadata {
+            witness_signals: vec![2, 0],
+            inputs: input_signals.iter().map(|(k, v)| {
+                (k.clone(), crate::proto::SignalDescription {
+                    offset: v.0 as u64,
+                    len: v.1 as u64
                 })
-            }).collect()
+            }).collect(),
+            symbols: Vec::new(),
+            public_signals: None,
+            node_slots: Vec::new(),
         };
 
         assert_eq!(metadata, metadata_want);
+
+        // corrupted metadata offset
+        let ln = tmp.len();
+        tmp[ln - 8..].copy_from_slice(&(ln as u64).to_le_bytes());
+        assert!(read_graph_metadata(std::io::Cursor::new(&tmp)).is_err());
+    }
+
+    #[test]
+    fn test_large_indexes() {
+        let big = u32::MAX as usize + 10;
+        let nodes = vec![
+            crate::graph::Node::Input(big),
+            crate::graph::Node::Op(Operation::Add, big + 1, big + 2),
+        ];
+        let witness_signals = vec![big + 3];
+        let mut input_signals: InputSignalsInfo = HashMap::new();
+        input_signals.insert("sig1".to_string(), (big + 4, 2));
+
+        let mut tmp = Vec::new();
+        serialize_witnesscalc_graph(&mut tmp, &nodes, &witness_signals, &input_signals).unwrap();
+
+        let (nodes_res, witness_signals_res, input_signals_res) =
+            deserialize_witnesscalc_graph(std::io::Cursor::new(&tmp)).unwrap();
+
+        assert_eq!(nodes, nodes_res);
+        assert_eq!(witness_signals, witness_signals_res);
+        assert_eq!(input_signals, input_signals_res);
+    }
+
+    fn gadget_node(
+        mut gadget: crate::proto::GadgetNode, args_num: u64) -> crate::proto::Node {
+
+        gadget.args = (0..args_num).collect();
+        crate::proto::Node {
+            node: Some(crate::proto::node::Node::Gadget(gadget))
+        }
+    }
+
+    #[test]
+    fn test_index_overflow() {
+        let nodes = vec![
+            crate::graph::Node::Input(0),
+            crate::graph::Node::Op(Operation::Add, 0, 1 << 32),
+        ];
+        let metadata = graph_metadata(&[1], &HashMap::new());
+        let mut tmp = Vec::new();
+        serialize_witnesscalc_graph_with_metadata(&mut tmp, &nodes, &metadata).unwrap();
+
+        let err = deserialize_witnesscalc_graph_compact(tmp.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(matches!(
+            crate::Graph::deserialize(&tmp), Err(crate::Error::InvalidGraph(_))));
+    }
+
+    #[test]
+    fn test_corrupt_extract() {
+        let corrupt = [
+            // result out of range
+            vec![
+                crate::graph::Node::Input(0),
+                crate::graph::Node::Gadget(Gadget::Bits(8), vec![0]),
+                crate::graph::Node::Extract(1, 8),
+            ],
+            // not a gadget node
+            vec![
+                crate::graph::Node::Input(0),
+                crate::graph::Node::Extract(0, 0),
+            ],
+            // gadget node after the extraction
+            vec![
+                crate::graph::Node::Input(0),
+                crate::graph::Node::Extract(2, 0),
+                crate::graph::Node::Gadget(Gadget::Bits(8), vec![0]),
+            ],
+        ];
+        let metadata = graph_metadata(&[0], &HashMap::new());
+        for nodes in corrupt {
+            let mut tmp = Vec::new();
+            serialize_witnesscalc_graph_with_metadata(&mut tmp, &nodes, &metadata).unwrap();
+            let err = deserialize_witnesscalc_graph(tmp.as_slice()).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_corrupt_nodes() {
+        let corrupt = [
+            crate::proto::Node { node: None },
+            crate::proto::Node {
+                node: Some(crate::proto::node::Node::Constant(
+                    crate::proto::ConstantNode { value: None }))
+            },
+            crate::proto::Node {
+                node: Some(crate::proto::node::Node::UnoOp(
+                    crate::proto::UnoOpNode { op: 100, a_idx: 0 }))
+            },
+            crate::proto::Node {
+                node: Some(crate::proto::node::Node::DuoOp(
+                    crate::proto::DuoOpNode { op: 100, a_idx: 0, b_idx: 0 }))
+            },
+            crate::proto::Node {
+                node: Some(crate::proto::node::Node::TresOp(
+                    crate::proto::TresOpNode {
+                        op: 100, a_idx: 0, b_idx: 0, c_idx: 0 }))
+            },
+            gadget_node(crate::proto::GadgetNode { op: 100, ..Default::default() }, 1),
+            // zero bits
+            gadget_node(crate::proto::GadgetNode {
+                op: crate::proto::GadgetOp::Bits as i32, ..Default::default() }, 1),
+            // limbs do not fit into the field element
+            gadget_node(crate::proto::GadgetNode {
+                op: crate::proto::GadgetOp::BigMul as i32,
+                limb_bits: 300, limbs_num: 2, ..Default::default() }, 4),
+            // wrong number of operands
+            gadget_node(crate::proto::GadgetNode {
+                op: crate::proto::GadgetOp::BigMul as i32,
+                limb_bits: 64, limbs_num: 2, ..Default::default() }, 3),
+        ];
+        for n in corrupt {
+            let err = crate::graph::Node::try_from(n).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_node_slots() {
+        let nodes = vec![
+            crate::graph::Node::Input(0),
+            crate::graph::Node::Input(1),
+            crate::graph::Node::Op(Operation::Add, 0, 1),
+        ];
+        let mut metadata = graph_metadata(&[2], &HashMap::new());
+        assert_eq!(node_slots_from_metadata(&metadata, nodes.len()).unwrap(), None);
+
+        metadata.node_slots = vec![0, 1, 0];
+        let mut tmp = Vec::new();
+        serialize_witnesscalc_graph_with_metadata(&mut tmp, &nodes, &metadata).unwrap();
+
+        let (nodes_res, metadata_res) =
+            deserialize_witnesscalc_graph_with_metadata(std::io::Cursor::new(&tmp)).unwrap();
+        assert_eq!(nodes, nodes_res);
+        assert_eq!(
+            node_slots_from_metadata(&metadata_res, nodes.len()).unwrap(),
+            Some(vec![0, 1, 0]));
+        assert!(node_slots_from_metadata(&metadata_res, 2).is_err());
+    }
+
+    #[test]
+    fn test_gadget_nodes() {
+        let nodes = vec![
+            crate::graph::Node::Input(0),
+            crate::graph::Node::Input(1),
+            crate::graph::Node::Gadget(Gadget::Sha256, vec![0, 1, 1]),
+            crate::graph::Node::Extract(2, 255),
+            crate::graph::Node::Gadget(Gadget::Bits(254), vec![1]),
+            crate::graph::Node::Extract(4, 3),
+            crate::graph::Node::Gadget(
+                Gadget::Limbs { limb_bits: 64, limbs_num: 4 }, vec![0]),
+            crate::graph::Node::Extract(6, 2),
+        ];
+        let mut tmp = Vec::new();
+        serialize_witnesscalc_graph(&mut tmp, &nodes, &[3, 5, 7], &HashMap::new()).unwrap();
+        let (nodes_res, witness_signals, _) =
+            deserialize_witnesscalc_graph(std::io::Cursor::new(&tmp)).unwrap();
+        assert_eq!(nodes, nodes_res);
+        assert_eq!(witness_signals, vec![3, 5, 7]);
+    }
+
+    #[test]
+    fn test_serialize_deterministic() {
+        let nodes = vec![crate::graph::Node::Input(0)];
+        let witness_signals = vec![0];
+
+        let mut serialized: Vec<Vec<u8>> = Vec::new();
+        for i in 0..10 {
+            let mut input_signals: InputSignalsInfo = HashMap::new();
+            for j in 0..20 {
+                let k = (i + j) % 20;
+                input_signals.insert(format!("sig{}", k), (k, 1));
+            }
+
+            let mut tmp = Vec::new();
+            serialize_witnesscalc_graph(&mut tmp, &nodes, &witness_signals, &input_signals).unwrap();
+            serialized.push(tmp);
+        }
+
+        for s in &serialized[1..] {
+            assert_eq!(&serialized[0], s);
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use ruint::aliases::U256;
+use crate::proto::{GraphMetadata, SignalSymbol};
+
+/// Signal symbol table of the circuit. Holds the same information as the
+/// circom .sym file: signal index, witness index, component index and the full
+/// dotted name of the signal.
+pub struct SymbolTable {
+    symbols: Vec<SignalSymbol>,
+    by_name: HashMap<String, usize>,
+    by_witness: HashMap<usize, usize>,
+}
+
+#[derive(Debug)]
+pub struct SymParseError {
+    line: usize,
+    msg: String,
+}
+
+impl std::fmt::Display for SymParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid sym file at line {}: {}", self.line, self.msg)
+    }
+}
+
+impl std::error::Error for SymParseError {}
+
+impl SymbolTable {
+    pub fn new(symbols: Vec<SignalSymbol>) -> Self {
+        let mut by_name = HashMap::with_capacity(symbols.len());
+        let mut by_witness = HashMap::new();
+        for (i, s) in symbols.iter().enumerate() {
+            by_name.insert(s.name.clone(), i);
+            if let Ok(witness_idx) = usize::try_from(s.witness_idx) {
+                by_witness.entry(witness_idx).or_insert(i);
+            }
+        }
+        SymbolTable { symbols, by_name, by_witness }
+    }
+
+    /// Create a symbol table from the graph metadata. Returns None if the
+    /// graph was built without symbols.
+    pub fn from_metadata(md: &GraphMetadata) -> Option<Self> {
+        if md.symbols.is_empty() {
+            return None;
+        }
+        Some(SymbolTable::new(md.symbols.clone()))
+    }
+
+    /// Parse the content of the circom .sym file. Each line has the format
+    /// `<signal idx>,<witness idx>,<component idx>,<name>`, where witness
+    /// index is -1 if the signal is not in the witness.
+    pub fn parse_sym(data: &str) -> Result<Self, SymParseError> {
+        let mut symbols = Vec::new();
+        for (i, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let err = |msg: &str| SymParseError {
+                line: i + 1, msg: msg.to_string() };
+
+            let mut parts = line.splitn(4, ',');
+            let mut next_field = |field: &str| parts.next()
+                .ok_or_else(|| err(&format!("missing {}", field)));
+
+            let signal_idx = next_field("signal index")?
+                .parse::<u64>()
+                .map_err(|e| err(&e.to_string()))?;
+            let witness_idx = next_field("witness index")?
+                .parse::<i64>()
+                .map_err(|e| err(&e.to_string()))?;
+            let component_idx = next_field("component index")?
+                .parse::<u64>()
+                .map_err(|e| err(&e.to_string()))?;
+            let name = next_field("name")?.to_string();
+
+            symbols.push(SignalSymbol {
+                witness_idx, signal_idx, component_idx, name });
+        }
+        Ok(SymbolTable::new(symbols))
+    }
+
+    pub fn symbols(&self) -> &[SignalSymbol] {
+        &self.symbols
+    }
+
+    pub fn into_symbols(self) -> Vec<SignalSymbol> {
+        self.symbols
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SignalSymbol> {
+        self.by_name.get(name).map(|&i| &self.symbols[i])
+    }
+
+    /// Index in the witness of the signal with the full dotted name,
+    /// like `main.sub.out[3]`. Returns None if the signal is not found or it
+    /// was removed from the witness.
+    pub fn witness_index(&self, name: &str) -> Option<usize> {
+        self.get(name).and_then(|s| usize::try_from(s.witness_idx).ok())
+    }
+
+    /// Name of the signal at the witness index.
+    pub fn witness_name(&self, witness_idx: usize) -> Option<&str> {
+        self.by_witness
+            .get(&witness_idx)
+            .map(|&i| self.symbols[i].name.as_str())
+    }
+
+    /// Lookup the value of the signal in the calculated witness.
+    pub fn witness_value(&self, witness: &[U256], name: &str) -> Option<U256> {
+        self.witness_index(name).and_then(|i| witness.get(i).copied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sym() {
+        let data = "1,1,1,main.out\n2,-1,0,main.sub.t\n3,2,0,main.sub.out[3]\n";
+        let st = SymbolTable::parse_sym(data).unwrap();
+        assert_eq!(st.symbols().len(), 3);
+        assert_eq!(st.witness_index("main.out"), Some(1));
+        assert_eq!(st.witness_index("main.sub.t"), None);
+        assert_eq!(st.witness_index("main.sub.out[3]"), Some(2));
+        assert_eq!(st.witness_index("main.unknown"), None);
+        assert_eq!(st.witness_name(2), Some("main.sub.out[3]"));
+        assert_eq!(st.get("main.sub.t").unwrap().signal_idx, 2);
+
+        let witness = vec![U256::from(1), U256::from(10), U256::from(20)];
+        assert_eq!(st.witness_value(&witness, "main.sub.out[3]"), Some(U256::from(20)));
+        assert_eq!(st.witness_value(&witness, "main.sub.t"), None);
+
+        assert!(SymbolTable::parse_sym("1,x,1,main.out").is_err());
+        assert!(SymbolTable::parse_sym("1,1,1").is_err());
+    }
+}
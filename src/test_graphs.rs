@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use ark_bn254::Fr;
+use ruint::aliases::U256;
+use crate::field::M;
+use crate::gadgets::Gadget;
+use crate::graph::{Node, Operation, TresOperation, UnoOperation};
+use crate::InputSignalsInfo;
+
+/// Graph with every operation and gadget for the code generator tests. Input
+/// 0 is the constant 1, inputs "a" are 1 and 2, input "b" is 3. Returns the
+/// nodes, the outputs and the input signals.
+pub(crate) fn all_operations() -> (Vec<Node>, Vec<usize>, InputSignalsInfo) {
+    let mut nodes = vec![
+        Node::Input(0),
+        Node::Input(1),
+        Node::Input(2),
+        Node::Input(3),
+        Node::MontConstant(Fr::from(5u64)),
+        Node::Constant(M - U256::from(3)),
+    ];
+    let ops = [
+        Operation::Mul, Operation::Div, Operation::Add, Operation::Sub,
+        Operation::Pow, Operation::Idiv, Operation::Mod, Operation::Eq,
+        Operation::Neq, Operation::Lt, Operation::Gt, Operation::Leq,
+        Operation::Geq, Operation::Land, Operation::Lor, Operation::Shl,
+        Operation::Shr, Operation::Band, Operation::Bor, Operation::Bxor];
+    let mut outputs = vec![0];
+    for op in ops {
+        for a in 0..6 {
+            for b in 0..6 {
+                outputs.push(nodes.len());
+                nodes.push(Node::Op(op, a, b));
+            }
+        }
+    }
+    // shifts where a * 2^b overflows 256 bits
+    for shift in [10u64, 200, 253] {
+        nodes.push(Node::MontConstant(Fr::from(shift)));
+        let b = nodes.len() - 1;
+        for a in [3, 5] {
+            outputs.push(nodes.len());
+            nodes.push(Node::Op(Operation::Shl, a, b));
+        }
+    }
+    outputs.push(nodes.len());
+    nodes.push(Node::UnoOp(UnoOperation::Neg, 5));
+    outputs.push(nodes.len());
+    nodes.push(Node::UnoOp(UnoOperation::Id, 3));
+    outputs.push(nodes.len());
+    nodes.push(Node::TresOp(TresOperation::TernCond, 1, 2, 3));
+    outputs.push(nodes.len());
+    nodes.push(Node::TresOp(TresOperation::TernCond, 6, 4, 5));
+    let limbs = [5, 0, 3, 1, 2, 4];
+    for (gadget, args) in [
+        (Gadget::Sha256, vec![1, 2, 3, 4, 0]),
+        (Gadget::Bits(254), vec![3]),
+        (Gadget::Limbs { limb_bits: 40, limbs_num: 7 }, vec![3]),
+        (Gadget::BigMul { limb_bits: 16, limbs_num: 3 }, limbs[..6].to_vec()),
+        (Gadget::BigDivMod { limb_bits: 16, limbs_num: 2, extra_limbs: 1 },
+         limbs[..5].to_vec()),
+        (Gadget::BigModExp { limb_bits: 16, limbs_num: 2 }, limbs[..6].to_vec()),
+        (Gadget::BigModInv { limb_bits: 16, limbs_num: 2 }, limbs[..4].to_vec())] {
+
+        let outputs_num = gadget.outputs_num(args.len());
+        nodes.push(Node::Gadget(gadget, args));
+        let g = nodes.len() - 1;
+        for k in 0..outputs_num {
+            outputs.push(nodes.len());
+            nodes.push(Node::Extract(g, k));
+        }
+    }
+
+    let mut input_signals: InputSignalsInfo = HashMap::new();
+    input_signals.insert("a".to_string(), (1, 2));
+    input_signals.insert("b".to_string(), (3, 1));
+
+    (nodes, outputs, input_signals)
+}
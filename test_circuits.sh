@@ -62,6 +62,22 @@ function test_circuit() {
 	pushd "${script_dir}" > /dev/null
 	time target/release/build-circuit "$circuit_path" "$circuit_graph_path" -l "$circomlib_path"
 	time target/release/calc-witness "$circuit_graph_path" "$inputs_path" "$witness_path"
+
+	# the witness must not change when the templates of the circuit are
+	# computed with gadgets
+	for gadget in Sha256 Num2Bits; do
+		if ! grep -q "${gadget}(" "$circuit_path"; then
+			continue
+		fi
+		local gadget_graph_path="${workdir}/${circuit_name}_${gadget}_graph.bin"
+		local gadget_witness_path="${workdir}/${circuit_name}_${gadget}.wtns"
+		target/release/build-circuit "$circuit_path" "$gadget_graph_path" -l "$circomlib_path" -gadget "$gadget"
+		target/release/calc-witness "$gadget_graph_path" "$inputs_path" "$gadget_witness_path"
+		if ! cmp -s "${witness_path}" "${gadget_witness_path}"; then
+			echo -e "${RED}Witness with -gadget ${gadget} does not match${NC}"
+			exit 1
+		fi
+	done
 	popd > /dev/null
 	
 	# run commands from the working directory